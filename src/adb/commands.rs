@@ -1,5 +1,6 @@
 //! ADB command execution utilities
 
+use std::path::PathBuf;
 use std::process::{Command, Output};
 use crate::error::{AppError, AppResult};
 
@@ -7,9 +8,28 @@ use crate::error::{AppError, AppResult};
 #[derive(Debug, Clone)]
 pub enum AdbCommand {
     GetProxy,
+    GetPacUrl,
     SetProxy(String),
     ClearProxy,
+    /// Remove the proxy auto-config URL (`settings delete global global_proxy_pac_url`)
+    ClearPacUrl,
     GetDevices,
+    GetProp(String),
+    Shell(String),
+    /// Copy a local file to the device (`adb push <local> <remote>`)
+    Push { local: String, remote: String },
+    /// Map a device-side socket to a host-side socket (`adb reverse <device> <host>`)
+    Reverse { device: String, host: String },
+    /// Remove a reverse mapping by its device-side socket
+    ReverseRemove(String),
+    ReverseList,
+    ReverseClear,
+    /// Map a host-side socket to a device-side socket (`adb forward <host> <device>`)
+    Forward { host: String, device: String },
+    /// Remove a forward mapping by its host-side socket
+    ForwardRemove(String),
+    ForwardList,
+    ForwardClear,
 }
 
 impl AdbCommand {
@@ -23,6 +43,13 @@ impl AdbCommand {
                 "global".to_string(),
                 "http_proxy".to_string(),
             ],
+            AdbCommand::GetPacUrl => vec![
+                "shell".to_string(),
+                "settings".to_string(),
+                "get".to_string(),
+                "global".to_string(),
+                "global_proxy_pac_url".to_string(),
+            ],
             AdbCommand::SetProxy(proxy) => vec![
                 "shell".to_string(),
                 "settings".to_string(),
@@ -39,7 +66,41 @@ impl AdbCommand {
                 "http_proxy".to_string(),
                 ":0".to_string(),
             ],
+            AdbCommand::ClearPacUrl => vec![
+                "shell".to_string(),
+                "settings".to_string(),
+                "delete".to_string(),
+                "global".to_string(),
+                "global_proxy_pac_url".to_string(),
+            ],
             AdbCommand::GetDevices => vec!["devices".to_string()],
+            AdbCommand::GetProp(name) => vec![
+                "shell".to_string(),
+                "getprop".to_string(),
+                name.clone(),
+            ],
+            AdbCommand::Shell(script) => vec!["shell".to_string(), script.clone()],
+            AdbCommand::Push { local, remote } => vec!["push".to_string(), local.clone(), remote.clone()],
+            AdbCommand::Reverse { device, host } => {
+                vec!["reverse".to_string(), device.clone(), host.clone()]
+            }
+            AdbCommand::ReverseRemove(device) => vec![
+                "reverse".to_string(),
+                "--remove".to_string(),
+                device.clone(),
+            ],
+            AdbCommand::ReverseList => vec!["reverse".to_string(), "--list".to_string()],
+            AdbCommand::ReverseClear => vec!["reverse".to_string(), "--remove-all".to_string()],
+            AdbCommand::Forward { host, device } => {
+                vec!["forward".to_string(), host.clone(), device.clone()]
+            }
+            AdbCommand::ForwardRemove(host) => vec![
+                "forward".to_string(),
+                "--remove".to_string(),
+                host.clone(),
+            ],
+            AdbCommand::ForwardList => vec!["forward".to_string(), "--list".to_string()],
+            AdbCommand::ForwardClear => vec!["forward".to_string(), "--remove-all".to_string()],
         }
     }
 
@@ -47,35 +108,93 @@ impl AdbCommand {
     pub fn description(&self) -> String {
         match self {
             AdbCommand::GetProxy => "get proxy settings".to_string(),
+            AdbCommand::GetPacUrl => "get proxy auto-config URL".to_string(),
             AdbCommand::SetProxy(proxy) => format!("set proxy to {}", proxy),
             AdbCommand::ClearProxy => "clear proxy settings".to_string(),
+            AdbCommand::ClearPacUrl => "clear proxy auto-config URL".to_string(),
             AdbCommand::GetDevices => "get connected devices".to_string(),
+            AdbCommand::GetProp(name) => format!("get property {}", name),
+            AdbCommand::Shell(_) => "run shell command".to_string(),
+            AdbCommand::Push { local, remote } => format!("push {} to {}", local, remote),
+            AdbCommand::Reverse { device, host } => format!("reverse {} to {}", device, host),
+            AdbCommand::ReverseRemove(device) => format!("remove reverse mapping {}", device),
+            AdbCommand::ReverseList => "list reverse mappings".to_string(),
+            AdbCommand::ReverseClear => "remove all reverse mappings".to_string(),
+            AdbCommand::Forward { host, device } => format!("forward {} to {}", host, device),
+            AdbCommand::ForwardRemove(host) => format!("remove forward mapping {}", host),
+            AdbCommand::ForwardList => "list forward mappings".to_string(),
+            AdbCommand::ForwardClear => "remove all forward mappings".to_string(),
         }
     }
 }
 
 /// Execute an ADB command and return the output
 pub fn execute_adb_command(command: AdbCommand) -> AppResult<Output> {
-    let args = command.to_args();
-    let description = command.description();
+    run_adb(&command.to_args(), &command.description())
+}
+
+/// Execute an ADB command and return the stdout as a string
+pub fn execute_adb_command_string(command: AdbCommand) -> AppResult<String> {
+    let output = execute_adb_command(command)?;
+    output_to_string(output)
+}
+
+/// Execute an ADB command against a specific device serial
+pub fn execute_device_command(serial: &str, command: AdbCommand) -> AppResult<Output> {
+    let mut args = vec!["-s".to_string(), serial.to_string()];
+    args.extend(command.to_args());
+    let description = format!("{} on {}", command.description(), serial);
+
+    run_adb(&args, &description)
+}
+
+/// Execute an ADB command against a specific device and return the stdout as a string
+pub fn execute_device_command_string(serial: &str, command: AdbCommand) -> AppResult<String> {
+    let output = execute_device_command(serial, command)?;
+    output_to_string(output)
+}
+
+/// Program run for adb commands, `adb` from `PATH`
+pub fn adb_program() -> PathBuf {
+    #[cfg(test)]
+    if let Some(program) = TEST_ADB_PROGRAM.with(|program| program.borrow().clone()) {
+        return program;
+    }
+    PathBuf::from("adb")
+}
 
-    let output = Command::new("adb")
-        .args(&args)
+/// Run adb commands of the current thread with `program` instead, `None` to go back to `adb`
+///
+/// Tests run in parallel threads, so the stand-in is set per thread rather
+/// than through `PATH`.
+#[cfg(test)]
+pub fn use_adb_program(program: Option<PathBuf>) {
+    TEST_ADB_PROGRAM.with(|current| *current.borrow_mut() = program);
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_ADB_PROGRAM: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+// Internal helper functions
+
+fn run_adb(args: &[String], description: &str) -> AppResult<Output> {
+    let output = Command::new(adb_program())
+        .args(args)
         .output()
-        .map_err(|e| AppError::adb_command_failed(&description, e))?;
+        .map_err(|e| AppError::adb_command_failed(description, e))?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(AppError::adb_command_failed(&description,
-            std::io::Error::new(std::io::ErrorKind::Other, stderr.to_string())));
+        return Err(AppError::adb_command_failed(description,
+            std::io::Error::other(stderr.to_string())));
     }
 
     Ok(output)
 }
 
-/// Execute an ADB command and return the stdout as a string
-pub fn execute_adb_command_string(command: AdbCommand) -> AppResult<String> {
-    let output = execute_adb_command(command)?;
+fn output_to_string(output: Output) -> AppResult<String> {
     let result = String::from_utf8(output.stdout)
         .map_err(|e| AppError::Utf8Error { source: e })?
         .trim()
@@ -83,4 +202,3 @@ pub fn execute_adb_command_string(command: AdbCommand) -> AppResult<String> {
 
     Ok(result)
 }
-
@@ -2,11 +2,11 @@
 
 use std::process::Command;
 use crate::error::{AppError, AppResult};
-use crate::adb::commands::{AdbCommand, execute_adb_command_string};
+use crate::adb::commands::{adb_program, AdbCommand, execute_adb_command_string};
 
 /// Check if ADB is available in the system PATH
 pub fn check_adb_availability() -> AppResult<String> {
-    let output = Command::new("adb")
+    let output = Command::new(adb_program())
         .arg("version")
         .output()
         .map_err(|_| AppError::AdbNotFound)?;
@@ -44,6 +44,30 @@ pub fn get_connected_devices() -> AppResult<Vec<String>> {
     Ok(devices)
 }
 
+/// Select the device to operate on
+///
+/// An explicitly requested serial must be connected. Without one, the only
+/// connected device is used; several devices require `--serial`.
+pub fn select_device(requested: Option<&str>) -> AppResult<String> {
+    let devices = get_connected_devices()?;
+
+    match requested {
+        Some(serial) => {
+            if devices.iter().any(|device| device == serial) {
+                Ok(serial.to_string())
+            } else {
+                Err(AppError::DeviceNotFound {
+                    serial: serial.to_string(),
+                })
+            }
+        }
+        None if devices.len() == 1 => Ok(devices[0].clone()),
+        None => Err(AppError::MultipleDevices {
+            devices: devices.join(", "),
+        }),
+    }
+}
+
 /// Check if ADB server is running
 pub fn is_adb_running() -> bool {
     let output = Command::new("pgrep")
@@ -76,7 +100,7 @@ pub fn restart_adb_server() -> AppResult<()> {
     } else {
         Err(AppError::adb_command_failed(
             "restart ADB server",
-            std::io::Error::new(std::io::ErrorKind::Other, "Failed to restart ADB server")
+            std::io::Error::other("Failed to restart ADB server")
         ))
     }
 }
\ No newline at end of file
@@ -0,0 +1,80 @@
+//! Stand-in adb for tests, keeping device state in files
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use crate::adb::commands::use_adb_program;
+
+/// Script answering the adb commands the tool runs
+///
+/// Global settings live in a file per setting, `getprop` values in
+/// `prop.<name>` and the boot id in `boot_id`. Reverse mappings are listed
+/// per device as `UsbFfs <device> <host>`, forward mappings for every device
+/// as `<serial> <host> <device>`, like adb does. Any other shell command
+/// fails. Every call is appended to `calls`.
+const SCRIPT: &str = r#"#!/bin/sh
+dir=$(dirname "$0")
+echo "$*" >> "$dir/calls"
+serial=
+[ "$1" = -s ] && { serial=$2; shift 2; }
+[ "$1" = shell ] && shift
+without() { grep -v "$1" "$2" > "$2.tmp" 2>/dev/null; mv "$2.tmp" "$2"; }
+case "$1 $2" in
+    "settings get") if [ -f "$dir/$4" ]; then cat "$dir/$4"; else echo null; fi ;;
+    "settings put") printf '%s' "$5" > "$dir/$4" ;;
+    "settings delete") rm -f "$dir/$4" ;;
+    "getprop "*) if [ -f "$dir/prop.$2" ]; then cat "$dir/prop.$2"; fi ;;
+    "cat /proc/sys/kernel/random/boot_id"*) cat "$dir/boot_id" 2>/dev/null ;;
+    "reverse --list") cat "$dir/reverse.$serial" 2>/dev/null ;;
+    "reverse --remove-all") rm -f "$dir/reverse.$serial" ;;
+    "reverse --remove") without "^UsbFfs $3 " "$dir/reverse.$serial" ;;
+    "reverse "*) without "^UsbFfs $2 " "$dir/reverse.$serial"; echo "UsbFfs $2 $3" >> "$dir/reverse.$serial" ;;
+    "forward --list") cat "$dir/forward" 2>/dev/null ;;
+    "forward --remove-all") without "^$serial " "$dir/forward" ;;
+    "forward --remove") without "^$serial $3 " "$dir/forward" ;;
+    "forward "*) without "^$serial $2 " "$dir/forward"; echo "$serial $2 $3" >> "$dir/forward" ;;
+    *) exit 1 ;;
+esac
+"#;
+
+/// A fake adb used by the adb commands of the current thread until dropped
+pub struct FakeAdb {
+    dir: PathBuf,
+}
+
+impl FakeAdb {
+    /// Install a fake adb in a fresh directory named after `name`
+    pub fn install(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aps-fake-adb-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("adb");
+        fs::write(&program, SCRIPT).unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        use_adb_program(Some(program));
+        Self { dir }
+    }
+
+    /// Store a global setting such as `http_proxy`
+    pub fn set(&self, setting: &str, value: &str) {
+        fs::write(self.dir.join(setting), value).unwrap();
+    }
+
+    /// File holding a global setting, to change it from another thread
+    pub fn path(&self, setting: &str) -> PathBuf {
+        self.dir.join(setting)
+    }
+
+    /// Read a global setting, `None` when it is not set
+    pub fn get(&self, setting: &str) -> Option<String> {
+        fs::read_to_string(self.dir.join(setting)).ok()
+    }
+}
+
+impl Drop for FakeAdb {
+    fn drop(&mut self) {
+        use_adb_program(None);
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,259 @@
+//! Port mappings between the device and this machine
+
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::adb::commands::{AdbCommand, execute_device_command, execute_device_command_string};
+use crate::error::{AppError, AppResult};
+
+/// Direction of a port mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MappingKind {
+    /// Device connects, this machine listens (`adb reverse`)
+    Reverse,
+    /// This machine connects, the device listens (`adb forward`)
+    Forward,
+}
+
+impl fmt::Display for MappingKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingKind::Reverse => write!(f, "reverse"),
+            MappingKind::Forward => write!(f, "forward"),
+        }
+    }
+}
+
+/// An adb socket specification such as `tcp:8081` or `localabstract:name`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SocketSpec(String);
+
+impl FromStr for SocketSpec {
+    type Err = AppError;
+
+    /// Parse `<protocol>:<address>`, or a bare port meaning `tcp:<port>`
+    fn from_str(s: &str) -> AppResult<Self> {
+        let s = s.trim();
+        if let Ok(port) = s.parse::<u16>() {
+            return Ok(SocketSpec(format!("tcp:{}", port)));
+        }
+
+        match s.split_once(':') {
+            Some(("tcp", port)) if port.parse::<u16>().is_ok() => Ok(SocketSpec(s.to_string())),
+            Some(("tcp", _)) => Err(AppError::InvalidPort {
+                input: s.to_string(),
+            }),
+            Some((protocol, address))
+                if !address.is_empty()
+                    && ["localabstract", "localreserved", "localfilesystem", "dev", "jdwp", "vsock"]
+                        .contains(&protocol) =>
+            {
+                Ok(SocketSpec(s.to_string()))
+            }
+            _ => Err(AppError::InvalidSocketSpec {
+                input: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<String> for SocketSpec {
+    type Error = AppError;
+
+    fn try_from(value: String) -> AppResult<Self> {
+        value.parse()
+    }
+}
+
+impl From<SocketSpec> for String {
+    fn from(spec: SocketSpec) -> Self {
+        spec.0
+    }
+}
+
+impl fmt::Display for SocketSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A reverse or forward mapping between a device socket and a host socket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub kind: MappingKind,
+    pub device: SocketSpec,
+    pub host: SocketSpec,
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            MappingKind::Reverse => write!(f, "reverse device {} -> host {}", self.device, self.host),
+            MappingKind::Forward => write!(f, "forward host {} -> device {}", self.host, self.device),
+        }
+    }
+}
+
+/// Make `tcp:<port>` on the device reach `tcp:<port>` on this machine over USB
+pub fn add_reverse_port(serial: &str, port: u16) -> AppResult<()> {
+    let socket = SocketSpec(format!("tcp:{}", port));
+    add_mapping(
+        serial,
+        &PortMapping {
+            kind: MappingKind::Reverse,
+            device: socket.clone(),
+            host: socket,
+        },
+    )
+}
+
+/// Remove the reverse mapping of `tcp:<port>` on the device
+pub fn remove_reverse_port(serial: &str, port: u16) -> AppResult<()> {
+    remove_mapping(serial, MappingKind::Reverse, &SocketSpec(format!("tcp:{}", port)))
+}
+
+/// List the mappings of one kind for the device
+pub fn list_mappings(serial: &str, kind: MappingKind) -> AppResult<Vec<PortMapping>> {
+    let command = match kind {
+        MappingKind::Reverse => AdbCommand::ReverseList,
+        MappingKind::Forward => AdbCommand::ForwardList,
+    };
+    let output = execute_device_command_string(serial, command)?;
+
+    Ok(output
+        .lines()
+        .filter_map(|line| parse_list_line(serial, kind, line))
+        .collect())
+}
+
+/// Create a mapping on the device
+pub fn add_mapping(serial: &str, mapping: &PortMapping) -> AppResult<()> {
+    let command = match mapping.kind {
+        MappingKind::Reverse => AdbCommand::Reverse {
+            device: mapping.device.to_string(),
+            host: mapping.host.to_string(),
+        },
+        MappingKind::Forward => AdbCommand::Forward {
+            host: mapping.host.to_string(),
+            device: mapping.device.to_string(),
+        },
+    };
+    execute_device_command(serial, command).map(|_| ())
+}
+
+/// Remove a mapping by the socket that identifies it
+///
+/// Reverse mappings are keyed by their device socket, forward mappings by
+/// their host socket, as in adb itself.
+pub fn remove_mapping(serial: &str, kind: MappingKind, socket: &SocketSpec) -> AppResult<()> {
+    let command = match kind {
+        MappingKind::Reverse => AdbCommand::ReverseRemove(socket.to_string()),
+        MappingKind::Forward => AdbCommand::ForwardRemove(socket.to_string()),
+    };
+    execute_device_command(serial, command).map(|_| ())
+}
+
+/// Remove all mappings of one kind from the device
+pub fn clear_mappings(serial: &str, kind: MappingKind) -> AppResult<()> {
+    let command = match kind {
+        MappingKind::Reverse => AdbCommand::ReverseClear,
+        MappingKind::Forward => AdbCommand::ForwardClear,
+    };
+    execute_device_command(serial, command).map(|_| ())
+}
+
+// Internal helper functions
+
+/// Parse a line of `adb reverse --list` (`<transport> <device> <host>`)
+/// or `adb forward --list` (`<serial> <host> <device>`)
+fn parse_list_line(serial: &str, kind: MappingKind, line: &str) -> Option<PortMapping> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [owner, first, second] = fields.as_slice() else {
+        return None;
+    };
+
+    match kind {
+        MappingKind::Reverse => Some(PortMapping {
+            kind,
+            device: first.parse().ok()?,
+            host: second.parse().ok()?,
+        }),
+        // The forward list covers every device
+        MappingKind::Forward if *owner == serial => Some(PortMapping {
+            kind,
+            host: first.parse().ok()?,
+            device: second.parse().ok()?,
+        }),
+        MappingKind::Forward => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(s: &str) -> SocketSpec {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn socket_specs_are_parsed() {
+        assert_eq!(spec("8081").to_string(), "tcp:8081");
+        assert_eq!(spec(" tcp:8081 ").to_string(), "tcp:8081");
+        assert_eq!(spec("8081"), spec("tcp:8081"));
+        for valid in ["localabstract:chrome_devtools_remote", "localfilesystem:/data/local/tmp/sock", "jdwp:1234", "vsock:3:5555"] {
+            assert_eq!(spec(valid).to_string(), valid);
+        }
+
+        assert!(matches!("tcp:http".parse::<SocketSpec>(), Err(AppError::InvalidPort { .. })));
+        assert!(matches!("tcp:70000".parse::<SocketSpec>(), Err(AppError::InvalidPort { .. })));
+        for invalid in ["", "70000", "udp:53", "localabstract:", "chrome_devtools_remote"] {
+            assert!(matches!(invalid.parse::<SocketSpec>(), Err(AppError::InvalidSocketSpec { .. })), "{}", invalid);
+        }
+
+        let mapping: PortMapping = serde_json::from_str(r#"{"kind":"reverse","device":"8081","host":"tcp:9000"}"#).unwrap();
+        assert_eq!(mapping.device, spec("tcp:8081"));
+        assert!(serde_json::from_str::<PortMapping>(r#"{"kind":"reverse","device":"udp:1","host":"tcp:1"}"#).is_err());
+    }
+
+    #[test]
+    fn reverse_list_lines_are_parsed_whatever_the_transport() {
+        for line in ["UsbFfs tcp:8081 tcp:8082", "emulator-5554 tcp:8081 tcp:8082", "host-19 tcp:8081 tcp:8082"] {
+            assert_eq!(
+                parse_list_line("emulator-5554", MappingKind::Reverse, line),
+                Some(PortMapping {
+                    kind: MappingKind::Reverse,
+                    device: spec("tcp:8081"),
+                    host: spec("tcp:8082"),
+                }),
+                "{}",
+                line
+            );
+        }
+        let abstract_socket = parse_list_line("R58M123ABC", MappingKind::Reverse, "UsbFfs localabstract:metro tcp:8081").unwrap();
+        assert_eq!(abstract_socket.device, spec("localabstract:metro"));
+    }
+
+    #[test]
+    fn forward_list_lines_only_count_for_their_device() {
+        let line = "emulator-5554 tcp:9222 localabstract:chrome_devtools_remote";
+        assert_eq!(
+            parse_list_line("emulator-5554", MappingKind::Forward, line),
+            Some(PortMapping {
+                kind: MappingKind::Forward,
+                host: spec("tcp:9222"),
+                device: spec("localabstract:chrome_devtools_remote"),
+            })
+        );
+        assert_eq!(parse_list_line("R58M123ABC", MappingKind::Forward, line), None);
+    }
+
+    #[test]
+    fn malformed_list_lines_are_skipped() {
+        for line in ["", "UsbFfs tcp:8081", "UsbFfs tcp:8081 tcp:8081 extra", "UsbFfs udp:53 tcp:53", "* daemon started successfully"] {
+            assert_eq!(parse_list_line("emulator-5554", MappingKind::Reverse, line), None, "{}", line);
+        }
+    }
+}
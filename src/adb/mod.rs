@@ -1,4 +1,8 @@
 //! ADB command execution module
 
 pub mod commands;
-pub mod device;
\ No newline at end of file
+pub mod device;
+#[cfg(all(test, unix))]
+pub mod fake;
+pub mod mapping;
+pub mod rule_sets;
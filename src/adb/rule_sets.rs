@@ -0,0 +1,89 @@
+//! Named sets of port mappings kept between runs
+
+use std::collections::BTreeMap;
+use crate::adb::mapping::PortMapping;
+use crate::config::paths::{data_file, read_state_file, write_state_file};
+use crate::error::{AppError, AppResult};
+
+/// File name of the rule set store inside the state directory
+const RULE_SETS_FILE: &str = "port_rules.json";
+
+/// Rule sets by name
+pub type RuleSets = BTreeMap<String, Vec<PortMapping>>;
+
+/// Load all saved rule sets
+pub fn load_rule_sets() -> AppResult<RuleSets> {
+    let path = data_file(RULE_SETS_FILE)?;
+    match read_state_file(&path)? {
+        Some(content) => serde_json::from_str(&content).map_err(|e| AppError::state_file_error(&path, e)),
+        None => Ok(RuleSets::new()),
+    }
+}
+
+/// Get one saved rule set
+pub fn get_rule_set(name: &str) -> AppResult<Vec<PortMapping>> {
+    load_rule_sets()?
+        .remove(name)
+        .ok_or_else(|| AppError::RuleSetNotFound {
+            name: name.to_string(),
+        })
+}
+
+/// Save a rule set, replacing any set with the same name
+pub fn save_rule_set(name: &str, mappings: Vec<PortMapping>) -> AppResult<()> {
+    let mut sets = load_rule_sets()?;
+    sets.insert(name.to_string(), mappings);
+    store(&sets)
+}
+
+/// Delete a saved rule set
+pub fn delete_rule_set(name: &str) -> AppResult<()> {
+    let mut sets = load_rule_sets()?;
+    if sets.remove(name).is_none() {
+        return Err(AppError::RuleSetNotFound {
+            name: name.to_string(),
+        });
+    }
+    store(&sets)
+}
+
+// Internal helper functions
+
+fn store(sets: &RuleSets) -> AppResult<()> {
+    let path = data_file(RULE_SETS_FILE)?;
+    let content = serde_json::to_string_pretty(sets).map_err(|e| AppError::state_file_error(&path, e))?;
+    write_state_file(&path, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adb::mapping::MappingKind;
+    use crate::config::paths::TestDataDir;
+
+    fn mapping(kind: MappingKind, device: &str, host: &str) -> PortMapping {
+        PortMapping {
+            kind,
+            device: device.parse().unwrap(),
+            host: host.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn rule_sets_are_saved_replaced_and_deleted() {
+        let _data = TestDataDir::new("rule-sets");
+        let metro = vec![mapping(MappingKind::Reverse, "8081", "8081"), mapping(MappingKind::Forward, "localabstract:devtools", "9222")];
+
+        save_rule_set("metro", metro.clone()).unwrap();
+        save_rule_set("other", Vec::new()).unwrap();
+        assert_eq!(get_rule_set("metro").unwrap(), metro);
+        assert_eq!(load_rule_sets().unwrap().keys().collect::<Vec<_>>(), ["metro", "other"]);
+
+        save_rule_set("metro", metro[..1].to_vec()).unwrap();
+        assert_eq!(get_rule_set("metro").unwrap(), metro[..1]);
+
+        delete_rule_set("metro").unwrap();
+        assert!(matches!(get_rule_set("metro"), Err(AppError::RuleSetNotFound { .. })));
+        assert!(matches!(delete_rule_set("metro"), Err(AppError::RuleSetNotFound { .. })));
+    }
+}
@@ -0,0 +1,166 @@
+//! The local root CA used to intercept HTTPS traffic
+//!
+//! The CA is generated on first use and kept in the state directory, so a
+//! device only has to trust it once. Leaf certificates for intercepted hosts
+//! are minted on the fly and never written to disk.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use time::{Duration, OffsetDateTime};
+use crate::config::paths::{data_file, read_state_file, write_state_file};
+use crate::error::{AppError, AppResult};
+
+/// Common name of the CA, also used to recognise it in certificate stores
+pub const CA_COMMON_NAME: &str = "Android Proxy Setter CA";
+
+/// File names of the CA inside the state directory
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key";
+
+/// How long the CA stays valid
+const CA_VALIDITY_DAYS: i64 = 3650;
+
+/// Leaf validity, below the 398 days clients accept for server certificates
+const LEAF_VALIDITY_DAYS: i64 = 365;
+
+/// A root certificate and the key it signs leaf certificates with
+pub struct CertificateAuthority {
+    cert_pem: String,
+    cert_der: CertificateDer<'static>,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+/// A leaf certificate chain and its private key, ready for a TLS server
+pub struct LeafCertificate {
+    pub chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl CertificateAuthority {
+    /// Load the CA from the state directory, `None` when it was not generated yet
+    pub fn load() -> AppResult<Option<Self>> {
+        let cert_path = data_file(CA_CERT_FILE)?;
+        let key_path = data_file(CA_KEY_FILE)?;
+
+        match (read_state_file(&cert_path)?, read_state_file(&key_path)?) {
+            (Some(cert_pem), Some(key_pem)) => Self::from_pem(&cert_pem, &key_pem).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Load the CA from the state directory, generating it on first use
+    pub fn load_or_create() -> AppResult<Self> {
+        match Self::load()? {
+            Some(authority) => Ok(authority),
+            None => Self::generate(&data_file(CA_CERT_FILE)?, &data_file(CA_KEY_FILE)?),
+        }
+    }
+
+    /// The CA certificate in DER form
+    pub fn cert_der(&self) -> &CertificateDer<'static> {
+        &self.cert_der
+    }
+
+    /// Mint a certificate for `host`, a DNS name or an IP address
+    pub fn issue_leaf(&self, host: &str) -> AppResult<LeafCertificate> {
+        let mut params = CertificateParams::new(vec![host.to_string()])
+            .map_err(|e| AppError::certificate(format!("cannot issue a certificate for {}: {}", host, e)))?;
+        params.distinguished_name = distinguished_name(host);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyEncipherment];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        // Backdated a day so devices with a slightly wrong clock accept it
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(LEAF_VALIDITY_DAYS);
+
+        let key = KeyPair::generate().map_err(certificate_error)?;
+        let cert = params.signed_by(&key, &self.issuer).map_err(certificate_error)?;
+
+        Ok(LeafCertificate {
+            chain: vec![cert.der().clone(), self.cert_der.clone()],
+            key: PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+        })
+    }
+
+    /// Write the certificate as `<name>.pem` and `<name>.der` into `dir`
+    pub fn export(&self, dir: &Path, name: &str) -> AppResult<(PathBuf, PathBuf)> {
+        fs::create_dir_all(dir).map_err(|e| AppError::state_file_error(dir, e))?;
+        let pem_path = dir.join(format!("{}.pem", name));
+        let der_path = dir.join(format!("{}.der", name));
+        fs::write(&pem_path, &self.cert_pem).map_err(|e| AppError::state_file_error(&pem_path, e))?;
+        fs::write(&der_path, self.cert_der.as_ref()).map_err(|e| AppError::state_file_error(&der_path, e))?;
+        Ok((pem_path, der_path))
+    }
+
+    fn from_pem(cert_pem: &str, key_pem: &str) -> AppResult<Self> {
+        let key = KeyPair::from_pem(key_pem).map_err(certificate_error)?;
+        let issuer = Issuer::from_ca_cert_pem(cert_pem, key).map_err(certificate_error)?;
+        let cert_der = CertificateDer::from_pem_slice(cert_pem.as_bytes()).map_err(certificate_error)?;
+        Ok(Self {
+            cert_pem: cert_pem.to_string(),
+            cert_der,
+            issuer,
+        })
+    }
+
+    /// Generate a new CA, writing its certificate and key to the given files
+    pub fn generate(cert_path: &Path, key_path: &Path) -> AppResult<Self> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name = distinguished_name(CA_COMMON_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign, KeyUsagePurpose::DigitalSignature];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + Duration::days(CA_VALIDITY_DAYS);
+
+        let key = KeyPair::generate().map_err(certificate_error)?;
+        let cert = params.self_signed(&key).map_err(certificate_error)?;
+
+        // The key goes first, a certificate without its key is useless
+        write_private_file(key_path, &key.serialize_pem())?;
+        write_state_file(cert_path, &cert.pem())?;
+
+        Ok(Self {
+            cert_pem: cert.pem(),
+            cert_der: cert.der().clone(),
+            issuer: Issuer::new(params, key),
+        })
+    }
+}
+
+// Internal helper functions
+
+fn distinguished_name(common_name: &str) -> DistinguishedName {
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, common_name);
+    name.push(DnType::OrganizationName, env!("CARGO_PKG_NAME"));
+    name
+}
+
+/// Write a file only the current user can read
+fn write_private_file(path: &Path, content: &str) -> AppResult<()> {
+    let temporary = path.with_extension("key.tmp");
+    // The mode only applies to new files, so never reuse a leftover one
+    let _ = fs::remove_file(&temporary);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&temporary).map_err(|e| AppError::state_file_error(&temporary, e))?;
+    std::io::Write::write_all(&mut file, content.as_bytes()).map_err(|e| AppError::state_file_error(&temporary, e))?;
+    fs::rename(&temporary, path).map_err(|e| AppError::state_file_error(path, e))
+}
+
+fn certificate_error(error: impl std::fmt::Display) -> AppError {
+    AppError::certificate(error.to_string())
+}
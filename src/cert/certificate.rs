@@ -0,0 +1,187 @@
+//! Reading CA certificates and naming them the way Android's stores do
+
+use std::fs;
+use std::path::Path;
+use base64::Engine;
+use md5::{Digest, Md5};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use sha2::Sha256;
+use time::OffsetDateTime;
+use x509_parser::parse_x509_certificate;
+use crate::error::{AppError, AppResult};
+
+/// A parsed X.509 certificate
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    der: Vec<u8>,
+    subject: String,
+    subject_hash: u32,
+    is_ca: bool,
+    not_after: OffsetDateTime,
+}
+
+impl Certificate {
+    /// Read the first certificate of a PEM or DER file
+    pub fn from_file(path: &Path) -> AppResult<Self> {
+        let content = fs::read(path).map_err(|e| AppError::state_file_error(path, e))?;
+        Self::from_bytes(content)
+            .map_err(|e| AppError::certificate(format!("{} is not a certificate: {}", path.display(), e)))
+    }
+
+    /// Parse the first certificate of PEM text, or DER
+    ///
+    /// Text before the PEM block is skipped, such as the `openssl x509 -text`
+    /// dump in front of the certificates of Android's system store.
+    pub fn from_bytes(content: Vec<u8>) -> AppResult<Self> {
+        match CertificateDer::from_pem_slice(&content) {
+            Ok(der) => Self::from_der(der.to_vec()),
+            // Not PEM, so it has to be DER
+            Err(_) => Self::from_der(content),
+        }
+    }
+
+    /// Parse a DER-encoded certificate
+    pub fn from_der(der: Vec<u8>) -> AppResult<Self> {
+        let (subject, subject_hash, is_ca, not_after) = {
+            let (_, certificate) = parse_x509_certificate(&der).map_err(|e| AppError::certificate(e.to_string()))?;
+            let subject = certificate.subject();
+            let not_after = OffsetDateTime::from_unix_timestamp(certificate.validity().not_after.timestamp())
+                .map_err(|e| AppError::certificate(e.to_string()))?;
+            (subject.to_string(), subject_hash_old(subject.as_raw()), certificate.is_ca(), not_after)
+        };
+
+        Ok(Self {
+            der,
+            subject,
+            subject_hash,
+            is_ca,
+            not_after,
+        })
+    }
+
+    /// Subject as an RFC 4514 string, e.g. `CN=Example CA, O=Example`
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Whether the certificate may act as a CA (basic constraints)
+    pub fn is_ca(&self) -> bool {
+        self.is_ca
+    }
+
+    /// End of the validity period
+    pub fn not_after(&self) -> OffsetDateTime {
+        self.not_after
+    }
+
+    /// Whether the validity period is over
+    pub fn is_expired(&self) -> bool {
+        self.not_after < OffsetDateTime::now_utc()
+    }
+
+    /// SHA-256 of the DER encoding, as colon-separated hex like browsers show it
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(&self.der)
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    /// OpenSSL `subject_hash_old` in hex, which names the certificate in
+    /// Android's CA stores as `<hash>.0` (`.1` and up for hash collisions)
+    pub fn subject_hash(&self) -> String {
+        format!("{:08x}", self.subject_hash)
+    }
+
+    /// The certificate in PEM form
+    pub fn to_pem(&self) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&self.der);
+        let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+        // Lines are ASCII, so splitting the bytes keeps them valid UTF-8
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(&String::from_utf8_lossy(line));
+            pem.push('\n');
+        }
+        pem.push_str("-----END CERTIFICATE-----\n");
+        pem
+    }
+}
+
+// Internal helper functions
+
+/// OpenSSL's pre-1.0 subject hash: the first four bytes of the MD5 of the
+/// DER-encoded subject, read as a little-endian number
+fn subject_hash_old(subject_der: &[u8]) -> u32 {
+    let digest = Md5::digest(subject_der);
+    u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ISRG Root X1, shipped by Android as `6187b673.0`
+    const ISRG_ROOT_X1: &str = "-----BEGIN CERTIFICATE-----
+MIIFazCCA1OgAwIBAgIRAIIQz7DSQONZRGPgu2OCiwAwDQYJKoZIhvcNAQELBQAw
+TzELMAkGA1UEBhMCVVMxKTAnBgNVBAoTIEludGVybmV0IFNlY3VyaXR5IFJlc2Vh
+cmNoIEdyb3VwMRUwEwYDVQQDEwxJU1JHIFJvb3QgWDEwHhcNMTUwNjA0MTEwNDM4
+WhcNMzUwNjA0MTEwNDM4WjBPMQswCQYDVQQGEwJVUzEpMCcGA1UEChMgSW50ZXJu
+ZXQgU2VjdXJpdHkgUmVzZWFyY2ggR3JvdXAxFTATBgNVBAMTDElTUkcgUm9vdCBY
+MTCCAiIwDQYJKoZIhvcNAQEBBQADggIPADCCAgoCggIBAK3oJHP0FDfzm54rVygc
+h77ct984kIxuPOZXoHj3dcKi/vVqbvYATyjb3miGbESTtrFj/RQSa78f0uoxmyF+
+0TM8ukj13Xnfs7j/EvEhmkvBioZxaUpmZmyPfjxwv60pIgbz5MDmgK7iS4+3mX6U
+A5/TR5d8mUgjU+g4rk8Kb4Mu0UlXjIB0ttov0DiNewNwIRt18jA8+o+u3dpjq+sW
+T8KOEUt+zwvo/7V3LvSye0rgTBIlDHCNAymg4VMk7BPZ7hm/ELNKjD+Jo2FR3qyH
+B5T0Y3HsLuJvW5iB4YlcNHlsdu87kGJ55tukmi8mxdAQ4Q7e2RCOFvu396j3x+UC
+B5iPNgiV5+I3lg02dZ77DnKxHZu8A/lJBdiB3QW0KtZB6awBdpUKD9jf1b0SHzUv
+KBds0pjBqAlkd25HN7rOrFleaJ1/ctaJxQZBKT5ZPt0m9STJEadao0xAH0ahmbWn
+OlFuhjuefXKnEgV4We0+UXgVCwOPjdAvBbI+e0ocS3MFEvzG6uBQE3xDk3SzynTn
+jh8BCNAw1FtxNrQHusEwMFxIt4I7mKZ9YIqioymCzLq9gwQbooMDQaHWBfEbwrbw
+qHyGO0aoSCqI3Haadr8faqU9GY/rOPNk3sgrDQoo//fb4hVC1CLQJ13hef4Y53CI
+rU7m2Ys6xt0nUW7/vGT1M0NPAgMBAAGjQjBAMA4GA1UdDwEB/wQEAwIBBjAPBgNV
+HRMBAf8EBTADAQH/MB0GA1UdDgQWBBR5tFnme7bl5AFzgAiIyBpY9umbbjANBgkq
+hkiG9w0BAQsFAAOCAgEAVR9YqbyyqFDQDLHYGmkgJykIrGF1XIpu+ILlaS/V9lZL
+ubhzEFnTIZd+50xx+7LSYK05qAvqFyFWhfFQDlnrzuBZ6brJFe+GnY+EgPbk6ZGQ
+3BebYhtF8GaV0nxvwuo77x/Py9auJ/GpsMiu/X1+mvoiBOv/2X/qkSsisRcOj/KK
+NFtY2PwByVS5uCbMiogziUwthDyC3+6WVwW6LLv3xLfHTjuCvjHIInNzktHCgKQ5
+ORAzI4JMPJ+GslWYHb4phowim57iaztXOoJwTdwJx4nLCgdNbOhdjsnvzqvHu7Ur
+TkXWStAmzOVyyghqpZXjFaH3pO3JLF+l+/+sKAIuvtd7u+Nxe5AW0wdeRlN8NwdC
+jNPElpzVmbUq4JUagEiuTDkHzsxHpFKVK7q4+63SM1N95R1NbdWhscdCb+ZAJzVc
+oyi3B43njTOQ5yOf+1CceWxG1bQVs5ZufpsMljq4Ui0/1lvh+wjChP4kqKOJ2qxq
+4RgqsahDYVvTH9w7jXbyLeiNdd8XM2w9U/t7y0Ff/9yi0GE44Za4rF2LN9d11TPA
+mRGunUHBcnWEvgJBQl9nJEiU0Zsnvgc/ubhPgXRR4Xq37Z0j4r7g1SgEEzwxA57d
+emyPxgcYxn/eR44/KJ4EBs+lVDR3veyJm+kXQ99b21/+jh5Xos1AnX5iItreGCc=
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn subject_hash_matches_openssl() {
+        // `openssl x509 -subject_hash_old -noout`
+        let certificate = Certificate::from_bytes(ISRG_ROOT_X1.as_bytes().to_vec()).unwrap();
+        assert_eq!(certificate.subject_hash(), "6187b673");
+        assert_eq!(certificate.subject(), "C=US, O=Internet Security Research Group, CN=ISRG Root X1");
+        assert!(certificate.is_ca());
+        assert!(certificate.fingerprint().starts_with("96:BC:EC:06:26:49:76:F3"));
+        assert_eq!(certificate.to_pem(), ISRG_ROOT_X1);
+    }
+
+    #[test]
+    fn system_store_files_skip_the_text_dump() {
+        // As in /system/etc/security/cacerts/6187b673.0
+        let store_file = format!(
+            "Certificate:\n    Data:\n        Version: 3 (0x2)\n        Serial Number:\n            \
+             82:10:cf:b0:d2:40:e3:59:44:63:e0:bb:63:82:8b:00\n        Signature Algorithm: sha256WithRSAEncryption\n        \
+             Issuer: C=US, O=Internet Security Research Group, CN=ISRG Root X1\n{}\
+             SHA1 Fingerprint=CA:BD:2A:79:A1:07:6A:31:F2:1D:25:36:35:CB:03:9D:43:29:A5:E8\n",
+            ISRG_ROOT_X1
+        );
+        let certificate = Certificate::from_bytes(store_file.into_bytes()).unwrap();
+        assert_eq!(certificate.subject_hash(), "6187b673");
+
+        let der = Certificate::from_bytes(ISRG_ROOT_X1.as_bytes().to_vec()).unwrap().der;
+        assert_eq!(Certificate::from_bytes(der).unwrap().subject_hash(), "6187b673");
+        assert!(Certificate::from_bytes(b"Certificate:\n    Data:\n".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Installing CA certificates on a device
+//!
+//! With root the certificate goes into the system store, which every app
+//! trusts. Without root only the user can add a CA (to the user store), so
+//! the certificate is pushed to Download and the installer is opened.
+
+use std::fs;
+use crate::adb::commands::{execute_device_command_string, AdbCommand};
+use crate::cert::certificate::Certificate;
+use crate::error::{AppError, AppResult};
+
+/// Where files are staged before a root shell moves them
+const DEVICE_TEMP_DIR: &str = "/data/local/tmp";
+
+/// Where certificates for the user store are pushed
+const DOWNLOAD_DIR: &str = "/sdcard/Download";
+
+/// Android 11 only accepts CA certificates installed from Settings
+const SDK_SETTINGS_ONLY: u32 = 30;
+
+/// Copies the certificate into the system store, through a tmpfs overlay
+/// when /system is read-only, and into the Conscrypt APEX store of zygote
+/// and the running apps on Android 14+. Prints `<persistent|overlay> <file>`.
+const SYSTEM_INSTALL_SCRIPT: &str = r#"set -e
+CERT=@CERT@
+HASH=@HASH@
+STORE=/system/etc/security/cacerts
+APEX=/apex/com.android.conscrypt/cacerts
+
+install_into() {
+    N=0
+    while [ -e "$1/$HASH.$N" ] && ! cmp -s "$1/$HASH.$N" "$CERT"; do N=$((N + 1)); done
+    FILE=$HASH.$N
+    cp "$CERT" "$1/$FILE"
+    chown root:root "$1/$FILE"
+    chmod 644 "$1/$FILE"
+    chcon u:object_r:system_file:s0 "$1/$FILE" 2>/dev/null || true
+}
+
+if [ ! -d "$APEX" ] && touch "$STORE/.aps-probe" 2>/dev/null; then
+    rm -f "$STORE/.aps-probe"
+    install_into "$STORE"
+    rm -f "$CERT"
+    echo "persistent $FILE"
+    exit 0
+fi
+
+SOURCE=$STORE
+[ -d "$APEX" ] && SOURCE=$APEX
+COPY=/data/local/tmp/aps-cacerts
+rm -rf "$COPY"
+mkdir -p -m 700 "$COPY"
+cp "$SOURCE"/* "$COPY"/
+mount -t tmpfs tmpfs "$STORE"
+cp "$COPY"/* "$STORE"/
+rm -rf "$COPY"
+chown root:root "$STORE" "$STORE"/*
+chmod 755 "$STORE"
+chmod 644 "$STORE"/*
+chcon u:object_r:system_file:s0 "$STORE" "$STORE"/* 2>/dev/null || true
+install_into "$STORE"
+
+if [ -d "$APEX" ]; then
+    mount --bind "$STORE" "$APEX"
+    for Z in $(pidof zygote zygote64); do
+        nsenter --mount=/proc/$Z/ns/mnt -- /bin/mount --bind "$STORE" "$APEX"
+        for P in $(ps -o PID= -P "$Z"); do
+            nsenter --mount=/proc/$P/ns/mnt -- /bin/mount --bind "$STORE" "$APEX" || true
+        done
+    done
+fi
+
+rm -f "$CERT"
+echo "overlay $FILE"
+"#;
+
+/// What `install_ca` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InstallOutcome {
+    /// Copied into the system store as `file`
+    System {
+        file: String,
+        /// False when it lives in a tmpfs overlay that is gone after a reboot
+        persistent: bool,
+    },
+    /// Pushed to `path` for the user to install
+    Pushed {
+        path: String,
+        opened: InstallScreen,
+    },
+}
+
+/// What was opened on the device for the user to finish the installation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstallScreen {
+    /// The certificate installer, already showing the certificate
+    Installer,
+    /// Security settings, where "Install a certificate" is found
+    SecuritySettings,
+}
+
+/// How shell commands get root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RootShell {
+    /// adbd itself runs as root (`adb root`, emulator images without Play)
+    Adbd,
+    /// Through `su`, e.g. Magisk
+    Su,
+}
+
+/// Install `certificate` on the device, pushed as `<name>.crt` for the user store
+///
+/// The system store is used when the device has root, unless `user_store` is set.
+pub fn install_ca(serial: &str, certificate: &Certificate, name: &str, user_store: bool) -> AppResult<InstallOutcome> {
+    if !certificate.is_ca() {
+        return Err(AppError::certificate(format!(
+            "{} is not a CA certificate, Android would not use it to trust servers",
+            certificate.subject()
+        )));
+    }
+
+    match root_shell(serial) {
+        Some(root) if !user_store => install_system(serial, certificate, root),
+        _ => install_user(serial, certificate, name),
+    }
+}
+
+/// Find out whether and how shell commands can run as root
+pub fn root_shell(serial: &str) -> Option<RootShell> {
+    if shell(serial, "id -u").ok()? == "0" {
+        return Some(RootShell::Adbd);
+    }
+    match shell(serial, &as_root(RootShell::Su, "id -u")) {
+        Ok(uid) if uid == "0" => Some(RootShell::Su),
+        _ => None,
+    }
+}
+
+/// Wrap `command`, which must not contain single quotes, to run as root
+pub fn as_root(root: RootShell, command: &str) -> String {
+    match root {
+        RootShell::Adbd => command.to_string(),
+        RootShell::Su => format!("su -c '{}'", command),
+    }
+}
+
+// Internal helper functions
+
+fn install_system(serial: &str, certificate: &Certificate, root: RootShell) -> AppResult<InstallOutcome> {
+    let hash = certificate.subject_hash();
+    let staged = format!("{}/aps-{}.pem", DEVICE_TEMP_DIR, hash);
+    let script_path = format!("{}/aps-install-ca.sh", DEVICE_TEMP_DIR);
+    let script = SYSTEM_INSTALL_SCRIPT
+        .replace("@CERT@", &staged)
+        .replace("@HASH@", &hash);
+
+    push_content(serial, certificate.to_pem().as_bytes(), &staged)?;
+    push_content(serial, script.as_bytes(), &script_path)?;
+    let output = shell(serial, &as_root(root, &format!("sh {}", script_path)));
+    let _ = shell(serial, &format!("rm -f {}", script_path));
+    let output = output?;
+
+    let result = output.lines().last().unwrap_or("");
+    match result.split_once(' ') {
+        Some(("persistent", file)) => Ok(InstallOutcome::System {
+            file: file.to_string(),
+            persistent: true,
+        }),
+        Some(("overlay", file)) => Ok(InstallOutcome::System {
+            file: file.to_string(),
+            persistent: false,
+        }),
+        _ => Err(AppError::certificate(format!(
+            "installing into the system store failed: {}",
+            output
+        ))),
+    }
+}
+
+fn install_user(serial: &str, certificate: &Certificate, name: &str) -> AppResult<InstallOutcome> {
+    let path = format!("{}/{}.crt", DOWNLOAD_DIR, name);
+    push_content(serial, certificate.to_pem().as_bytes(), &path)?;
+
+    let sdk = execute_device_command_string(serial, AdbCommand::GetProp("ro.build.version.sdk".to_string()))?
+        .parse::<u32>()
+        .unwrap_or(0);
+    let (opened, command) = if sdk >= SDK_SETTINGS_ONLY {
+        (InstallScreen::SecuritySettings, "am start -a android.settings.SECURITY_SETTINGS".to_string())
+    } else {
+        (
+            InstallScreen::Installer,
+            format!(
+                "am start -a android.intent.action.VIEW -t application/x-x509-ca-cert -d file://{} -n com.android.certinstaller/.CertInstallerMain",
+                path
+            ),
+        )
+    };
+
+    // am reports a missing activity on its output and still exits with 0
+    let output = shell(serial, &command)?;
+    if output.contains("Error") {
+        return Err(AppError::certificate(format!(
+            "pushed {} but could not open the installer: {}",
+            path, output
+        )));
+    }
+
+    Ok(InstallOutcome::Pushed { path, opened })
+}
+
+fn shell(serial: &str, command: &str) -> AppResult<String> {
+    execute_device_command_string(serial, AdbCommand::Shell(command.to_string()))
+}
+
+/// Push `content` to `remote` through a local temporary file
+fn push_content(serial: &str, content: &[u8], remote: &str) -> AppResult<()> {
+    let file_name = remote.rsplit('/').next().unwrap_or(remote);
+    let local = std::env::temp_dir().join(format!("{}-{}", std::process::id(), file_name));
+    fs::write(&local, content).map_err(|e| AppError::state_file_error(&local, e))?;
+
+    let pushed = execute_device_command_string(
+        serial,
+        AdbCommand::Push {
+            local: local.display().to_string(),
+            remote: remote.to_string(),
+        },
+    );
+    let _ = fs::remove_file(&local);
+    pushed.map(|_| ())
+}
@@ -0,0 +1,6 @@
+//! Certificates for intercepting HTTPS traffic and their installation on devices
+
+pub mod authority;
+pub mod certificate;
+pub mod install;
+pub mod store;
@@ -0,0 +1,186 @@
+//! Reading the CA certificate stores of a device
+
+use std::fmt;
+use base64::Engine;
+use crate::adb::commands::{execute_device_command_string, AdbCommand};
+use crate::cert::certificate::Certificate;
+use crate::cert::install::{as_root, root_shell};
+use crate::error::AppResult;
+
+/// System store before Android 14
+const SYSTEM_STORE: &str = "/system/etc/security/cacerts";
+
+/// System store from Android 14 on, updated with the Conscrypt APEX
+const APEX_STORE: &str = "/apex/com.android.conscrypt/cacerts";
+
+/// Certificates the primary user added
+const USER_STORE: &str = "/data/misc/user/0/cacerts-added";
+
+/// Prints `==> <file>` and the base64 of each file of @DIR@, which covers
+/// both the PEM files of the system store and the DER files of the user store
+const LIST_STORE_SCRIPT: &str = "if [ ! -d @DIR@ ]; then echo APS_MISSING; \
+elif [ ! -r @DIR@ ] || [ ! -x @DIR@ ]; then echo APS_DENIED; \
+else for f in @DIR@/*; do [ -f \"$f\" ] && echo \"==> ${f##*/}\" && base64 \"$f\"; done; fi; true";
+
+/// Which store a listing comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    System,
+    User,
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreKind::System => write!(f, "System"),
+            StoreKind::User => write!(f, "User"),
+        }
+    }
+}
+
+/// One file of a store
+#[derive(Debug, Clone)]
+pub struct StoreEntry {
+    pub file: String,
+    /// `None` when the file does not hold a readable certificate
+    pub certificate: Option<Certificate>,
+}
+
+/// What could be read of a store
+#[derive(Debug, Clone)]
+pub enum StoreContents {
+    Entries(Vec<StoreEntry>),
+    /// The directory does not exist, e.g. no user certificates were ever added
+    Missing,
+    /// The directory is not readable without root
+    Denied,
+}
+
+/// A CA store of the device
+#[derive(Debug, Clone)]
+pub struct CaStore {
+    pub kind: StoreKind,
+    pub path: &'static str,
+    pub contents: StoreContents,
+}
+
+/// Read the system store in effect and the user store of the device
+///
+/// The user store is only readable with root, which is used when available.
+pub fn list_stores(serial: &str) -> AppResult<Vec<CaStore>> {
+    let root = root_shell(serial);
+    let run = |dir: &str| {
+        let script = LIST_STORE_SCRIPT.replace("@DIR@", dir);
+        let command = match root {
+            Some(root) => as_root(root, &script),
+            None => script,
+        };
+        execute_device_command_string(serial, AdbCommand::Shell(command))
+    };
+
+    let mut system = read_store(StoreKind::System, APEX_STORE, run(APEX_STORE)?);
+    if matches!(system.contents, StoreContents::Missing) {
+        system = read_store(StoreKind::System, SYSTEM_STORE, run(SYSTEM_STORE)?);
+    }
+    let user = read_store(StoreKind::User, USER_STORE, run(USER_STORE)?);
+
+    Ok(vec![system, user])
+}
+
+// Internal helper functions
+
+fn read_store(kind: StoreKind, path: &'static str, output: String) -> CaStore {
+    let contents = match output.trim() {
+        "APS_MISSING" => StoreContents::Missing,
+        "APS_DENIED" => StoreContents::Denied,
+        output => StoreContents::Entries(parse_listing(output)),
+    };
+    CaStore { kind, path, contents }
+}
+
+/// Split the script output into files and decode each of them
+fn parse_listing(output: &str) -> Vec<StoreEntry> {
+    let mut entries = Vec::new();
+    let mut current: Option<(String, String)> = None;
+
+    for line in output.lines() {
+        if let Some(file) = line.strip_prefix("==> ") {
+            entries.extend(current.take().map(|(file, encoded)| decode_entry(file, &encoded)));
+            current = Some((file.trim().to_string(), String::new()));
+        } else if let Some((_, encoded)) = &mut current {
+            encoded.push_str(line.trim());
+        }
+    }
+    entries.extend(current.map(|(file, encoded)| decode_entry(file, &encoded)));
+
+    entries
+}
+
+fn decode_entry(file: String, encoded: &str) -> StoreEntry {
+    let certificate = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|content| Certificate::from_bytes(content).ok());
+    StoreEntry { file, certificate }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cert::authority::CertificateAuthority;
+    use crate::config::paths::TestDataDir;
+
+    /// Encode a file the way `base64` on the device prints it, in 76-character lines
+    fn listed(file: &str, content: &[u8]) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(content);
+        let lines: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(76)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+        format!("==> {}\n{}\n", file, lines.join("\n"))
+    }
+
+    #[test]
+    fn missing_and_unreadable_stores() {
+        assert!(matches!(read_store(StoreKind::User, USER_STORE, "APS_MISSING\n".to_string()).contents, StoreContents::Missing));
+        assert!(matches!(read_store(StoreKind::User, USER_STORE, "APS_DENIED\r\n".to_string()).contents, StoreContents::Denied));
+        match read_store(StoreKind::User, USER_STORE, String::new()).contents {
+            StoreContents::Entries(entries) => assert!(entries.is_empty()),
+            other => panic!("expected an empty store, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pem_and_der_files_are_listed() {
+        let _data = TestDataDir::new("store-listing");
+        let authority = CertificateAuthority::load_or_create().unwrap();
+        let der = authority.cert_der().to_vec();
+        let certificate = Certificate::from_der(der.clone()).unwrap();
+        let hash = certificate.subject_hash();
+
+        let output = format!(
+            "{}{}{}",
+            listed(&format!("{}.0", hash), certificate.to_pem().as_bytes()),
+            listed("notes.txt", b"not a certificate"),
+            listed(&format!("{}.1", hash), &der)
+        );
+        let store = read_store(StoreKind::System, SYSTEM_STORE, output);
+
+        let StoreContents::Entries(entries) = store.contents else {
+            panic!("expected entries");
+        };
+        let files: Vec<(&str, Option<String>)> = entries
+            .iter()
+            .map(|entry| (entry.file.as_str(), entry.certificate.as_ref().map(|c| c.subject_hash())))
+            .collect();
+        assert_eq!(
+            files,
+            [
+                (format!("{}.0", hash).as_str(), Some(hash.clone())),
+                ("notes.txt", None),
+                (format!("{}.1", hash).as_str(), Some(hash.clone())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Commands for the local CA and CA certificates on the device
+
+use std::collections::HashMap;
+use std::path::Path;
+use colored::*;
+use crate::cert::authority::{CertificateAuthority, CA_COMMON_NAME};
+use crate::cert::certificate::Certificate;
+use crate::cert::install::{install_ca, InstallOutcome, InstallScreen};
+use crate::cert::store::{list_stores, CaStore, StoreContents, StoreKind};
+use crate::config::args::CaAction;
+use crate::error::AppResult;
+
+/// File name of exported certificates, without extension
+const EXPORT_NAME: &str = "android-proxy-setter-ca";
+
+/// Where the user installs CA certificates by hand
+const INSTALL_SETTINGS_PATH: &str = "Settings > Security > Encryption & credentials > Install a certificate > CA certificate";
+
+/// Run a `ca` subcommand for the device
+pub fn run_ca_command(serial: &str, action: &CaAction) -> AppResult<()> {
+    match action {
+        CaAction::Export { out } => export_ca(out),
+        CaAction::List => show_ca_stores(serial),
+        CaAction::Install { cert, user } => {
+            let (certificate, name) = match cert {
+                Some(path) => {
+                    let name = path
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .unwrap_or_else(|| EXPORT_NAME.to_string());
+                    (Certificate::from_file(path)?, name)
+                }
+                None => {
+                    let authority = CertificateAuthority::load_or_create()?;
+                    (Certificate::from_der(authority.cert_der().to_vec())?, EXPORT_NAME.to_string())
+                }
+            };
+
+            println!(
+                "Installing {} ({}.0) on {}...",
+                certificate.subject().cyan(),
+                certificate.subject_hash(),
+                serial
+            );
+            report_install(&install_ca(serial, &certificate, &name, *user)?);
+            Ok(())
+        }
+    }
+}
+
+/// Write the local CA certificate as PEM and DER into `out`, generating the CA on first use
+pub fn export_ca(out: &Path) -> AppResult<()> {
+    let authority = CertificateAuthority::load_or_create()?;
+    let (pem, der) = authority.export(out, EXPORT_NAME)?;
+    println!("{}", "✅ CA certificate exported:".green().bold());
+    println!("  {}", pem.display());
+    println!("  {}", der.display());
+    println!("Install it with {}, or on the device under {}", "ca install".cyan(), INSTALL_SETTINGS_PATH);
+    Ok(())
+}
+
+/// Print the CA stores of the device, flagging the local CA, expired and duplicate certificates
+pub fn show_ca_stores(serial: &str) -> AppResult<()> {
+    let stores = list_stores(serial)?;
+    let local = match CertificateAuthority::load()? {
+        Some(authority) => Some(Certificate::from_der(authority.cert_der().to_vec())?.fingerprint()),
+        None => None,
+    };
+
+    // Where each certificate was seen first, to point duplicates at it
+    let mut first_seen: HashMap<String, String> = HashMap::new();
+    let mut local_found = Vec::new();
+    let mut expired = 0;
+    let mut duplicates = 0;
+
+    for store in &stores {
+        print_store_header(store);
+        let entries = match &store.contents {
+            StoreContents::Entries(entries) => entries,
+            _ => continue,
+        };
+
+        for entry in entries {
+            let certificate = match &entry.certificate {
+                Some(certificate) => certificate,
+                None => {
+                    println!("  {:<12} {}", entry.file, "(not a readable certificate)".red());
+                    continue;
+                }
+            };
+
+            let fingerprint = certificate.fingerprint();
+            let mut notes = Vec::new();
+            if local.as_deref() == Some(fingerprint.as_str()) {
+                notes.push("local CA".green().bold());
+                local_found.push(store.kind);
+            } else if certificate.subject().contains(CA_COMMON_NAME) {
+                notes.push("CA of another or an older install of this tool".yellow());
+            }
+            if certificate.is_expired() {
+                notes.push("expired".red().bold());
+                expired += 1;
+            }
+            let location = format!("{}/{}", store.kind, entry.file);
+            match first_seen.get(&fingerprint) {
+                Some(original) => {
+                    notes.push(format!("duplicate of {}", original).yellow());
+                    duplicates += 1;
+                }
+                None => {
+                    first_seen.insert(fingerprint.clone(), location);
+                }
+            }
+
+            let subject = if notes.is_empty() { certificate.subject().normal() } else { certificate.subject().bold() };
+            println!("  {:<12} {}", entry.file, subject);
+            let expires = format!("expires {}", certificate.not_after().date());
+            println!("  {:<12} {}  SHA-256 {}", "", expires, fingerprint.dimmed());
+            for note in notes {
+                println!("  {:<12} ← {}", "", note);
+            }
+        }
+    }
+
+    let user_unreadable = stores
+        .iter()
+        .any(|store| store.kind == StoreKind::User && matches!(store.contents, StoreContents::Denied));
+
+    println!();
+    match (&local, local_found.as_slice()) {
+        (None, _) => println!("Local CA: not generated yet (see {})", "ca export".cyan()),
+        (Some(_), []) if user_unreadable => println!(
+            "Local CA: {}, the user store needs root to check",
+            "not in the system store".yellow()
+        ),
+        (Some(_), []) => println!("Local CA: {}, run {}", "not installed".red(), "ca install".cyan()),
+        (Some(_), kinds) if kinds.contains(&StoreKind::System) => {
+            println!("Local CA: {}", "trusted by all apps (system store)".green())
+        }
+        (Some(_), _) => println!(
+            "Local CA: {}",
+            "in the user store, only trusted by apps whose network security config allows user CAs".yellow()
+        ),
+    }
+    println!("Expired: {}, duplicates: {}", expired, duplicates);
+
+    Ok(())
+}
+
+// Internal helper functions
+
+fn print_store_header(store: &CaStore) {
+    let title = format!("{} store", store.kind).blue().bold();
+    match &store.contents {
+        StoreContents::Entries(entries) => {
+            let plural = if entries.len() == 1 { "" } else { "s" };
+            println!("\n{} {} ({} certificate{})", title, store.path, entries.len(), plural)
+        }
+        StoreContents::Missing => println!("\n{} {} (none)", title, store.path),
+        StoreContents::Denied => println!(
+            "\n{} {} {}",
+            title,
+            store.path,
+            "(needs root to read, the local CA may still be installed here)".yellow()
+        ),
+    }
+}
+
+fn report_install(outcome: &InstallOutcome) {
+    match outcome {
+        InstallOutcome::System { file, persistent: true } => {
+            println!("{} {}", "✅ Installed into the system store as".green().bold(), file);
+        }
+        InstallOutcome::System { file, persistent: false } => {
+            println!("{} {}", "✅ Installed into the system store as".green().bold(), file);
+            println!(
+                "{}",
+                "⚠️ /system is read-only, so the store is overlaid in memory: run `ca install` again after a reboot".yellow()
+            );
+        }
+        InstallOutcome::Pushed { path, opened } => {
+            println!("{} {}", "✅ Pushed to".green().bold(), path);
+            match opened {
+                InstallScreen::Installer => println!("Confirm the installation in the certificate installer on the device"),
+                InstallScreen::SecuritySettings => {
+                    println!("Security settings are open on the device, finish with {}", INSTALL_SETTINGS_PATH);
+                    println!("and pick the file from Download");
+                }
+            }
+            println!(
+                "{}",
+                "⚠️ This is the user store: apps targeting Android 7+ only trust it when their network security config allows it"
+                    .yellow()
+            );
+        }
+    }
+}
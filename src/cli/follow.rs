@@ -0,0 +1,145 @@
+//! Keeping device proxies pointed at this computer when its address changes
+
+use std::collections::BTreeSet;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+use colored::*;
+use crate::adb::device::get_connected_devices;
+use crate::cli::interactive::proxy_options;
+use crate::config::args::Args;
+use crate::config::time::{format_timestamp, unix_now};
+use crate::error::AppResult;
+use crate::network::interfaces::{list_host_addresses, select_host_address};
+use crate::proxy::history;
+use crate::proxy::manager::{read_proxy_state, set_proxy, ProxyOptions};
+use crate::proxy::settings::ProxySettings;
+use crate::proxy::state::ProxyState;
+
+/// Watch the addresses of this computer and re-point devices that used a vanished one
+///
+/// Only devices whose proxy was set by this tool (the latest history entry
+/// matches the device) are changed. Runs until interrupted.
+pub fn run_follow(args: &Args, interval: u64) -> AppResult<()> {
+    let interval = Duration::from_secs(interval);
+    // A re-pointed proxy keeps the expiry of the one it replaces
+    let options = ProxyOptions {
+        keep_lease: true,
+        ..proxy_options(args)
+    };
+
+    let mut current = host_addresses()?;
+    // Every address this computer had while watching, to recognize stale proxies
+    let mut known = current.clone();
+
+    log(&format!(
+        "Watching host addresses {} (every {}s, Ctrl-C to stop)",
+        describe(&current).green(),
+        interval.as_secs()
+    ));
+
+    loop {
+        thread::sleep(interval);
+
+        let addresses = match host_addresses() {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                log(&format!("{} {}", "⚠️ Failed to list host addresses:".yellow(), e));
+                continue;
+            }
+        };
+        if addresses == current {
+            continue;
+        }
+
+        log(&format!(
+            "Host addresses changed: {} -> {}",
+            describe(&current).yellow(),
+            describe(&addresses).green()
+        ));
+        known.extend(addresses.iter().copied());
+        current = addresses;
+
+        // Devices can only follow to an address that exists again
+        if !current.is_empty() {
+            repoint_devices(args, &current, &known, &options);
+        }
+    }
+}
+
+// Internal helper functions
+
+/// Re-apply the proxy of devices still pointing at an address this computer no longer has
+fn repoint_devices(args: &Args, current: &BTreeSet<Ipv4Addr>, known: &BTreeSet<Ipv4Addr>, options: &ProxyOptions) {
+    let devices = match &args.serial {
+        Some(serial) => vec![serial.clone()],
+        None => match get_connected_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                log(&format!("{} {}", "⚠️ Failed to list devices:".yellow(), e));
+                return;
+            }
+        },
+    };
+
+    for serial in devices {
+        let state = match read_proxy_state(&serial) {
+            Ok(state) => state,
+            Err(e) => {
+                log(&format!("{} {}: {}", "⚠️ Failed to read proxy of".yellow(), serial, e));
+                continue;
+            }
+        };
+        let ProxyState::HostPort { host, port } = &state else {
+            continue;
+        };
+        let stale = match host.parse::<Ipv4Addr>() {
+            Ok(ip) => known.contains(&ip) && !current.contains(&ip),
+            Err(_) => false,
+        };
+        if !stale || !set_by_tool(&serial, &state) {
+            continue;
+        }
+
+        let result = select_host_address(&serial, args.iface.as_deref())
+            .and_then(|ip| ProxySettings::new(*port, ip))
+            .and_then(|settings| {
+                log(&format!(
+                    "Moving {} from {} to {}",
+                    serial.bold(),
+                    state.to_string().yellow(),
+                    settings.to_proxy_string().green()
+                ));
+                set_proxy(&serial, &settings, options)
+            });
+        if let Err(e) = result {
+            log(&format!("{} {}: {}", "⚠️ Failed to move proxy of".yellow(), serial, e));
+        }
+    }
+}
+
+/// Whether the latest recorded change on the device set its current proxy
+fn set_by_tool(serial: &str, state: &ProxyState) -> bool {
+    match history::entries_for(serial) {
+        Ok(entries) => entries
+            .first()
+            .is_some_and(|entry| ProxyState::parse(&entry.new) == *state),
+        Err(_) => false,
+    }
+}
+
+fn host_addresses() -> AppResult<BTreeSet<Ipv4Addr>> {
+    Ok(list_host_addresses()?.into_iter().map(|address| address.ip).collect())
+}
+
+fn describe(addresses: &BTreeSet<Ipv4Addr>) -> String {
+    if addresses.is_empty() {
+        return "none".to_string();
+    }
+    addresses.iter().map(|ip| ip.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/// Print a line prefixed with the current time
+fn log(message: &str) {
+    println!("[{}] {}", format_timestamp(unix_now()), message);
+}
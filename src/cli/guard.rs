@@ -0,0 +1,251 @@
+//! Dead-man switch clearing device proxies while the local proxy is down
+
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use colored::*;
+use crate::adb::device::get_connected_devices;
+use crate::cli::interactive::{detect_port, proxy_options};
+use crate::config::args::Args;
+use crate::emulator::detect::is_emulator_host_alias;
+use crate::error::{AppError, AppResult};
+use crate::network::interfaces::list_host_addresses;
+use crate::proxy::lease::{self, Lease};
+use crate::proxy::manager::{read_proxy_state, resume_proxy, suspend_proxy, ProxyOptions};
+use crate::proxy::preflight::{probe_from_host, Reachability};
+use crate::proxy::settings::{is_loopback_host, ProxySettings, LOOPBACK_HOST};
+use crate::proxy::state::ProxyState;
+
+/// Time between two probes of the listener
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watch the local proxy listener and clear the devices using it while it is down
+///
+/// Runs until Ctrl-C. Devices cleared by the guard get their proxy back
+/// once the listener accepts connections again, or when the guard stops,
+/// unless it was changed or its lease expired meanwhile. Clearing and
+/// restoring leave the history and the lease alone.
+pub fn run_guard(args: &Args, grace: u64) -> AppResult<()> {
+    let port = match args.port {
+        Some(port) => port,
+        None => detect_port(false)?,
+    };
+    let host = args.ip.clone().unwrap_or_else(|| LOOPBACK_HOST.to_string());
+    let listener = ProxySettings::new(port, Some(host))?;
+    let grace = Duration::from_secs(grace);
+    let options = proxy_options(args);
+
+    let (shutdown, stop) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = shutdown.send(());
+    })
+    .map_err(|e| AppError::proxy_server(format!("cannot handle Ctrl-C: {}", e)))?;
+
+    println!(
+        "Guarding proxy listener {} (devices are cleared after {}s down, Ctrl-C to stop)",
+        listener.to_proxy_string().green(),
+        grace.as_secs()
+    );
+
+    let mut down_since: Option<Instant> = None;
+    let mut cleared: Vec<Suspended> = Vec::new();
+
+    loop {
+        match probe_from_host(&listener) {
+            Reachability::Reachable => {
+                if down_since.take().is_some() {
+                    println!("{}", "Proxy listener is back".green());
+                }
+                if !cleared.is_empty() {
+                    cleared.retain(|device| !restore_device(device, &options));
+                }
+            }
+            Reachability::Unreachable(reason) | Reachability::Unknown(reason) => {
+                let since = *down_since.get_or_insert_with(|| {
+                    println!("{} {}", "⚠️ Proxy listener is down:".yellow(), reason);
+                    Instant::now()
+                });
+                // Keep looking, devices connected while it is down are cleared too
+                if since.elapsed() >= grace {
+                    cleared.extend(clear_devices(args, &listener, &options));
+                }
+            }
+        }
+
+        match stop.recv_timeout(POLL_INTERVAL) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // Suspended devices would otherwise stay cleared with nobody left to restore them
+    println!("\n{}", "Stopping guard...".yellow());
+    if !cleared.is_empty() && down_since.is_some() {
+        println!("{}", "⚠️ Restoring proxies although the listener is still down".yellow());
+    }
+    for device in &cleared {
+        if !restore_device(device, &options) {
+            println!(
+                "Set it again with {} once the device is back",
+                format!("--serial {} --set", device.serial).cyan()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Internal helper functions
+
+/// A device whose proxy the guard cleared
+struct Suspended {
+    serial: String,
+    state: ProxyState,
+    /// Lease of the proxy when it was cleared
+    lease: Option<Lease>,
+}
+
+/// Clear every device whose proxy points at the listener, returning what was cleared
+fn clear_devices(args: &Args, listener: &ProxySettings, options: &ProxyOptions) -> Vec<Suspended> {
+    let devices = match &args.serial {
+        Some(serial) => vec![serial.clone()],
+        None => match get_connected_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                println!("{} {}", "⚠️ Failed to list devices:".yellow(), e);
+                return Vec::new();
+            }
+        },
+    };
+
+    let mut cleared = Vec::new();
+    for serial in devices {
+        let state = match read_proxy_state(&serial) {
+            Ok(state) => state,
+            Err(e) => {
+                println!("{} {}: {}", "⚠️ Failed to read proxy of".yellow(), serial, e);
+                continue;
+            }
+        };
+        if !points_at_listener(&serial, &state, args, listener) {
+            continue;
+        }
+
+        println!("Clearing proxy {} on {}", state.to_string().yellow(), serial.bold());
+        let lease = lease::get_lease(&serial).ok().flatten().filter(|lease| lease.covers(&state));
+        match suspend_proxy(&serial, options) {
+            Ok(()) => cleared.push(Suspended { serial, state, lease }),
+            Err(e) => println!("{} {}: {}", "⚠️ Failed to clear proxy on".yellow(), serial, e),
+        }
+    }
+
+    cleared
+}
+
+/// Put back a proxy cleared by the guard, returning whether the device is done with
+fn restore_device(device: &Suspended, options: &ProxyOptions) -> bool {
+    let Suspended { serial, state, lease } = device;
+    match read_proxy_state(serial) {
+        Ok(ProxyState::Unset) => {}
+        Ok(current) => {
+            println!("Not restoring {}, its proxy was changed to {}", serial, current);
+            return true;
+        }
+        Err(e) => {
+            println!("{} {}: {}", "⚠️ Failed to read proxy of".yellow(), serial, e);
+            return false;
+        }
+    }
+
+    if lease.as_ref().is_some_and(|lease| lease.remaining().is_none()) {
+        println!("Not restoring {}, its proxy lease expired meanwhile", serial);
+        if let Err(e) = lease::release_lease(serial) {
+            println!("{} {}", "⚠️ Failed to update proxy lease:".yellow(), e);
+        }
+        return true;
+    }
+
+    println!("Restoring proxy {} on {}", state.to_string().green(), serial.bold());
+    match resume_proxy(serial, state, options) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{} {}: {}", "⚠️ Failed to restore proxy on".yellow(), serial, e);
+            false
+        }
+    }
+}
+
+/// Whether a device proxy leads to the guarded listener on this computer
+fn points_at_listener(serial: &str, state: &ProxyState, args: &Args, listener: &ProxySettings) -> bool {
+    let ProxyState::HostPort { host, port } = state else {
+        return false;
+    };
+    if *port != listener.port {
+        return false;
+    }
+
+    if let Some(ip) = &args.ip {
+        return host == ip || *host == listener.ip;
+    }
+
+    is_loopback_host(host)
+        || is_emulator_host_alias(serial, host)
+        || list_host_addresses()
+            .map(|addresses| addresses.iter().any(|address| address.ip.to_string() == *host))
+            .unwrap_or(false)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::adb::fake::FakeAdb;
+    use crate::config::paths::TestDataDir;
+
+    fn options() -> ProxyOptions {
+        ProxyOptions {
+            verify_timeout: Duration::from_secs(1),
+            force: false,
+            ttl: None,
+            keep_lease: false,
+        }
+    }
+
+    fn suspended(lease: Option<Lease>) -> Suspended {
+        Suspended {
+            serial: "emulator-5554".to_string(),
+            state: ProxyState::parse("10.0.2.2:8083"),
+            lease,
+        }
+    }
+
+    #[test]
+    fn suspended_proxies_are_restored() {
+        let _data = TestDataDir::new("guard-restore");
+        let adb = FakeAdb::install("guard-restore");
+        adb.set("http_proxy", "10.0.2.2:8083");
+
+        suspend_proxy("emulator-5554", &options()).unwrap();
+        assert_eq!(adb.get("http_proxy").as_deref(), Some(":0"));
+
+        assert!(restore_device(&suspended(None), &options()));
+        assert_eq!(adb.get("http_proxy").as_deref(), Some("10.0.2.2:8083"));
+    }
+
+    #[test]
+    fn changed_or_expired_proxies_are_not_restored() {
+        let _data = TestDataDir::new("guard-skip");
+        let adb = FakeAdb::install("guard-skip");
+
+        adb.set("http_proxy", "192.168.1.20:8888");
+        assert!(restore_device(&suspended(None), &options()));
+        assert_eq!(adb.get("http_proxy").as_deref(), Some("192.168.1.20:8888"));
+
+        adb.set("http_proxy", ":0");
+        let expired = Lease {
+            id: 1,
+            proxy: "10.0.2.2:8083".to_string(),
+            expires_at: 1,
+        };
+        assert!(restore_device(&suspended(Some(expired)), &options()));
+        assert_eq!(adb.get("http_proxy").as_deref(), Some(":0"));
+    }
+}
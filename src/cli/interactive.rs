@@ -1,43 +1,90 @@
 //! Interactive command-line interface
 
 use std::io::{self, Write};
+use std::time::Duration;
 use colored::*;
-use crate::error::AppResult;
-use crate::config::args::Args;
-use crate::proxy::manager::{set_proxy, clear_proxy, view_proxy};
-use crate::proxy::settings::ProxySettings;
+use crate::error::{AppError, AppResult};
+use crate::config::args::{Args, Command, NetworkAction, RulesAction};
+use crate::emulator::shaping::{set_network_delay, set_network_speed, show_network_status};
+use crate::adb::mapping::MappingKind;
+use crate::cli::ca::run_ca_command;
+use crate::cli::serve::run_serve;
+use crate::cli::mappings::{apply_rule_set, remove_rule_set, run_mapping_command, show_rule_sets, snapshot_rule_set};
+use crate::proxy::manager::{set_proxy, clear_proxy, view_proxy, undo_proxy, restore_proxy, ProxyOptions};
+use crate::proxy::detect::{detect_local_proxies, DetectedProxy};
+use crate::proxy::settings::{ProxySettings, DEFAULT_PORT};
+use crate::proxy::state::ProxyState;
 use crate::adb::device::restart_adb_server;
+use crate::emulator::detect::detect_emulator;
+use crate::network::interfaces::select_host_address;
 
 /// Run the interactive CLI mode
-pub fn run_cli_mode(args: Args, current_proxy_setting: String) -> AppResult<()> {
+pub fn run_cli_mode(args: Args, serial: &str, current_proxy_setting: ProxyState) -> AppResult<()> {
+    let options = proxy_options(&args);
+
+    // Subcommands take precedence over the direct action flags
+    match &args.command {
+        Some(Command::Undo) => return undo_proxy(serial, &options),
+        Some(Command::Restore { index }) => return restore_proxy(serial, *index, &options),
+        Some(Command::Reverse { action }) => return run_mapping_command(serial, MappingKind::Reverse, action),
+        Some(Command::Forward { action }) => return run_mapping_command(serial, MappingKind::Forward, action),
+        Some(Command::Rules { action }) => {
+            return match action {
+                RulesAction::List => show_rule_sets(),
+                RulesAction::Save { name } => snapshot_rule_set(serial, name),
+                RulesAction::Apply { name } => apply_rule_set(serial, name),
+                RulesAction::Delete { name } => remove_rule_set(name),
+            }
+        }
+        Some(Command::Network { action }) => {
+            return match action {
+                NetworkAction::Speed { preset } => set_network_speed(serial, *preset),
+                NetworkAction::Delay { preset } => set_network_delay(serial, *preset),
+                NetworkAction::Status => show_network_status(serial),
+            }
+        }
+        Some(Command::Serve(serve)) => return run_serve(&args, serve, Some(serial)),
+        Some(Command::Ca { action }) => return run_ca_command(serial, action),
+        Some(Command::History)
+        | Some(Command::Launch { .. })
+        | Some(Command::Guard { .. })
+        | Some(Command::Follow { .. })
+        | Some(Command::RebootWatch { .. })
+        | Some(Command::LeaseWatch { .. })
+        | None => {}
+    }
+
     // Interactive mode or direct action based on flags
     if args.set {
-        let settings = ProxySettings::new(args.port, args.ip)?;
-        set_proxy(&settings)?;
+        let settings = build_settings(&args, serial, false)?;
+        set_proxy(serial, &settings, &options)?;
+        if let Some(rules) = &args.rules {
+            apply_rule_set(serial, rules)?;
+        }
     } else if args.clear {
-        clear_proxy()?;
+        clear_proxy(serial, &options)?;
     } else if args.restart_adb {
         restart_adb_server()?;
     } else if args.help_commands {
         show_available_commands()?;
     } else if args.view {
-        view_proxy()?;
+        view_proxy(serial)?;
     } else {
-        run_interactive_mode(current_proxy_setting, args)?;
+        run_interactive_mode(serial, current_proxy_setting, args)?;
     }
 
     Ok(())
 }
 
 /// Run the interactive menu
-fn run_interactive_mode(current_proxy_setting: String, args: Args) -> AppResult<()> {
+fn run_interactive_mode(serial: &str, current_proxy_setting: ProxyState, args: Args) -> AppResult<()> {
     println!("\n{}", "=== Android Proxy Manager ===".green().bold());
     println!(
         "Current proxy setting: {}",
-        if current_proxy_setting.is_empty() || current_proxy_setting == ":0" {
-            "Not set".red()
+        if current_proxy_setting.is_set() {
+            current_proxy_setting.to_string().green()
         } else {
-            current_proxy_setting.green()
+            "Not set".red()
         }
     );
     println!("\nPlease select an option:");
@@ -55,11 +102,11 @@ fn run_interactive_mode(current_proxy_setting: String, args: Args) -> AppResult<
 
     match choice.trim() {
         "1" => {
-            let settings = ProxySettings::new(args.port, args.ip)?;
-            set_proxy(&settings)?;
+            let settings = build_settings(&args, serial, true)?;
+            set_proxy(serial, &settings, &proxy_options(&args))?;
         }
-        "2" => clear_proxy()?,
-        "3" => view_proxy()?,
+        "2" => clear_proxy(serial, &proxy_options(&args))?,
+        "3" => view_proxy(serial)?,
         "4" => restart_adb_server()?,
         "5" => {
             println!("{}", "Exiting...".yellow());
@@ -74,6 +121,118 @@ fn run_interactive_mode(current_proxy_setting: String, args: Args) -> AppResult<
     Ok(())
 }
 
+/// Build the options for proxy changes from the arguments
+pub fn proxy_options(args: &Args) -> ProxyOptions {
+    ProxyOptions {
+        verify_timeout: Duration::from_secs(args.verify_timeout),
+        force: args.force,
+        ttl: args.ttl,
+        keep_lease: false,
+    }
+}
+
+/// Build proxy settings from the arguments, validating them before anything is written
+fn build_settings(args: &Args, serial: &str, interactive: bool) -> AppResult<ProxySettings> {
+    let port = match args.port {
+        Some(port) => port,
+        None => detect_port(interactive)?,
+    };
+    settings_for_port(args, serial, port)
+}
+
+/// Build proxy settings for a known port, choosing the host the device can reach
+pub fn settings_for_port(args: &Args, serial: &str, port: u16) -> AppResult<ProxySettings> {
+    let settings = if args.usb {
+        ProxySettings::usb(port)?
+    } else {
+        let ip = match (&args.ip, &args.iface) {
+            (Some(ip), _) => Some(ip.clone()),
+            (None, Some(iface)) => select_host_address(serial, Some(iface))?,
+            (None, None) => match detect_emulator(serial)? {
+                Some(kind) => {
+                    println!(
+                        "Detected {} {}, using its host loopback alias {}",
+                        kind,
+                        serial,
+                        kind.host_alias().green()
+                    );
+                    Some(kind.host_alias().to_string())
+                }
+                None => select_host_address(serial, None)?,
+            },
+        };
+        ProxySettings::new(port, ip)?
+    };
+
+    if args.check_dns {
+        let addresses = settings.resolve()?;
+        let addresses: Vec<String> = addresses.iter().map(|ip| ip.to_string()).collect();
+        println!("{} resolves to {}", settings.ip, addresses.join(", ").green());
+    }
+
+    Ok(settings)
+}
+
+/// Pick the port of a running debugging proxy, falling back to the default port
+///
+/// A proxy guessed from its port alone is never picked without asking.
+pub fn detect_port(interactive: bool) -> AppResult<u16> {
+    let found = detect_local_proxies();
+    let identified: Vec<&DetectedProxy> = found.iter().filter(|proxy| proxy.identified).collect();
+
+    match (identified.as_slice(), found.as_slice()) {
+        (_, []) => Ok(DEFAULT_PORT),
+        ([proxy], [_]) => {
+            println!("Detected {}", proxy.to_string().green());
+            Ok(proxy.port)
+        }
+        _ if interactive => choose_detected_proxy(&found),
+        ([proxy], _) => {
+            println!("Detected {}", proxy.to_string().green());
+            Ok(proxy.port)
+        }
+        ([], _) => {
+            let guesses: Vec<String> = found.iter().map(|proxy| proxy.to_string()).collect();
+            println!(
+                "{} {}, using port {}",
+                "⚠️ Could not confirm the proxy listening on".yellow(),
+                guesses.join(", "),
+                DEFAULT_PORT
+            );
+            Ok(DEFAULT_PORT)
+        }
+        _ => Err(AppError::MultipleProxiesDetected {
+            candidates: identified
+                .iter()
+                .map(|proxy| format!("{} on {}", proxy.tool, proxy.port))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }),
+    }
+}
+
+/// Let the user choose between several detected proxies
+fn choose_detected_proxy(found: &[DetectedProxy]) -> AppResult<u16> {
+    println!("\n{}", "Several local proxies are running:".yellow());
+    for (index, proxy) in found.iter().enumerate() {
+        println!("{}. {}", index + 1, proxy);
+    }
+
+    print!("\nChoose the proxy to use (1-{}): ", found.len());
+    io::stdout().flush()?;
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice)?;
+
+    match choice.trim().parse::<usize>() {
+        Ok(index) if (1..=found.len()).contains(&index) => Ok(found[index - 1].port),
+        _ => {
+            println!("{}", format!("Invalid choice, using port {}", DEFAULT_PORT).red());
+            Ok(DEFAULT_PORT)
+        }
+    }
+}
+
 /// Show available commands and aliases
 pub fn show_available_commands() -> AppResult<()> {
     use colored::*;
@@ -88,8 +247,43 @@ pub fn show_available_commands() -> AppResult<()> {
     println!("  aps-restart                       - Restart ADB server");
 
     println!("\n{}", "Options:".blue());
-    println!("  --port <PORT>                     - Specify proxy port (default: 8083)");
-    println!("  --ip <IP_ADDRESS>                 - Specify IP address (auto-detected if not specified)");
+    println!("  --port <PORT>                     - Specify proxy port (default: detected local proxy, else 8083)");
+    println!("  --ip <HOST[:PORT]>                - Specify IP address or hostname (default: address on the device's subnet)");
+    println!("  --iface <NAME>                    - Use the address of this network interface");
+    println!("  --usb                             - Proxy over USB with adb reverse, no shared network needed");
+    println!("  --serial <SERIAL>                 - Choose the device when several are connected");
+    println!("  --check-dns                       - Check that the proxy hostname resolves before setting it");
+    println!("  --force                           - Set the proxy even if it does not accept connections");
+    println!("  --rules <NAME>                    - Apply a saved rule set of mappings after setting the proxy");
+    println!("  --ttl <DURATION>                  - Clear the proxy again after this long (e.g. 30m, 2h)");
+    println!("  --on-reboot reapply|clear|report  - What to do when a rebooted device lost its proxy (default: report)");
+    println!("  --verify-timeout <SECONDS>        - How long to wait for the device to confirm a change (default: 5)");
+
+    println!("\n{}", "Subcommands:".blue());
+    println!("  history                           - Show recorded proxy changes per device");
+    println!("  undo                              - Revert the most recent proxy change");
+    println!("  restore <N>                       - Re-apply the proxy from history entry N");
+    println!("  reverse list|add|remove|clear     - Manage adb reverse mappings");
+    println!("  forward list|add|remove|clear     - Manage adb forward mappings");
+    println!("  rules list|save|apply|delete      - Manage named sets of mappings");
+    println!("  launch <AVD> | launch --list      - Start an AVD with the proxy as -http-proxy");
+    println!("  guard [--grace <SECONDS>]         - Clear device proxies while the local proxy is down, restore when back");
+    println!("  follow [--interval <SECONDS>]     - Re-point device proxies when this computer's IP changes");
+    println!("  reboot-watch [--interval <SEC>]   - Keep checking devices for reboots and apply --on-reboot");
+    println!("  serve [--apply] [--bind <ADDR>]   - Run a built-in HTTP/CONNECT proxy, --apply points the device at it");
+    println!("  serve --har <FILE> [--har-bodies] - Record the proxied traffic as a HAR 1.2 file");
+    println!("  serve --intercept [--upstream-ca] - Decrypt HTTPS with certificates from the local CA");
+    println!("  serve --upstream <HOST:PORT>      - Chain to an HTTP proxy, or socks5[h]://HOST:PORT for SOCKS5 (h: remote DNS),");
+    println!("                                      credentials from --upstream-credentials <FILE> or $APS_UPSTREAM_CREDENTIALS");
+    println!("                                      (Basic only, no NTLM), --share-upstream-credentials to allow them beyond loopback");
+    println!("  serve --rules-file <FILE>         - Apply map-local, rewrite, header, body and fault rules from a TOML file");
+    println!("  serve --profile <PROFILE>         - Simulate 3g, edge or lossy-wifi: latency, throttling, drops, resets,");
+    println!("                                      --seed <N> replays the same random failures");
+    println!("  ca export [--out <DIR>]           - Write the local CA certificate as PEM and DER");
+    println!("  ca install [<CERT>] [--user]      - Install a CA (default: the local one), system store if rooted");
+    println!("  ca list                           - Audit the device's CA stores (local CA, expired, duplicates)");
+    println!("  network speed|delay <PRESET>      - Shape an emulator's network (e.g. speed edge, delay umts)");
+    println!("  network status                    - Show an emulator's network speed and delay");
 
     println!("\n{}", "Installation:".blue());
     println!("  make install                      - Build and install");
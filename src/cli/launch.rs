@@ -0,0 +1,64 @@
+//! Commands for listing and starting AVDs
+
+use std::path::Path;
+use std::time::Duration;
+use colored::*;
+use crate::cli::interactive::detect_port;
+use crate::config::args::Args;
+use crate::emulator::avd::{avd_home, emulator_binary, find_avd, launch_avd, list_avds, wait_for_boot};
+use crate::error::AppResult;
+use crate::proxy::settings::{ProxySettings, LOOPBACK_HOST};
+
+/// Print the AVDs that can be launched
+pub fn show_avds() -> AppResult<()> {
+    let dir = avd_home()?;
+    let avds = list_avds(&dir)?;
+    if avds.is_empty() {
+        println!("{} {}", "No AVDs found in".yellow(), dir.display());
+        return Ok(());
+    }
+
+    println!("{}", "=== Available AVDs ===".blue().bold());
+    for avd in avds {
+        let target = avd.target.as_deref().unwrap_or("unknown target");
+        match &avd.path {
+            Some(path) if !path.exists() => println!(
+                "  {} ({}) {}",
+                avd.name.green(),
+                target,
+                format!("image directory missing: {}", path.display()).red()
+            ),
+            _ => println!("  {} ({})", avd.name.green(), target),
+        }
+    }
+
+    Ok(())
+}
+
+/// Start an AVD pointed at the proxy and wait until it has booted
+///
+/// The emulator process runs on this computer, so the proxy defaults to
+/// the loopback address rather than the LAN address used for phones.
+pub fn run_launch(args: &Args, name: &str, emulator: Option<&Path>, boot_timeout: u64) -> AppResult<()> {
+    let avd = find_avd(&avd_home()?, name)?;
+    let port = match args.port {
+        Some(port) => port,
+        None => detect_port(false)?,
+    };
+    let ip = args.ip.clone().unwrap_or_else(|| LOOPBACK_HOST.to_string());
+    let settings = ProxySettings::new(port, Some(ip))?;
+    let emulator = emulator_binary(emulator);
+
+    println!(
+        "Launching {} with -http-proxy http://{}...",
+        avd.name.green(),
+        settings.to_proxy_string().green()
+    );
+    let (mut child, serial) = launch_avd(&avd, &emulator, &settings)?;
+
+    println!("Waiting for {} to finish booting...", serial);
+    wait_for_boot(&serial, &mut child, Duration::from_secs(boot_timeout))?;
+
+    println!("{} {} is ready", "✅".green(), serial.green().bold());
+    Ok(())
+}
@@ -0,0 +1,134 @@
+//! Commands for port mappings and saved rule sets
+
+use colored::*;
+use crate::adb::mapping::{
+    add_mapping, clear_mappings, list_mappings, remove_mapping, MappingKind, PortMapping, SocketSpec,
+};
+use crate::adb::rule_sets::{delete_rule_set, get_rule_set, load_rule_sets, save_rule_set};
+use crate::config::args::MappingAction;
+use crate::error::AppResult;
+
+/// Run a `reverse` or `forward` subcommand on the device
+pub fn run_mapping_command(serial: &str, kind: MappingKind, action: &MappingAction) -> AppResult<()> {
+    match action {
+        MappingAction::List => {
+            let mappings = list_mappings(serial, kind)?;
+            if mappings.is_empty() {
+                println!("No {} mappings on {}", kind, serial);
+            }
+            for mapping in mappings {
+                println!("  {}", mapping);
+            }
+        }
+        MappingAction::Add { listen, target } => {
+            let listen: SocketSpec = listen.parse()?;
+            let target: SocketSpec = match target {
+                Some(target) => target.parse()?,
+                None => listen.clone(),
+            };
+            let mapping = match kind {
+                MappingKind::Reverse => PortMapping { kind, device: listen, host: target },
+                MappingKind::Forward => PortMapping { kind, device: target, host: listen },
+            };
+            add_mapping(serial, &mapping)?;
+            println!("{} {}", "✅ Added".green(), mapping);
+        }
+        MappingAction::Remove { listen } => {
+            let listen: SocketSpec = listen.parse()?;
+            remove_mapping(serial, kind, &listen)?;
+            println!("{} {} mapping {}", "✅ Removed".green(), kind, listen);
+        }
+        MappingAction::Clear => {
+            clear_mappings(serial, kind)?;
+            println!("{} all {} mappings on {}", "✅ Removed".green(), kind, serial);
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the saved rule sets
+pub fn show_rule_sets() -> AppResult<()> {
+    let sets = load_rule_sets()?;
+    if sets.is_empty() {
+        println!("{}", "No rule sets saved".yellow());
+    }
+
+    for (name, mappings) in sets {
+        println!("\n{}", format!("=== {} ===", name).blue().bold());
+        for mapping in mappings {
+            println!("  {}", mapping);
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete a saved rule set
+pub fn remove_rule_set(name: &str) -> AppResult<()> {
+    delete_rule_set(name)?;
+    println!("{} rule set '{}'", "✅ Deleted".green(), name);
+    Ok(())
+}
+
+/// Save the device's current reverse and forward mappings as a rule set
+pub fn snapshot_rule_set(serial: &str, name: &str) -> AppResult<()> {
+    let mut mappings = list_mappings(serial, MappingKind::Reverse)?;
+    mappings.extend(list_mappings(serial, MappingKind::Forward)?);
+    let count = mappings.len();
+
+    save_rule_set(name, mappings)?;
+    println!("{} {} mappings as rule set '{}'", "✅ Saved".green(), count, name);
+    Ok(())
+}
+
+/// Apply every mapping of a saved rule set to the device
+pub fn apply_rule_set(serial: &str, name: &str) -> AppResult<()> {
+    let mappings = get_rule_set(name)?;
+    println!("Applying rule set '{}'...", name.green());
+
+    for mapping in &mappings {
+        add_mapping(serial, mapping)?;
+        println!("  {}", mapping);
+    }
+
+    println!("{} {} mappings", "✅ Applied".green(), mappings.len());
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::adb::fake::FakeAdb;
+    use crate::config::paths::TestDataDir;
+
+    #[test]
+    fn a_saved_rule_set_applies_to_another_device() {
+        let _data = TestDataDir::new("rule-set-round-trip");
+        let _adb = FakeAdb::install("rule-set-round-trip");
+        let reverse = PortMapping {
+            kind: MappingKind::Reverse,
+            device: "tcp:8081".parse().unwrap(),
+            host: "tcp:8082".parse().unwrap(),
+        };
+        let forward = PortMapping {
+            kind: MappingKind::Forward,
+            device: "localabstract:chrome_devtools_remote".parse().unwrap(),
+            host: "tcp:9222".parse().unwrap(),
+        };
+        add_mapping("emulator-5554", &reverse).unwrap();
+        add_mapping("emulator-5554", &forward).unwrap();
+
+        snapshot_rule_set("emulator-5554", "web").unwrap();
+        assert_eq!(get_rule_set("web").unwrap(), [reverse.clone(), forward.clone()]);
+
+        assert!(list_mappings("R58M123ABC", MappingKind::Forward).unwrap().is_empty());
+        apply_rule_set("R58M123ABC", "web").unwrap();
+        assert_eq!(list_mappings("R58M123ABC", MappingKind::Reverse).unwrap(), [reverse]);
+        assert_eq!(list_mappings("R58M123ABC", MappingKind::Forward).unwrap(), [forward]);
+
+        clear_mappings("emulator-5554", MappingKind::Forward).unwrap();
+        assert!(list_mappings("emulator-5554", MappingKind::Forward).unwrap().is_empty());
+        assert_eq!(list_mappings("R58M123ABC", MappingKind::Forward).unwrap().len(), 1);
+    }
+}
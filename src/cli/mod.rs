@@ -1,5 +1,12 @@
 //! Command-line interface module
 
+pub mod ca;
+pub mod follow;
+pub mod guard;
 pub mod interactive;
+pub mod launch;
+pub mod mappings;
+pub mod reboots;
+pub mod serve;
 
-pub use interactive::{run_cli_mode, show_available_commands};
\ No newline at end of file
+pub use interactive::{proxy_options, run_cli_mode, show_available_commands};
@@ -0,0 +1,52 @@
+//! Polling devices for reboots
+
+use std::thread;
+use std::time::Duration;
+use colored::*;
+use crate::adb::commands::{AdbCommand, execute_device_command_string};
+use crate::adb::device::get_connected_devices;
+use crate::cli::interactive::proxy_options;
+use crate::config::args::Args;
+use crate::error::AppResult;
+use crate::proxy::reboot::check_reboot;
+
+/// Check the connected devices for reboots every `interval` seconds
+///
+/// Runs until interrupted. Devices still booting are checked once
+/// `sys.boot_completed` is set, so the settings provider is available.
+pub fn run_reboot_watch(args: &Args, interval: u64) -> AppResult<()> {
+    let interval = Duration::from_secs(interval);
+    let options = proxy_options(args);
+
+    println!(
+        "Watching devices for reboots (every {}s, Ctrl-C to stop)",
+        interval.as_secs()
+    );
+
+    loop {
+        let devices = match &args.serial {
+            Some(serial) => vec![serial.clone()],
+            None => get_connected_devices().unwrap_or_default(),
+        };
+
+        for serial in devices {
+            if !boot_completed(&serial) {
+                continue;
+            }
+            if let Err(e) = check_reboot(&serial, args.on_reboot, &options) {
+                println!("{} {}: {}", "⚠️ Failed to check".yellow(), serial, e);
+            }
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+// Internal helper functions
+
+fn boot_completed(serial: &str) -> bool {
+    matches!(
+        execute_device_command_string(serial, AdbCommand::GetProp("sys.boot_completed".to_string())),
+        Ok(value) if value == "1"
+    )
+}
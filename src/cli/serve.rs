@@ -0,0 +1,150 @@
+//! Running the built-in proxy server
+
+use std::sync::mpsc;
+use colored::*;
+use crate::cert::authority::CertificateAuthority;
+use crate::cli::interactive::{proxy_options, settings_for_port};
+use crate::config::args::{Args, ServeArgs};
+use crate::emulator::detect::is_emulator_host_alias;
+use crate::error::{AppError, AppResult};
+use crate::proxy::manager::{clear_proxy, read_proxy_state, set_proxy};
+use crate::proxy::settings::{is_loopback_host, DEFAULT_PORT, LOOPBACK_HOST};
+use crate::proxy::state::ProxyState;
+use crate::server::forward::{ForwardProxy, ProxyContext};
+use crate::server::har::HarRecorder;
+use crate::server::intercept::Interceptor;
+use crate::server::rules::RulesFile;
+use crate::server::shaping::{Random, Shaper};
+use crate::server::upstream::UpstreamProxy;
+
+/// Serve until Ctrl-C, pointing `serial` at the proxy meanwhile when given
+///
+/// The device gets the same host and port `--set` would choose, and its
+/// proxy is cleared on shutdown unless it was changed in the meantime.
+/// Without `--bind` the proxy only listens where the device reaches it:
+/// the chosen host address, or loopback for USB mode and emulators.
+pub fn run_serve(args: &Args, serve: &ServeArgs, serial: Option<&str>) -> AppResult<()> {
+    let port = args.port.unwrap_or(DEFAULT_PORT);
+    let options = proxy_options(args);
+
+    let (shutdown, stop) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = shutdown.send(());
+    })
+    .map_err(|e| AppError::proxy_server(format!("cannot handle Ctrl-C: {}", e)))?;
+
+    let mut context = ProxyContext::default();
+    if let Some(path) = &serve.har {
+        let body_limit = if serve.har_bodies { serve.har_body_limit } else { 0 };
+        context.har = Some(HarRecorder::create(path, body_limit)?);
+    }
+    if serve.intercept {
+        let authority = CertificateAuthority::load_or_create()?;
+        context.interceptor = Some(Interceptor::new(authority, serve.upstream_ca.as_deref())?);
+    }
+    if let Some(address) = &serve.upstream {
+        context.upstream = Some(UpstreamProxy::new(address, serve.upstream_credentials.as_deref())?);
+    }
+    if let Some(path) = &serve.rules_file {
+        context.rules = Some(RulesFile::load(path)?);
+    }
+    context.shaper = serve.profile.map(Shaper::new);
+    context.random = Random::new(serve.seed);
+
+    let upstream = context.upstream.as_ref().map(UpstreamProxy::describe);
+    let rule_count = context.rules.as_ref().map(RulesFile::rule_count);
+    let shaping = context.shaper.as_ref().map(Shaper::describe);
+    let seed = context.random.seed();
+
+    let device_settings = match serial {
+        Some(serial) => Some((serial, settings_for_port(args, serial, port)?)),
+        None => None,
+    };
+    let bind = match (&serve.bind, &device_settings) {
+        (Some(bind), _) => bind.clone(),
+        (None, Some((serial, settings))) if !settings.is_loopback() && !is_emulator_host_alias(serial, &settings.ip) => {
+            settings.ip.clone()
+        }
+        (None, _) => LOOPBACK_HOST.to_string(),
+    };
+
+    // Every client of the proxy gets to use the upstream credentials, and any
+    // address beyond loopback, the device's LAN address included, has other clients
+    let shared = !is_loopback_host(&bind);
+    let has_credentials = context.upstream.as_ref().is_some_and(UpstreamProxy::has_credentials);
+    if shared && has_credentials && !serve.share_upstream_credentials {
+        return Err(AppError::proxy_server(format!(
+            "listening on {} would let every host that can reach it use the upstream credentials, \
+             connect the device with --usb to keep the proxy on loopback or pass --share-upstream-credentials",
+            bind
+        )));
+    }
+
+    // Listen before applying, the reachability checks connect to the proxy
+    let proxy = ForwardProxy::bind(&bind, port, context)?;
+    println!(
+        "{} {} (Ctrl-C to stop)",
+        "Proxy listening on".green().bold(),
+        proxy.local_addr()?
+    );
+    if proxy.local_addr()?.ip().is_unspecified() {
+        println!(
+            "{}",
+            "⚠️ Listening on every interface, anyone who can reach this computer can use the proxy".yellow()
+        );
+    } else if serial.is_none() && is_loopback_host(&bind) {
+        println!(
+            "Only this computer and its emulators can connect, point devices at it with {} or use {}",
+            "--usb".cyan(),
+            "--bind <ADDR>".cyan()
+        );
+    }
+    if let Some(path) = &serve.har {
+        println!("Recording traffic to {}", path.display().to_string().green());
+    }
+    if serve.intercept {
+        println!(
+            "Intercepting HTTPS, devices must trust the CA from {}",
+            "ca export".cyan()
+        );
+    }
+    if let Some(upstream) = &upstream {
+        println!("Forwarding through {}", upstream.green());
+    }
+    if let (Some(path), Some(count)) = (&serve.rules_file, rule_count) {
+        println!(
+            "Applying {} rule{} from {} (reloaded on change)",
+            count,
+            if count == 1 { "" } else { "s" },
+            path.display().to_string().green()
+        );
+    }
+    if let Some(shaping) = &shaping {
+        println!("Simulating {}", shaping.yellow());
+    }
+    if serve.profile.is_some() || serve.rules_file.is_some() {
+        println!("Random seed {} (replay with {} {})", seed, "--seed".cyan(), seed);
+    }
+    proxy.spawn();
+
+    let applied = match device_settings {
+        Some((serial, settings)) => {
+            set_proxy(serial, &settings, &options)?;
+            Some((serial, ProxyState::parse(&settings.to_proxy_string())))
+        }
+        None => None,
+    };
+
+    let _ = stop.recv();
+    println!("\n{}", "Stopping proxy...".yellow());
+
+    if let Some((serial, expected)) = applied {
+        match read_proxy_state(serial) {
+            Ok(current) if current == expected => clear_proxy(serial, &options)?,
+            Ok(current) => println!("Leaving the proxy of {} as is, it was changed to {}", serial, current),
+            Err(e) => println!("{} {}", "⚠️ Failed to read device proxy:".yellow(), e),
+        }
+    }
+
+    Ok(())
+}
@@ -1,19 +1,62 @@
 //! Command-line argument parsing
 
-use clap::Parser;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::{Parser, Subcommand};
+use crate::config::duration::parse_duration;
+use crate::emulator::shaping::{NetworkDelay, NetworkSpeed};
+use crate::server::shaping::ShapingProfile;
+use crate::proxy::manager::DEFAULT_VERIFY_TIMEOUT;
+use crate::proxy::reboot::RebootPolicy;
 
 /// Command-line arguments
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Proxy server port
-    #[arg(short, long, default_value_t = 8083)]
-    pub port: u16,
+    /// Proxy server port, detected from a running debugging proxy if not specified (fallback: 8083)
+    #[arg(short, long, global = true)]
+    pub port: Option<u16>,
 
-    /// Manually specify IP address, automatically get if not specified
-    #[arg(short, long)]
+    /// Manually specify IP address or hostname (host, host:port or [v6]:port), automatically get if not specified
+    #[arg(short, long, global = true)]
     pub ip: Option<String>,
 
+    /// Check that the proxy hostname resolves before setting it
+    #[arg(long)]
+    pub check_dns: bool,
+
+    /// Set the proxy even if it is not reachable from this computer or the device
+    #[arg(long)]
+    pub force: bool,
+
+    /// Apply this saved rule set of port mappings after setting the proxy
+    #[arg(long, value_name = "NAME")]
+    pub rules: Option<String>,
+
+    /// Clear the proxy again after this long (e.g. 30m, 2h, 1h30m)
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub ttl: Option<Duration>,
+
+    /// What to do when a device rebooted and lost or changed the proxy set by this tool
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = RebootPolicy::Report, global = true)]
+    pub on_reboot: RebootPolicy,
+
+    /// Seconds to wait for a change to be confirmed by the device
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_VERIFY_TIMEOUT.as_secs())]
+    pub verify_timeout: u64,
+
+    /// USB-only mode: tunnel the proxy port with `adb reverse` and point the device at 127.0.0.1
+    #[arg(long, conflicts_with_all = ["ip", "iface"])]
+    pub usb: bool,
+
+    /// Use the address of this network interface as proxy host
+    #[arg(long, conflicts_with = "ip")]
+    pub iface: Option<String>,
+
+    /// Serial of the device to operate on, required when several are connected
+    #[arg(long, global = true)]
+    pub serial: Option<String>,
+
     /// Skip interactive mode and directly set proxy
     #[arg(short, long)]
     pub set: bool,
@@ -34,9 +77,258 @@ pub struct Args {
     #[arg(long)]
     pub view: bool,
 
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Subcommands
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Show the proxy changes recorded for each device
+    History,
+
+    /// Revert the most recent proxy change on the device
+    Undo,
+
+    /// Re-apply the proxy value of history entry <N> (as numbered by `history`)
+    Restore {
+        /// History entry number, 1 being the most recent
+        index: usize,
+    },
+
+    /// Manage `adb reverse` mappings (the device connects to this computer)
+    Reverse {
+        #[command(subcommand)]
+        action: MappingAction,
+    },
+
+    /// Manage `adb forward` mappings (this computer connects to the device)
+    Forward {
+        #[command(subcommand)]
+        action: MappingAction,
+    },
+
+    /// Save and re-apply named sets of reverse and forward mappings
+    Rules {
+        #[command(subcommand)]
+        action: RulesAction,
+    },
+
+    /// Start an AVD with the proxy passed as -http-proxy and wait for it to boot
+    Launch {
+        /// Name of the AVD to start
+        #[arg(required_unless_present = "list")]
+        avd: Option<String>,
+
+        /// List the available AVDs instead
+        #[arg(long)]
+        list: bool,
+
+        /// Path of the emulator binary (default: $ANDROID_HOME/emulator/emulator, then PATH)
+        #[arg(long, value_name = "PATH")]
+        emulator: Option<PathBuf>,
+
+        /// Seconds to wait for sys.boot_completed
+        #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+        boot_timeout: u64,
+    },
+
+    /// Shape an emulator's network through its console (AVD only)
+    Network {
+        #[command(subcommand)]
+        action: NetworkAction,
+    },
+
+    /// Clear the proxy of devices using the local proxy while it is not accepting connections
+    Guard {
+        /// Seconds the listener must be down before devices are cleared
+        #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+        grace: u64,
+    },
+
+    /// Re-apply device proxies with the new address when this computer's IP changes
+    Follow {
+        /// Seconds between two checks of the host addresses
+        #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+        interval: u64,
+    },
+
+    /// Run a built-in HTTP proxy (absolute-URI requests and CONNECT tunnels) on --port
+    Serve(ServeArgs),
+
+    /// Manage the local CA that `serve --intercept` signs certificates with
+    Ca {
+        #[command(subcommand)]
+        action: CaAction,
+    },
+
+    /// Keep checking devices for reboots and apply --on-reboot when they come back
+    RebootWatch {
+        /// Seconds between two checks
+        #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+        interval: u64,
+    },
+
+    /// Clear the proxy of a device when its lease expires (started by --ttl)
+    #[command(hide = true)]
+    LeaseWatch {
+        device: String,
+
+        #[arg(long)]
+        lease: u64,
+    },
+}
+
+/// Options of the built-in proxy server
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Point the device at the proxy while it runs and clear it on shutdown
+    #[arg(long)]
+    pub apply: bool,
+
+    /// Address to listen on (default: the address given to the device with --apply, else 127.0.0.1).
+    /// The proxy has no authentication, use 0.0.0.0 only on trusted networks
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<String>,
+
+    /// Record every exchange to this HAR 1.2 file
+    #[arg(long, value_name = "FILE")]
+    pub har: Option<PathBuf>,
+
+    /// Include request and response bodies in the HAR file
+    #[arg(long, requires = "har")]
+    pub har_bodies: bool,
+
+    /// Bytes of each body kept with --har-bodies
+    #[arg(long, value_name = "BYTES", default_value_t = 1024 * 1024, requires = "har_bodies")]
+    pub har_body_limit: usize,
+
+    /// Decrypt HTTPS in CONNECT tunnels with certificates from the local CA (see `ca export`)
+    #[arg(long)]
+    pub intercept: bool,
+
+    /// Also trust the certificates in this PEM file when connecting to intercepted servers
+    #[arg(long, value_name = "FILE", requires = "intercept")]
+    pub upstream_ca: Option<PathBuf>,
+
+    /// Send all traffic through this proxy: host:port for HTTP, socks5://host:port,
+    /// or socks5h://host:port to have the SOCKS proxy resolve host names.
+    /// HTTP proxies get Basic authentication only, NTLM and Negotiate are not supported
+    #[arg(long, value_name = "HOST:PORT")]
+    pub upstream: Option<String>,
+
+    /// File whose first line is user:password, spaces included, for the upstream proxy (default: $APS_UPSTREAM_CREDENTIALS)
+    #[arg(long, value_name = "FILE", requires = "upstream")]
+    pub upstream_credentials: Option<PathBuf>,
+
+    /// Allow upstream credentials while listening beyond loopback, where other hosts can use them (see --bind)
+    #[arg(long, requires = "upstream")]
+    pub share_upstream_credentials: bool,
+
+    /// TOML file of map-local, rewrite and fault rules, reloaded when it changes
+    /// (not to be confused with the saved mapping sets of `--rules <NAME>`)
+    #[arg(long, value_name = "FILE")]
+    pub rules_file: Option<PathBuf>,
+
+    /// Simulate network conditions: latency, throttling, dropped and reset connections
+    #[arg(long, value_name = "PROFILE", value_enum)]
+    pub profile: Option<ShapingProfile>,
+
+    /// Seed for the random drops, resets and faults, to replay a session
+    #[arg(long, value_name = "N")]
+    pub seed: Option<u64>,
+}
+
+/// Operations on the local CA
+#[derive(Subcommand, Debug)]
+pub enum CaAction {
+    /// Write the CA certificate as PEM and DER, generating the CA on first use
+    Export {
+        /// Directory to write the files to
+        #[arg(long, value_name = "DIR", default_value = ".")]
+        out: PathBuf,
+    },
+
+    /// List the system and user CA stores of the device, flagging the local CA, expired and duplicate entries
+    List,
+
+    /// Install a CA certificate on the device, into the system store when it is rooted
+    Install {
+        /// PEM or DER certificate file (e.g. Charles' or mitmproxy's), the local CA if omitted
+        cert: Option<PathBuf>,
+
+        /// Push it for the user store even when the device is rooted
+        #[arg(long)]
+        user: bool,
+    },
+}
+
+/// Operations on reverse or forward mappings
+#[derive(Subcommand, Debug)]
+pub enum MappingAction {
+    /// List the mappings of the device
+    List,
+
+    /// Add a mapping from a listening socket to a target socket
+    Add {
+        /// Listening socket (device side for reverse, this computer for forward), e.g. 8081 or tcp:8081
+        listen: String,
+        /// Target socket, the same as the listening socket if omitted
+        target: Option<String>,
+    },
+
+    /// Remove the mapping of a listening socket
+    Remove {
+        /// Listening socket, e.g. 8081 or tcp:8081
+        listen: String,
+    },
+
+    /// Remove all mappings
+    Clear,
+}
+
+/// Operations on saved rule sets
+#[derive(Subcommand, Debug)]
+pub enum RulesAction {
+    /// List the saved rule sets
+    List,
+
+    /// Save the device's current reverse and forward mappings under a name
+    Save {
+        name: String,
+    },
+
+    /// Re-apply a saved rule set to the device
+    Apply {
+        name: String,
+    },
+
+    /// Delete a saved rule set
+    Delete {
+        name: String,
+    },
+}
+
+/// Emulator network shaping operations
+#[derive(Subcommand, Debug)]
+pub enum NetworkAction {
+    /// Throttle the network to a speed preset
+    Speed {
+        #[arg(value_enum)]
+        preset: NetworkSpeed,
+    },
+
+    /// Add latency from a delay preset
+    Delay {
+        #[arg(value_enum)]
+        preset: NetworkDelay,
+    },
+
+    /// Show the current speed and delay
+    Status,
 }
 
 /// Parse command-line arguments
 pub fn parse_args() -> Args {
     Args::parse()
-}
\ No newline at end of file
+}
@@ -0,0 +1,135 @@
+//! Human-friendly durations such as `90s`, `15m` or `1h30m`
+
+use std::time::Duration;
+use crate::error::{AppError, AppResult};
+
+/// Parse a duration made of `<number><unit>` parts, units being s, m, h and d
+///
+/// A bare number is taken as seconds.
+pub fn parse_duration(input: &str) -> AppResult<Duration> {
+    let invalid = || AppError::InvalidDuration {
+        input: input.to_string(),
+    };
+    let text = input.trim();
+    if text.is_empty() {
+        return Err(invalid());
+    }
+    if let Ok(seconds) = text.parse::<u64>() {
+        return match seconds {
+            0 => Err(invalid()),
+            _ => Ok(Duration::from_secs(seconds)),
+        };
+    }
+
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3_600,
+            'd' => 86_400,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = digits.parse().map_err(|_| invalid())?;
+        total = value
+            .checked_mul(unit)
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(invalid)?;
+        digits.clear();
+    }
+
+    if !digits.is_empty() || total == 0 {
+        return Err(invalid());
+    }
+
+    Ok(Duration::from_secs(total))
+}
+
+/// Format a duration with its two most significant units, e.g. `1h 05m`
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (days, hours, minutes, seconds) = (
+        seconds / 86_400,
+        seconds % 86_400 / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60,
+    );
+
+    if days > 0 {
+        format!("{}d {:02}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {:02}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_parsed() {
+        let cases = [
+            ("30m", 1_800),
+            ("2h", 7_200),
+            ("1h30m", 5_400),
+            ("90s", 90),
+            ("90", 90),
+            (" 1d ", 86_400),
+            ("1H30M", 5_400),
+            ("0h5m", 300),
+            ("1m1m", 120),
+        ];
+        for (input, seconds) in cases {
+            assert_eq!(parse_duration(input).unwrap(), Duration::from_secs(seconds), "{}", input);
+        }
+    }
+
+    #[test]
+    fn invalid_durations_are_rejected() {
+        for input in [
+            "",
+            "   ",
+            "0",
+            "0m",
+            "0h0m",
+            "m",
+            "1h30",
+            "30x",
+            "1w",
+            "-5m",
+            "1.5h",
+            "1h 30m",
+            "99999999999999999999",
+            "99999999999999999999s",
+            "300000000000000000d",
+            "18446744073709551615s1s",
+        ] {
+            assert!(matches!(parse_duration(input), Err(AppError::InvalidDuration { .. })), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn durations_are_formatted_with_two_units() {
+        let cases = [
+            (0, "0s"),
+            (59, "59s"),
+            (90, "1m 30s"),
+            (3_900, "1h 05m"),
+            (86_399, "23h 59m"),
+            (90_061, "1d 01h"),
+        ];
+        for (seconds, expected) in cases {
+            assert_eq!(format_duration(Duration::from_secs(seconds)), expected);
+        }
+    }
+}
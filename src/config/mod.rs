@@ -1,3 +1,6 @@
 //! Configuration and argument parsing module
 
-pub mod args;
\ No newline at end of file
+pub mod args;
+pub mod duration;
+pub mod paths;
+pub mod time;
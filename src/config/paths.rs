@@ -0,0 +1,96 @@
+//! Locations of files the tool keeps between runs
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::error::{AppError, AppResult};
+
+/// Environment variable overriding the state directory
+const DATA_DIR_ENV: &str = "APS_DATA_DIR";
+
+/// Directory name used under the user's home directory
+const DATA_DIR_NAME: &str = ".android_proxy_setter";
+
+/// Get the state directory, creating it if necessary
+pub fn data_dir() -> AppResult<PathBuf> {
+    let dir = match data_dir_override() {
+        Some(dir) => dir,
+        None => home_dir()?.join(DATA_DIR_NAME),
+    };
+
+    fs::create_dir_all(&dir).map_err(|e| AppError::state_file_error(&dir, e))?;
+    Ok(dir)
+}
+
+/// A scratch state directory for the current thread, removed when dropped
+///
+/// Tests run in parallel threads, so each gets its own directory rather
+/// than sharing `APS_DATA_DIR`.
+#[cfg(test)]
+pub struct TestDataDir(PathBuf);
+
+#[cfg(test)]
+impl TestDataDir {
+    /// Use a fresh directory named after `name` until dropped
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aps-data-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TEST_DATA_DIR.with(|current| *current.borrow_mut() = Some(dir.clone()));
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDataDir {
+    fn drop(&mut self) {
+        TEST_DATA_DIR.with(|current| *current.borrow_mut() = None);
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_DATA_DIR: std::cell::RefCell<Option<PathBuf>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Get the user's home directory
+pub fn home_dir() -> AppResult<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .ok_or_else(|| AppError::StateFileError {
+            path: "~".to_string(),
+            reason: "home directory not found".to_string(),
+        })
+}
+
+/// Get the path of a file inside the state directory
+pub fn data_file(name: &str) -> AppResult<PathBuf> {
+    Ok(data_dir()?.join(name))
+}
+
+/// Read a state file, returning `None` when it does not exist yet
+pub fn read_state_file(path: &Path) -> AppResult<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(AppError::state_file_error(path, e)),
+    }
+}
+
+/// Replace a state file, writing to a temporary file first so readers never see a partial file
+pub fn write_state_file(path: &Path, content: &str) -> AppResult<()> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, content).map_err(|e| AppError::state_file_error(&temporary, e))?;
+    fs::rename(&temporary, path).map_err(|e| AppError::state_file_error(path, e))
+}
+
+// Internal helper functions
+
+/// `APS_DATA_DIR`, or the directory a test chose for its thread
+fn data_dir_override() -> Option<PathBuf> {
+    #[cfg(test)]
+    if let Some(dir) = TEST_DATA_DIR.with(|dir| dir.borrow().clone()) {
+        return Some(dir);
+    }
+    std::env::var_os(DATA_DIR_ENV).map(PathBuf::from)
+}
@@ -0,0 +1,295 @@
+//! Discovering and starting Android Virtual Devices
+
+use std::fs;
+use std::net::{Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::adb::commands::{AdbCommand, execute_device_command_string};
+use crate::config::paths::home_dir;
+use crate::error::{AppError, AppResult};
+use crate::proxy::settings::ProxySettings;
+
+/// Console ports the emulator accepts, two per instance
+const FIRST_CONSOLE_PORT: u16 = 5554;
+const LAST_CONSOLE_PORT: u16 = 5682;
+
+/// Delay between two boot checks
+const BOOT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// An AVD described by an `<name>.ini` file
+#[derive(Debug, Clone)]
+pub struct Avd {
+    pub name: String,
+    /// Directory holding the AVD's disk images
+    pub path: Option<PathBuf>,
+    /// Platform target, e.g. `android-34`
+    pub target: Option<String>,
+}
+
+/// Directory holding the AVD `.ini` files
+///
+/// Honors `ANDROID_AVD_HOME` and `ANDROID_USER_HOME` like the SDK tools.
+pub fn avd_home() -> AppResult<PathBuf> {
+    if let Some(dir) = std::env::var_os("ANDROID_AVD_HOME") {
+        return Ok(PathBuf::from(dir));
+    }
+    if let Some(dir) = std::env::var_os("ANDROID_USER_HOME") {
+        return Ok(PathBuf::from(dir).join("avd"));
+    }
+    Ok(home_dir()?.join(".android").join("avd"))
+}
+
+/// List the AVDs in `dir`, usually [`avd_home`], sorted by name
+pub fn list_avds(dir: &Path) -> AppResult<Vec<Avd>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(AppError::state_file_error(dir, e)),
+    };
+
+    let mut avds: Vec<Avd> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "ini"))
+        .filter_map(|path| read_avd_ini(&path))
+        .collect();
+    avds.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(avds)
+}
+
+/// Find an AVD in `dir` by name
+pub fn find_avd(dir: &Path, name: &str) -> AppResult<Avd> {
+    let avds = list_avds(dir)?;
+    avds.iter()
+        .find(|avd| avd.name == name)
+        .cloned()
+        .ok_or_else(|| AppError::AvdNotFound {
+            name: name.to_string(),
+            available: if avds.is_empty() {
+                "none".to_string()
+            } else {
+                avds.iter().map(|avd| avd.name.as_str()).collect::<Vec<_>>().join(", ")
+            },
+        })
+}
+
+/// Locate the emulator binary: the given path, the SDK's `emulator/` directory, or `PATH`
+pub fn emulator_binary(custom: Option<&Path>) -> PathBuf {
+    if let Some(path) = custom {
+        return path.to_path_buf();
+    }
+
+    for variable in ["ANDROID_HOME", "ANDROID_SDK_ROOT"] {
+        if let Some(sdk) = std::env::var_os(variable) {
+            let candidate = PathBuf::from(sdk).join("emulator").join("emulator");
+            if candidate.exists() {
+                return candidate;
+            }
+        }
+    }
+
+    PathBuf::from("emulator")
+}
+
+/// Start an AVD whose traffic goes through the given proxy
+///
+/// Returns the emulator process and the adb serial it will appear under.
+pub fn launch_avd(avd: &Avd, emulator: &Path, settings: &ProxySettings) -> AppResult<(Child, String)> {
+    let port = free_console_port().ok_or_else(|| AppError::EmulatorLaunchFailed {
+        reason: format!("no free console port between {} and {}", FIRST_CONSOLE_PORT, LAST_CONSOLE_PORT),
+    })?;
+
+    let child = Command::new(emulator)
+        .arg("-avd")
+        .arg(&avd.name)
+        .arg("-port")
+        .arg(port.to_string())
+        .arg("-http-proxy")
+        .arg(format!("http://{}", settings.to_proxy_string()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| AppError::EmulatorLaunchFailed {
+            reason: format!("cannot run {}: {}", emulator.display(), e),
+        })?;
+
+    Ok((child, format!("emulator-{}", port)))
+}
+
+/// Wait until the emulator reports `sys.boot_completed`
+///
+/// An emulator that does not boot in time is stopped.
+pub fn wait_for_boot(serial: &str, child: &mut Child, timeout: Duration) -> AppResult<()> {
+    let started = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(AppError::EmulatorLaunchFailed {
+                reason: format!("emulator exited with {}", status),
+            });
+        }
+
+        // The device is offline or missing while it boots, so errors mean "not yet"
+        let booted = execute_device_command_string(serial, AdbCommand::GetProp("sys.boot_completed".to_string()))
+            .map(|value| value == "1")
+            .unwrap_or(false);
+        if booted {
+            return Ok(());
+        }
+
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(AppError::BootTimeout {
+                serial: serial.to_string(),
+                seconds: timeout.as_secs(),
+            });
+        }
+        thread::sleep(BOOT_POLL_INTERVAL);
+    }
+}
+
+// Internal helper functions
+
+/// Parse `<name>.ini`, which holds `path=` and `target=` entries
+fn read_avd_ini(path: &Path) -> Option<Avd> {
+    let name = path.file_stem()?.to_string_lossy().to_string();
+    let content = fs::read_to_string(path).ok()?;
+
+    let value = |key: &str| {
+        content.lines().find_map(|line| {
+            let (k, v) = line.split_once('=')?;
+            (k.trim() == key).then(|| v.trim().to_string())
+        })
+    };
+
+    Some(Avd {
+        name,
+        path: value("path").map(PathBuf::from),
+        target: value("target"),
+    })
+}
+
+/// First even console port whose console and adb ports are both free
+fn free_console_port() -> Option<u16> {
+    (FIRST_CONSOLE_PORT..=LAST_CONSOLE_PORT).step_by(2).find(|port| {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, *port)).is_ok()
+            && TcpListener::bind((Ipv4Addr::LOCALHOST, port + 1)).is_ok()
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::adb::fake::FakeAdb;
+    use crate::emulator::console::console_port;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aps-avd-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Stub emulator recording its arguments, then running `then`
+    fn stub_emulator(dir: &Path, then: &str) -> PathBuf {
+        let binary = dir.join("emulator");
+        let script = format!("#!/bin/sh\necho \"$@\" > \"{}\"\n{}\n", dir.join("args").display(), then);
+        fs::write(&binary, script).unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+        binary
+    }
+
+    fn recorded_args(dir: &Path) -> String {
+        let file = dir.join("args");
+        for _ in 0..100 {
+            if let Ok(args) = fs::read_to_string(&file) {
+                if !args.is_empty() {
+                    return args.trim().to_string();
+                }
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("the stub emulator did not record its arguments");
+    }
+
+    #[test]
+    fn avds_are_listed_from_their_directory() {
+        let dir = scratch_dir("home");
+        fs::write(
+            dir.join("Pixel_8_API_34.ini"),
+            "avd.ini.encoding=UTF-8\npath=/avds/Pixel_8_API_34.avd\ntarget=android-34\n",
+        )
+        .unwrap();
+        fs::write(dir.join("Nexus_5.ini"), "path = /avds/Nexus_5.avd\n").unwrap();
+        fs::create_dir(dir.join("Pixel_8_API_34.avd")).unwrap();
+        fs::write(dir.join("notes.txt"), "path=/nowhere\n").unwrap();
+
+        let avds = list_avds(&dir).unwrap();
+        let names: Vec<&str> = avds.iter().map(|avd| avd.name.as_str()).collect();
+        assert_eq!(names, ["Nexus_5", "Pixel_8_API_34"]);
+        assert_eq!(avds[0].path, Some(PathBuf::from("/avds/Nexus_5.avd")));
+        assert_eq!(avds[0].target, None);
+        assert_eq!(avds[1].target.as_deref(), Some("android-34"));
+
+        assert_eq!(find_avd(&dir, "Nexus_5").unwrap().name, "Nexus_5");
+        let missing = find_avd(&dir, "Pixel_9").unwrap_err().to_string();
+        assert!(missing.contains("Nexus_5, Pixel_8_API_34"), "{}", missing);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn launch_passes_the_avd_port_and_proxy() {
+        let dir = scratch_dir("launch");
+        let emulator = stub_emulator(&dir, "exit 0");
+        let avd = Avd {
+            name: "Pixel_8_API_34".to_string(),
+            path: None,
+            target: None,
+        };
+        let settings = ProxySettings::new(8083, Some("10.0.2.2".to_string())).unwrap();
+
+        let (mut child, serial) = launch_avd(&avd, &emulator_binary(Some(&emulator)), &settings).unwrap();
+        child.wait().unwrap();
+
+        let port = console_port(&serial).unwrap();
+        assert_eq!(
+            recorded_args(&dir),
+            format!("-avd Pixel_8_API_34 -port {} -http-proxy http://10.0.2.2:8083", port)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn boot_fails_when_the_emulator_exits() {
+        let dir = scratch_dir("exit");
+        let _adb = FakeAdb::install("boot-exit");
+        let mut child = Command::new(stub_emulator(&dir, "exit 3")).spawn().unwrap();
+
+        let error = wait_for_boot("emulator-5790", &mut child, Duration::from_secs(30)).unwrap_err();
+        assert!(error.to_string().contains("emulator exited"), "{}", error);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn boot_timeout_stops_the_emulator() {
+        let dir = scratch_dir("timeout");
+        let _adb = FakeAdb::install("boot-timeout");
+        let mut child = Command::new(stub_emulator(&dir, "exec sleep 60")).spawn().unwrap();
+        recorded_args(&dir);
+
+        let error = wait_for_boot("emulator-5792", &mut child, Duration::ZERO).unwrap_err();
+        assert!(matches!(error, AppError::BootTimeout { .. }), "{}", error);
+        assert!(child.try_wait().unwrap().is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! Client for the emulator console protocol
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+use crate::config::paths::home_dir;
+use crate::error::{AppError, AppResult};
+
+/// File holding the console auth token, relative to the home directory
+const AUTH_TOKEN_FILE: &str = ".emulator_console_auth_token";
+
+/// Timeout for connecting and for each console reply
+const CONSOLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to an emulator's console port
+pub struct EmulatorConsole {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl EmulatorConsole {
+    /// Connect to the console on `port` of this computer and read the banner
+    pub fn connect(port: u16) -> AppResult<Self> {
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let stream = TcpStream::connect_timeout(&address, CONSOLE_TIMEOUT)
+            .map_err(|e| AppError::emulator_console(format!("cannot connect to port {}: {}", port, e)))?;
+        stream.set_read_timeout(Some(CONSOLE_TIMEOUT))?;
+
+        let mut console = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        console.read_reply()?;
+
+        Ok(console)
+    }
+
+    /// Connect to the console of the emulator behind `serial` and authenticate
+    pub fn for_serial(serial: &str) -> AppResult<Self> {
+        let port = console_port(serial).ok_or_else(|| AppError::NotAnEmulator {
+            serial: serial.to_string(),
+        })?;
+
+        Self::authenticate(port, read_auth_token()?)
+    }
+
+    /// Connect to the console on `port` and authenticate with `token` when auth is enabled
+    fn authenticate(port: u16, token: Option<String>) -> AppResult<Self> {
+        let mut console = Self::connect(port)?;
+        if let Some(token) = token {
+            console.command(&format!("auth {}", token))?;
+        }
+
+        Ok(console)
+    }
+
+    /// Send a command and return the lines printed before `OK`
+    pub fn command(&mut self, command: &str) -> AppResult<String> {
+        writeln!(self.writer, "{}\r", command)?;
+        self.writer.flush()?;
+        self.read_reply()
+    }
+
+    /// Read lines until the console reports `OK` or `KO: <reason>`
+    fn read_reply(&mut self) -> AppResult<String> {
+        let mut lines = Vec::new();
+
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(AppError::emulator_console("connection closed by the emulator"));
+            }
+
+            let line = line.trim_end();
+            if line == "OK" {
+                return Ok(lines.join("\n"));
+            }
+            if let Some(reason) = line.strip_prefix("KO") {
+                return Err(AppError::emulator_console(reason.trim_start_matches(':').trim()));
+            }
+            lines.push(line.to_string());
+        }
+    }
+}
+
+/// Console port of an emulator serial such as `emulator-5554`
+pub fn console_port(serial: &str) -> Option<u16> {
+    serial.strip_prefix("emulator-")?.parse().ok()
+}
+
+// Internal helper functions
+
+/// Read the auth token; a missing or empty file means auth is disabled
+fn read_auth_token() -> AppResult<Option<String>> {
+    let path = home_dir()?.join(AUTH_TOKEN_FILE);
+    match fs::read_to_string(&path) {
+        Ok(token) if !token.trim().is_empty() => Ok(Some(token.trim().to_string())),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(AppError::state_file_error(&path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Stand-in console: checks the auth token, answers `network status` and
+    /// rejects anything unknown, returning the commands it received
+    fn spawn_console(token: &'static str) -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let reader = BufReader::new(stream);
+            write!(writer, "Android Console: Authentication required\r\nOK\r\n").unwrap();
+
+            let mut received = Vec::new();
+            let mut authenticated = false;
+            for line in reader.lines() {
+                let line = line.unwrap().trim_end().to_string();
+                let reply = match line.split_once(' ') {
+                    Some(("auth", given)) if given == token => {
+                        authenticated = true;
+                        "OK\r\n".to_string()
+                    }
+                    Some(("auth", _)) => "KO: authentication token does not match\r\n".to_string(),
+                    _ if !authenticated => "KO: unknown command, try 'help'\r\n".to_string(),
+                    Some(("network", "status")) => "Current network status:\r\n  download speed: 0 bits/s\r\nOK\r\n".to_string(),
+                    Some(("network", _)) => "OK\r\n".to_string(),
+                    _ => "KO: unknown command, try 'help'\r\n".to_string(),
+                };
+                received.push(line);
+                writer.write_all(reply.as_bytes()).unwrap();
+            }
+            received
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn authenticates_and_sends_commands() {
+        let (port, console) = spawn_console("s3cret");
+
+        let mut client = EmulatorConsole::authenticate(port, Some("s3cret".to_string())).unwrap();
+        client.command("network speed edge").unwrap();
+        let status = client.command("network status").unwrap();
+        let error = client.command("bogus");
+        drop(client);
+
+        assert_eq!(status, "Current network status:\n  download speed: 0 bits/s");
+        assert!(error.unwrap_err().to_string().contains("unknown command"));
+        assert_eq!(
+            console.join().unwrap(),
+            ["auth s3cret", "network speed edge", "network status", "bogus"]
+        );
+    }
+
+    #[test]
+    fn reports_a_rejected_token() {
+        let (port, console) = spawn_console("s3cret");
+
+        let error = EmulatorConsole::authenticate(port, Some("wrong".to_string())).err().unwrap();
+
+        assert!(error.to_string().contains("authentication token does not match"));
+        assert_eq!(console.join().unwrap(), ["auth wrong"]);
+    }
+
+    #[test]
+    fn console_ports_come_from_emulator_serials() {
+        assert_eq!(console_port("emulator-5556"), Some(5556));
+        assert_eq!(console_port("R58M123ABC"), None);
+    }
+}
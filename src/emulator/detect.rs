@@ -0,0 +1,66 @@
+//! Recognizing emulator devices and their host address
+
+use std::fmt;
+use crate::adb::commands::{AdbCommand, execute_device_command_string};
+use crate::error::AppResult;
+
+/// Kind of emulator behind a device serial
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatorKind {
+    /// Stock Android Virtual Device
+    Avd,
+    /// Genymotion (VirtualBox based)
+    Genymotion,
+}
+
+impl EmulatorKind {
+    /// Address under which the emulator reaches this computer's loopback interface
+    pub fn host_alias(&self) -> &'static str {
+        match self {
+            EmulatorKind::Avd => "10.0.2.2",
+            EmulatorKind::Genymotion => "10.0.3.2",
+        }
+    }
+}
+
+impl fmt::Display for EmulatorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorKind::Avd => write!(f, "Android emulator"),
+            EmulatorKind::Genymotion => write!(f, "Genymotion emulator"),
+        }
+    }
+}
+
+/// Detect whether the device is an emulator
+///
+/// Genymotion is recognized by its system properties, the stock emulator by
+/// its `emulator-<port>` transport or `ro.kernel.qemu`.
+pub fn detect_emulator(serial: &str) -> AppResult<Option<EmulatorKind>> {
+    let genymotion_version = get_prop(serial, "ro.genymotion.version")?;
+    let manufacturer = get_prop(serial, "ro.product.manufacturer")?;
+    if !genymotion_version.is_empty() || manufacturer.eq_ignore_ascii_case("genymotion") {
+        return Ok(Some(EmulatorKind::Genymotion));
+    }
+
+    if serial.starts_with("emulator-") || get_prop(serial, "ro.kernel.qemu")? == "1" {
+        return Ok(Some(EmulatorKind::Avd));
+    }
+
+    Ok(None)
+}
+
+/// Whether `host` is the host alias of the emulator behind `serial`
+pub fn is_emulator_host_alias(serial: &str, host: &str) -> bool {
+    let is_alias = [EmulatorKind::Avd, EmulatorKind::Genymotion]
+        .iter()
+        .any(|kind| kind.host_alias() == host);
+
+    is_alias && matches!(detect_emulator(serial), Ok(Some(kind)) if kind.host_alias() == host)
+}
+
+// Internal helper functions
+
+fn get_prop(serial: &str, name: &str) -> AppResult<String> {
+    execute_device_command_string(serial, AdbCommand::GetProp(name.to_string()))
+}
@@ -0,0 +1,6 @@
+//! Android emulator support
+
+pub mod avd;
+pub mod console;
+pub mod detect;
+pub mod shaping;
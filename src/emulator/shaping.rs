@@ -0,0 +1,62 @@
+//! Network speed and latency presets applied through the emulator console
+
+use clap::ValueEnum;
+use colored::*;
+use crate::emulator::console::EmulatorConsole;
+use crate::error::AppResult;
+
+/// Network speed presets understood by `network speed`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NetworkSpeed {
+    Gsm,
+    Hscsd,
+    Gprs,
+    Edge,
+    Umts,
+    Hsdpa,
+    Lte,
+    Evdo,
+    Full,
+}
+
+/// Network latency presets understood by `network delay`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NetworkDelay {
+    Gprs,
+    Edge,
+    Umts,
+    None,
+}
+
+/// Throttle the emulator's network to a speed preset
+pub fn set_network_speed(serial: &str, speed: NetworkSpeed) -> AppResult<()> {
+    let preset = preset_name(speed);
+    EmulatorConsole::for_serial(serial)?.command(&format!("network speed {}", preset))?;
+    println!("{} network speed of {} to {}", "✅ Set".green(), serial, preset.green());
+    Ok(())
+}
+
+/// Add latency to the emulator's network from a delay preset
+pub fn set_network_delay(serial: &str, delay: NetworkDelay) -> AppResult<()> {
+    let preset = preset_name(delay);
+    EmulatorConsole::for_serial(serial)?.command(&format!("network delay {}", preset))?;
+    println!("{} network delay of {} to {}", "✅ Set".green(), serial, preset.green());
+    Ok(())
+}
+
+/// Print the emulator's current network speed and delay
+pub fn show_network_status(serial: &str) -> AppResult<()> {
+    let status = EmulatorConsole::for_serial(serial)?.command("network status")?;
+    println!("{}", format!("=== Network status of {} ===", serial).blue().bold());
+    println!("{}", status);
+    Ok(())
+}
+
+// Internal helper functions
+
+fn preset_name(preset: impl ValueEnum) -> String {
+    preset
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
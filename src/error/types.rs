@@ -20,21 +20,167 @@ pub enum AppError {
     #[error("No connected Android devices found")]
     NoDevicesConnected,
 
+    #[error("Device {serial} is not connected")]
+    DeviceNotFound {
+        serial: String,
+    },
+
+    #[error("Multiple devices connected ({devices}), please choose one with --serial")]
+    MultipleDevices {
+        devices: String,
+    },
+
     #[error("Failed to get local IP address: {reason}")]
     LocalIpError {
         reason: String,
     },
 
+    #[error("Invalid proxy host '{host}': {reason}")]
+    InvalidHost {
+        host: String,
+        reason: String,
+    },
+
+    #[error("Invalid proxy port '{input}': expected a number between 1 and 65535")]
+    InvalidPort {
+        input: String,
+    },
+
+    #[error("Invalid proxy address '{input}': {reason}")]
+    InvalidProxyAddress {
+        input: String,
+        reason: String,
+    },
+
+    #[error("Several local proxies detected ({candidates}), please choose one with --port")]
+    MultipleProxiesDetected {
+        candidates: String,
+    },
+
+    #[error("Failed to resolve proxy host '{host}': {reason}")]
+    HostResolutionFailed {
+        host: String,
+        reason: String,
+    },
+
+    #[error("Proxy {endpoint} is not reachable from {from}: {reason} (use --force to set it anyway)")]
+    ProxyUnreachable {
+        endpoint: String,
+        from: String,
+        reason: String,
+    },
+
+    #[error("Network interface {iface} has no IPv4 address (available: {available})")]
+    InterfaceNotFound {
+        iface: String,
+        available: String,
+    },
+
+    #[error("No local address shares a subnet with the device (device: {device}; this computer: {host}). \
+             The device is probably on another network, behind a VPN or guest Wi-Fi isolation; \
+             use --ip or --iface to choose an address")]
+    NoSharedSubnet {
+        device: String,
+        host: String,
+    },
+
     #[error("Failed to clear proxy: {reason}")]
     ProxyClearFailed {
         reason: String,
     },
 
+    #[error("Proxy did not become {expected} within {waited_ms} ms (device reports {actual})")]
+    ProxyVerifyFailed {
+        expected: String,
+        actual: String,
+        waited_ms: u64,
+    },
+
     #[error("Failed to get proxy settings: {reason}")]
     ProxyGetFailed {
         reason: String,
     },
 
+    #[error("Device {serial} is not an emulator with a console port (expected a serial like emulator-5554)")]
+    NotAnEmulator {
+        serial: String,
+    },
+
+    #[error("Emulator console error: {reason}")]
+    EmulatorConsoleError {
+        reason: String,
+    },
+
+    #[error("No AVD named '{name}' (available: {available})")]
+    AvdNotFound {
+        name: String,
+        available: String,
+    },
+
+    #[error("Failed to launch emulator: {reason}")]
+    EmulatorLaunchFailed {
+        reason: String,
+    },
+
+    #[error("Proxy server error: {reason}")]
+    ProxyServerError {
+        reason: String,
+    },
+
+    #[error("Certificate error: {reason}")]
+    CertificateError {
+        reason: String,
+    },
+
+    #[error("Invalid rules file {path}: {reason}")]
+    InvalidRules {
+        path: String,
+        reason: String,
+    },
+
+    #[error("{serial} did not finish booting within {seconds} seconds, the emulator was stopped")]
+    BootTimeout {
+        serial: String,
+        seconds: u64,
+    },
+
+    #[error("Could not read the boot id of {serial}")]
+    BootIdUnavailable {
+        serial: String,
+    },
+
+    #[error("Invalid duration '{input}': expected e.g. 90s, 15m, 2h or 1h30m")]
+    InvalidDuration {
+        input: String,
+    },
+
+    #[error("Invalid adb socket '{input}': expected a port or <protocol>:<address> such as tcp:8081")]
+    InvalidSocketSpec {
+        input: String,
+    },
+
+    #[error("No port rule set named '{name}'")]
+    RuleSetNotFound {
+        name: String,
+    },
+
+    #[error("No proxy history recorded for device {serial}")]
+    HistoryEmpty {
+        serial: String,
+    },
+
+    #[error("No history entry #{index} for device {serial}")]
+    HistoryEntryNotFound {
+        serial: String,
+        index: usize,
+    },
+
+    #[error("Failed to access state file {path}: {reason}")]
+    StateFileError {
+        path: String,
+        reason: String,
+    },
+
     #[error("I/O error: {source}")]
     IoError {
         source: std::io::Error,
@@ -75,4 +221,41 @@ impl AppError {
             reason: reason.into(),
         }
     }
+
+    /// Create a new emulator console error
+    pub fn emulator_console(reason: impl Into<String>) -> Self {
+        AppError::EmulatorConsoleError {
+            reason: reason.into(),
+        }
+    }
+
+    /// Create a new proxy server error
+    pub fn proxy_server(reason: impl Into<String>) -> Self {
+        AppError::ProxyServerError {
+            reason: reason.into(),
+        }
+    }
+
+    /// Create a new certificate error
+    pub fn certificate(reason: impl Into<String>) -> Self {
+        AppError::CertificateError {
+            reason: reason.into(),
+        }
+    }
+
+    /// Create a new invalid rules file error
+    pub fn invalid_rules(path: &std::path::Path, reason: impl ToString) -> Self {
+        AppError::InvalidRules {
+            path: path.display().to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Create a new state file error
+    pub fn state_file_error(path: &std::path::Path, reason: impl ToString) -> Self {
+        AppError::StateFileError {
+            path: path.display().to_string(),
+            reason: reason.to_string(),
+        }
+    }
 }
\ No newline at end of file
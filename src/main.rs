@@ -4,20 +4,50 @@
 //! HTTP proxy settings on connected Android devices through ADB.
 
 // Internal modules
+mod cert;
 mod cli;
 mod adb;
 mod proxy;
 mod config;
+mod emulator;
 mod error;
+mod network;
+mod server;
+
+use std::process::ExitCode;
+use colored::*;
 
 // Re-exports for cleaner usage
-use crate::config::args::parse_args;
+use crate::config::args::{parse_args, CaAction, Command, RulesAction};
 use crate::error::AppResult;
-use crate::adb::device::{check_adb_availability, get_connected_devices, is_adb_running, restart_adb_server};
-use crate::proxy::manager::{get_proxy_info, view_proxy_direct};
-use crate::cli::{run_cli_mode, show_available_commands};
+use crate::adb::device::{check_adb_availability, get_connected_devices, is_adb_running, restart_adb_server, select_device};
+use crate::proxy::history::show_history;
+use crate::proxy::lease::watch_lease;
+use crate::proxy::manager::{expire_lease, read_proxy_state, view_proxy_direct, ProxyOptions};
+use crate::proxy::reboot::{check_reboot, RebootPolicy};
+use crate::proxy::state::ProxyState;
+use crate::cli::{proxy_options, run_cli_mode, show_available_commands};
+use crate::cli::ca::export_ca;
+use crate::cli::follow::run_follow;
+use crate::cli::guard::run_guard;
+use crate::cli::reboots::run_reboot_watch;
+use crate::cli::serve::run_serve;
+use crate::cli::launch::{run_launch, show_avds};
+use crate::cli::mappings::{remove_rule_set, show_rule_sets};
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Print the readable message rather than the Debug form of the error
+            eprintln!("{} {}", "Error:".red().bold(), e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-fn main() -> AppResult<()> {
+/// Run the requested operation
+fn run() -> AppResult<()> {
     // Parse command-line arguments
     let args = parse_args();
 
@@ -26,7 +56,18 @@ fn main() -> AppResult<()> {
         if args.help_commands {
             return show_help_commands_only();
         }
-        return view_proxy_only();
+        return view_proxy_only(args.serial.as_deref(), args.on_reboot, &proxy_options(&args));
+    }
+
+    // These only read or write the local store and need no device
+    match &args.command {
+        Some(Command::History) => return show_history(args.serial.as_deref()),
+        Some(Command::Rules { action: RulesAction::List }) => return show_rule_sets(),
+        Some(Command::Rules { action: RulesAction::Delete { name } }) => return remove_rule_set(name),
+        Some(Command::Serve(serve)) if !serve.apply => return run_serve(&args, serve, None),
+        Some(Command::Ca { action: CaAction::Export { out } }) => return export_ca(out),
+        Some(Command::LeaseWatch { device, lease }) => return watch_lease(device, *lease, &proxy_options(&args)),
+        _ => {}
     }
 
     // Check if ADB is running and restart if necessary
@@ -35,14 +76,36 @@ fn main() -> AppResult<()> {
     // Check ADB availability
     check_adb_environment()?;
 
+    // Launching an AVD happens before its device exists
+    if let Some(Command::Launch { avd, list, emulator, boot_timeout }) = &args.command {
+        return match avd {
+            Some(avd) if !list => run_launch(&args, avd, emulator.as_deref(), *boot_timeout),
+            _ => show_avds(),
+        };
+    }
+
+    // Watchers cover every device and keep running while they come and go
+    match &args.command {
+        Some(Command::Guard { grace }) => return run_guard(&args, *grace),
+        Some(Command::Follow { interval }) => return run_follow(&args, *interval),
+        Some(Command::RebootWatch { interval }) => return run_reboot_watch(&args, *interval),
+        _ => {}
+    }
+
     // Check device connection status
     check_device_connection()?;
 
+    // Select the device to operate on
+    let serial = select_device(args.serial.as_deref())?;
+
+    // Catch up on what happened to the device since the tool last ran
+    catch_up(&serial, args.on_reboot, &proxy_options(&args));
+
     // Get current proxy settings for display
-    let current_proxy_setting = get_current_proxy_setting()?;
+    let current_proxy_setting = get_current_proxy_setting(&serial)?;
 
     // Run CLI mode
-    run_cli_mode(args, current_proxy_setting)
+    run_cli_mode(args, &serial, current_proxy_setting)
 }
 
 /// Check if ADB is running and restart if necessary
@@ -77,25 +140,26 @@ fn check_device_connection() -> AppResult<()> {
     Ok(())
 }
 
-/// Get current proxy settings
-fn get_current_proxy_setting() -> AppResult<String> {
-    Ok(get_proxy_info()
-        .map(|info| {
-            // Extract just the proxy setting from the info string
-            if info.contains("Not set") {
-                String::new()
-            } else if let Some(line) = info.lines().find(|line| line.contains("Global HTTP Proxy")) {
-                line.split(": ").nth(1).unwrap_or("").to_string()
-            } else {
-                String::new()
-            }
-        })
-        .unwrap_or_else(|_| String::new()))
+/// Get current proxy settings, treating a failed read as unset
+fn get_current_proxy_setting(serial: &str) -> AppResult<ProxyState> {
+    Ok(read_proxy_state(serial).unwrap_or(ProxyState::Unset))
 }
 
 /// View proxy settings only, without any initialization checks
-fn view_proxy_only() -> AppResult<()> {
-    view_proxy_direct()
+fn view_proxy_only(serial: Option<&str>, policy: RebootPolicy, options: &ProxyOptions) -> AppResult<()> {
+    let serial = select_device(serial)?;
+    catch_up(&serial, policy, options);
+    view_proxy_direct(&serial)
+}
+
+/// Handle reboots and expired leases since the last run, warning instead of failing the requested operation
+fn catch_up(serial: &str, policy: RebootPolicy, options: &ProxyOptions) {
+    if let Err(e) = check_reboot(serial, policy, options) {
+        println!("{} {}", "⚠️ Failed to check for a device reboot:".yellow(), e);
+    }
+    if let Err(e) = expire_lease(serial, options) {
+        println!("{} {}", "⚠️ Failed to clear expired proxy lease:".yellow(), e);
+    }
 }
 
 /// Show help commands only, without any initialization checks
@@ -0,0 +1,228 @@
+//! Choosing the host address a device can reach
+
+use std::net::{IpAddr, Ipv4Addr};
+use colored::*;
+use local_ip_address::list_afinet_netifas;
+use crate::adb::commands::{AdbCommand, execute_device_command_string};
+use crate::error::{AppError, AppResult};
+
+/// An IPv4 address assigned to a network interface
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    /// Prefix length, when known
+    pub prefix: Option<u8>,
+}
+
+impl InterfaceAddress {
+    /// Whether `ip` lies in this address's subnet
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let prefix = u32::from(self.prefix.unwrap_or(32).min(32));
+        let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+        u32::from(self.ip) & mask == u32::from(ip) & mask
+    }
+}
+
+impl std::fmt::Display for InterfaceAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "{}/{} ({})", self.ip, prefix, self.name),
+            None => write!(f, "{} ({})", self.ip, self.name),
+        }
+    }
+}
+
+/// List the non-loopback IPv4 addresses of this machine
+pub fn list_host_addresses() -> AppResult<Vec<InterfaceAddress>> {
+    let interfaces = list_afinet_netifas().map_err(|e| AppError::LocalIpError {
+        reason: e.to_string(),
+    })?;
+
+    Ok(interfaces
+        .into_iter()
+        .filter_map(|(name, ip)| match ip {
+            IpAddr::V4(ip) if !ip.is_loopback() => Some(InterfaceAddress {
+                name,
+                ip,
+                prefix: None,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Read the device's IPv4 addresses, Wi-Fi interfaces first
+pub fn device_addresses(serial: &str) -> AppResult<Vec<InterfaceAddress>> {
+    let output = execute_device_command_string(
+        serial,
+        AdbCommand::Shell("ip -4 -o addr show".to_string()),
+    )?;
+
+    Ok(parse_device_addresses(&output))
+}
+
+/// Choose the host address to use as proxy for the device
+///
+/// With `iface`, that interface's address is used. Otherwise the host address
+/// on the same subnet as the device is chosen. Returns `None` when the
+/// device's addresses are unknown, leaving the choice to the default route.
+pub fn select_host_address(serial: &str, iface: Option<&str>) -> AppResult<Option<String>> {
+    let host = list_host_addresses()?;
+
+    if let Some(iface) = iface {
+        let address = host
+            .iter()
+            .find(|address| address.name == iface)
+            .ok_or_else(|| AppError::InterfaceNotFound {
+                iface: iface.to_string(),
+                available: describe(&host),
+            })?;
+        println!("Using {} as requested by --iface", address.to_string().green());
+        return Ok(Some(address.ip.to_string()));
+    }
+
+    let device = match device_addresses(serial) {
+        Ok(device) if !device.is_empty() => device,
+        _ => {
+            println!(
+                "{}",
+                "Could not read the device's network address, using the default route".yellow()
+            );
+            return Ok(None);
+        }
+    };
+
+    let (device_address, matching) = same_subnet(&device, &host).ok_or_else(|| AppError::NoSharedSubnet {
+        device: describe(&device),
+        host: describe(&host),
+    })?;
+    let chosen = matching[0];
+    println!(
+        "Using {}, on the same subnet as device address {}",
+        chosen.to_string().green(),
+        device_address
+    );
+    if matching.len() > 1 {
+        let others: Vec<String> = matching[1..].iter().map(|a| a.to_string()).collect();
+        println!("  (also on this subnet: {}; use --iface to choose)", others.join(", "));
+    }
+    Ok(Some(chosen.ip.to_string()))
+}
+
+// Internal helper functions
+
+/// Parse a line of `ip -4 -o addr show`, e.g. `32: wlan0    inet 192.168.1.23/24 brd ...`
+fn parse_ip_addr_line(line: &str) -> Option<InterfaceAddress> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let inet = fields.iter().position(|field| *field == "inet")?;
+    let name = fields.get(1)?.trim_end_matches(':');
+    let (ip, prefix) = fields.get(inet + 1)?.split_once('/')?;
+
+    Some(InterfaceAddress {
+        name: name.to_string(),
+        ip: ip.parse().ok()?,
+        prefix: prefix.parse().ok(),
+    })
+}
+
+/// Non-loopback addresses from `ip -4 -o addr show`, Wi-Fi interfaces first
+fn parse_device_addresses(output: &str) -> Vec<InterfaceAddress> {
+    let mut addresses: Vec<InterfaceAddress> = output.lines().filter_map(parse_ip_addr_line).collect();
+    addresses.retain(|address| !address.ip.is_loopback());
+    addresses.sort_by_key(|address| !address.name.starts_with("wlan"));
+    addresses
+}
+
+/// The first device address with host addresses on its subnet, and those host addresses
+fn same_subnet<'a>(
+    device: &'a [InterfaceAddress],
+    host: &'a [InterfaceAddress],
+) -> Option<(&'a InterfaceAddress, Vec<&'a InterfaceAddress>)> {
+    device.iter().find_map(|device_address| {
+        let matching: Vec<&InterfaceAddress> = host
+            .iter()
+            .filter(|address| device_address.contains(address.ip))
+            .collect();
+        (!matching.is_empty()).then_some((device_address, matching))
+    })
+}
+
+fn describe(addresses: &[InterfaceAddress]) -> String {
+    if addresses.is_empty() {
+        return "none".to_string();
+    }
+    addresses
+        .iter()
+        .map(|address| address.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(name: &str, ip: &str, prefix: Option<u8>) -> InterfaceAddress {
+        InterfaceAddress {
+            name: name.to_string(),
+            ip: ip.parse().unwrap(),
+            prefix,
+        }
+    }
+
+    #[test]
+    fn ip_addr_lines_are_parsed() {
+        assert_eq!(
+            parse_ip_addr_line("32: wlan0    inet 192.168.1.23/24 brd 192.168.1.255 scope global wlan0\\       valid_lft forever"),
+            Some(address("wlan0", "192.168.1.23", Some(24)))
+        );
+        assert_eq!(
+            parse_ip_addr_line("1: lo    inet 127.0.0.1/8 scope host lo"),
+            Some(address("lo", "127.0.0.1", Some(8)))
+        );
+        assert_eq!(parse_ip_addr_line("3: rmnet_data0    inet 10.12.0.5/bad scope global"), Some(address("rmnet_data0", "10.12.0.5", None)));
+        for line in ["", "2: dummy0: <BROADCAST,NOARP> mtu 1500", "4: eth0    inet 300.1.1.1/24", "5: eth0    inet 10.0.0.1"] {
+            assert_eq!(parse_ip_addr_line(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn device_addresses_list_wifi_first_without_loopback() {
+        let output = "1: lo    inet 127.0.0.1/8 scope host lo\n\
+                      12: rmnet_data1    inet 10.54.3.9/30 scope global rmnet_data1\n\
+                      32: wlan0    inet 192.168.1.23/24 brd 192.168.1.255 scope global wlan0";
+        let names: Vec<String> = parse_device_addresses(output).into_iter().map(|address| address.name).collect();
+        assert_eq!(names, ["wlan0", "rmnet_data1"]);
+    }
+
+    #[test]
+    fn subnets_contain_their_addresses() {
+        let wifi = address("wlan0", "192.168.1.23", Some(24));
+        assert!(wifi.contains("192.168.1.200".parse().unwrap()));
+        assert!(!wifi.contains("192.168.2.23".parse().unwrap()));
+        assert!(address("any", "10.1.2.3", Some(0)).contains("172.16.0.1".parse().unwrap()));
+        let unknown = address("eth0", "10.0.0.5", None);
+        assert!(unknown.contains("10.0.0.5".parse().unwrap()));
+        assert!(!unknown.contains("10.0.0.6".parse().unwrap()));
+    }
+
+    #[test]
+    fn the_host_address_on_the_device_subnet_is_chosen() {
+        let device = [address("wlan0", "192.168.1.23", Some(24)), address("rmnet0", "10.54.3.9", Some(8))];
+        let host = [
+            address("docker0", "172.17.0.1", None),
+            address("en0", "192.168.1.10", None),
+            address("en1", "192.168.1.11", None),
+            address("utun3", "10.8.0.2", None),
+        ];
+
+        let (device_address, matching) = same_subnet(&device, &host).unwrap();
+        assert_eq!(device_address.name, "wlan0");
+        let names: Vec<&str> = matching.iter().map(|address| address.name.as_str()).collect();
+        assert_eq!(names, ["en0", "en1"]);
+
+        assert!(same_subnet(&device[..1], &host[..1]).is_none());
+        assert_eq!(same_subnet(&device[1..], &host).unwrap().1[0].name, "utun3");
+    }
+}
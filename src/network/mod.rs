@@ -0,0 +1,3 @@
+//! Host and device network addressing
+
+pub mod interfaces;
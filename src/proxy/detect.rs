@@ -0,0 +1,314 @@
+//! Detection of debugging proxies listening on this machine
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// Well-known debugging proxies: (port, tool name, program names)
+const KNOWN_PROXIES: &[(u16, &str, &[&str])] = &[
+    (8888, "Charles", &["charles"]),
+    (8080, "mitmproxy", &["mitmproxy", "mitmdump", "mitmweb"]),
+    (9090, "Proxyman", &["proxyman"]),
+    (8866, "Fiddler", &["fiddler"]),
+    (8899, "whistle", &["whistle"]),
+];
+
+/// Longest process command line shown when listing proxies
+const MAX_PROCESS_DISPLAY: usize = 60;
+
+/// Interpreters whose script or jar names the program they run
+const INTERPRETERS: &[&str] = &["java", "node", "python", "python3"];
+
+/// A listening socket that looks like a debugging proxy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetectedProxy {
+    pub port: u16,
+    /// Name of the tool the socket most likely belongs to
+    pub tool: String,
+    /// Command line of the owning process, when it could be read
+    pub process: Option<String>,
+    /// Whether the owning process was identified, rather than guessed from the port
+    pub identified: bool,
+}
+
+impl DetectedProxy {
+    /// Tool name, marked with `?` when only the port suggests it
+    pub fn tool_label(&self) -> String {
+        if self.identified {
+            self.tool.clone()
+        } else {
+            format!("{}?", self.tool)
+        }
+    }
+}
+
+impl fmt::Display for DetectedProxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.process {
+            Some(process) if process.chars().count() > MAX_PROCESS_DISPLAY => {
+                let short: String = process.chars().take(MAX_PROCESS_DISPLAY).collect();
+                write!(f, "{} on port {} ({}…)", self.tool_label(), self.port, short)
+            }
+            Some(process) => write!(f, "{} on port {} ({})", self.tool_label(), self.port, process),
+            None => write!(f, "{} on port {}", self.tool_label(), self.port),
+        }
+    }
+}
+
+/// Find debugging proxies listening on this machine
+///
+/// On Linux the listening sockets and their owning processes are read from
+/// `/proc`, so proxies on non-standard ports are found by program name and
+/// a tool listening on several ports is reported once. Elsewhere only the
+/// well-known ports are probed on loopback, which only guesses the tool.
+pub fn detect_local_proxies() -> Vec<DetectedProxy> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(found) = linux::detect() {
+            return found;
+        }
+    }
+
+    probe_known_ports()
+}
+
+// Internal helper functions
+
+fn probe_known_ports() -> Vec<DetectedProxy> {
+    KNOWN_PROXIES
+        .iter()
+        .filter(|(port, _, _)| {
+            let address = SocketAddr::from((Ipv4Addr::LOCALHOST, *port));
+            TcpStream::connect_timeout(&address, Duration::from_millis(200)).is_ok()
+        })
+        .map(|(port, tool, _)| DetectedProxy {
+            port: *port,
+            tool: tool.to_string(),
+            process: None,
+            identified: false,
+        })
+        .collect()
+}
+
+/// Tool run by a process, from the names of its executable and command
+///
+/// `names` are the executable and `comm` names, `args` the command line.
+/// Arguments only count after an interpreter, where they name the script
+/// or jar being run, so an editor or a grep mentioning a tool never matches.
+fn identify_tool(names: &[&str], args: &[&str]) -> Option<&'static str> {
+    let mut programs: Vec<String> = names.iter().map(|name| program_name(name)).collect();
+    if let Some(first) = args.first() {
+        let first = program_name(first);
+        if INTERPRETERS.iter().any(|interpreter| first.starts_with(interpreter)) {
+            programs.extend(args[1..].iter().filter(|arg| !arg.starts_with('-')).take(1).map(|arg| program_name(arg)));
+        }
+        programs.push(first);
+    }
+
+    KNOWN_PROXIES
+        .iter()
+        .find(|(_, _, known)| programs.iter().any(|program| known.contains(&program.as_str())))
+        .map(|(_, tool, _)| *tool)
+}
+
+/// Lowercase file name of a path, without a `.jar`, `.js`, `.py` or `.exe` extension
+fn program_name(path: &str) -> String {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path).to_ascii_lowercase();
+    for extension in [".jar", ".js", ".py", ".exe"] {
+        if let Some(stem) = name.strip_suffix(extension) {
+            return stem.to_string();
+        }
+    }
+    name
+}
+
+/// Tool usually listening on `port`
+fn tool_for_port(port: u16) -> Option<&'static str> {
+    KNOWN_PROXIES
+        .iter()
+        .find(|(known_port, _, _)| *known_port == port)
+        .map(|(_, tool, _)| *tool)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::collections::{BTreeMap, HashMap};
+    use std::fs;
+    use std::path::Path;
+    use super::{identify_tool, tool_for_port, DetectedProxy};
+
+    /// TCP state code of a listening socket in `/proc/net/tcp`
+    const TCP_LISTEN: &str = "0A";
+
+    /// Process holding a socket
+    struct Owner {
+        pid: u32,
+        command: String,
+        tool: Option<&'static str>,
+    }
+
+    /// Detect proxies from `/proc`, or `None` when it cannot be read
+    ///
+    /// Sockets of an identified tool are grouped by process and reported on
+    /// the tool's usual port when it listens there, else on its lowest port.
+    /// Sockets whose owner cannot be read are guessed from the port alone.
+    pub fn detect() -> Option<Vec<DetectedProxy>> {
+        let mut listeners: Vec<(u16, u64)> = Vec::new();
+        let mut readable = false;
+        for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+            if let Ok(content) = fs::read_to_string(table) {
+                readable = true;
+                listeners.extend(parse_listeners(&content));
+            }
+        }
+        if !readable {
+            return None;
+        }
+        listeners.sort_unstable();
+        listeners.dedup_by_key(|(port, _)| *port);
+
+        let owners = socket_owners();
+        let mut by_process: BTreeMap<u32, (&Owner, Vec<u16>)> = BTreeMap::new();
+        let mut found: Vec<DetectedProxy> = Vec::new();
+        for (port, inode) in listeners {
+            match owners.get(&inode) {
+                Some(owner) if owner.tool.is_some() => by_process.entry(owner.pid).or_insert((owner, Vec::new())).1.push(port),
+                Some(_) => {}
+                None => {
+                    if let Some(tool) = tool_for_port(port) {
+                        found.push(DetectedProxy {
+                            port,
+                            tool: tool.to_string(),
+                            process: None,
+                            identified: false,
+                        });
+                    }
+                }
+            }
+        }
+
+        for (owner, ports) in by_process.into_values() {
+            let tool = owner.tool.unwrap_or_default();
+            let port = ports
+                .iter()
+                .copied()
+                .find(|port| tool_for_port(*port) == Some(tool))
+                .unwrap_or(ports[0]);
+            found.push(DetectedProxy {
+                port,
+                tool: tool.to_string(),
+                process: Some(owner.command.clone()),
+                identified: true,
+            });
+        }
+        found.sort_by_key(|proxy| proxy.port);
+
+        Some(found)
+    }
+
+    /// Parse (port, inode) of listening sockets from a `/proc/net/tcp*` table
+    fn parse_listeners(content: &str) -> Vec<(u16, u64)> {
+        content
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 10 || fields[3] != TCP_LISTEN {
+                    return None;
+                }
+                let port = fields[1].rsplit_once(':')?.1;
+                let port = u16::from_str_radix(port, 16).ok()?;
+                let inode = fields[9].parse().ok()?;
+                Some((port, inode))
+            })
+            .collect()
+    }
+
+    /// Map socket inodes to the process holding them
+    fn socket_owners() -> HashMap<u64, Owner> {
+        let mut owners = HashMap::new();
+        let Ok(processes) = fs::read_dir("/proc") else {
+            return owners;
+        };
+
+        for process in processes.flatten() {
+            let Some(pid) = process.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+            let path = process.path();
+            let Ok(fds) = fs::read_dir(path.join("fd")) else {
+                continue;
+            };
+
+            let args: Vec<String> = fs::read(path.join("cmdline"))
+                .map(|raw| {
+                    String::from_utf8_lossy(&raw)
+                        .split('\0')
+                        .filter(|part| !part.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default();
+            let comm = fs::read_to_string(path.join("comm")).unwrap_or_default().trim().to_string();
+            let exe = fs::read_link(path.join("exe"))
+                .ok()
+                .and_then(|exe| exe.file_name().map(|name| name.to_string_lossy().into_owned()))
+                .unwrap_or_default();
+
+            let arg_refs: Vec<&str> = args.iter().map(String::as_str).collect();
+            let tool = identify_tool(&[exe.as_str(), comm.as_str()], &arg_refs);
+            let command = if args.is_empty() { comm } else { args.join(" ") };
+
+            for fd in fds.flatten() {
+                let Ok(target) = fs::read_link(fd.path()) else {
+                    continue;
+                };
+                if let Some(inode) = socket_inode(&target) {
+                    owners.insert(
+                        inode,
+                        Owner {
+                            pid,
+                            command: command.clone(),
+                            tool,
+                        },
+                    );
+                }
+            }
+        }
+
+        owners
+    }
+
+    fn socket_inode(target: &Path) -> Option<u64> {
+        target
+            .to_str()?
+            .strip_prefix("socket:[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .and_then(|inode| inode.parse().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tools_are_identified_by_program_name() {
+        assert_eq!(identify_tool(&["mitmweb"], &["/usr/bin/mitmweb"]), Some("mitmproxy"));
+        assert_eq!(
+            identify_tool(&["python3.12", "mitmdump"], &["/usr/bin/python3", "/home/me/.local/bin/mitmdump", "-p", "8081"]),
+            Some("mitmproxy")
+        );
+        assert_eq!(
+            identify_tool(&["java", "java"], &["java", "-Xmx1024M", "-jar", "/usr/lib/charles-proxy/charles.jar"]),
+            Some("Charles")
+        );
+    }
+
+    #[test]
+    fn arguments_mentioning_a_tool_do_not_match() {
+        assert_eq!(identify_tool(&["grep", "grep"], &["grep", "-r", "charles", "."]), None);
+        assert_eq!(identify_tool(&["nvim", "nvim"], &["nvim", "proxyman.md"]), None);
+        assert_eq!(identify_tool(&["java", "java"], &["java", "-jar", "tomcat.jar", "charles"]), None);
+    }
+}
@@ -0,0 +1,203 @@
+//! Per-device record of proxy changes
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use crate::config::paths::data_file;
+use crate::config::time::{format_timestamp, unix_now};
+use crate::error::{AppError, AppResult};
+use crate::proxy::state::ProxyState;
+
+/// File name of the history store inside the state directory
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// Kind of change recorded in the history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryAction {
+    Set,
+    Clear,
+    Undo,
+    Restore,
+    Expire,
+    Reapply,
+}
+
+impl fmt::Display for HistoryAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HistoryAction::Set => "set",
+            HistoryAction::Clear => "clear",
+            HistoryAction::Undo => "undo",
+            HistoryAction::Restore => "restore",
+            HistoryAction::Expire => "expire",
+            HistoryAction::Reapply => "reapply",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A single proxy change on a device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub serial: String,
+    pub action: HistoryAction,
+    /// Raw `http_proxy` value before the change
+    pub previous: String,
+    /// Raw `http_proxy` value after the change
+    pub new: String,
+    /// Local user who ran the tool
+    pub user: String,
+}
+
+impl HistoryEntry {
+    /// Create an entry for a change made now by the current user
+    pub fn new(serial: &str, action: HistoryAction, previous: &str, new: &str) -> Self {
+        Self {
+            timestamp: unix_now(),
+            serial: serial.to_string(),
+            action,
+            previous: previous.to_string(),
+            new: new.to_string(),
+            user: current_user(),
+        }
+    }
+}
+
+/// Append an entry to the history store
+pub fn record(entry: &HistoryEntry) -> AppResult<()> {
+    let path = data_file(HISTORY_FILE)?;
+    let line = serde_json::to_string(entry).map_err(|e| AppError::state_file_error(&path, e))?;
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| AppError::state_file_error(&path, e))?;
+    writeln!(file, "{}", line).map_err(|e| AppError::state_file_error(&path, e))?;
+
+    Ok(())
+}
+
+/// Load the entries of one device, most recent first
+pub fn entries_for(serial: &str) -> AppResult<Vec<HistoryEntry>> {
+    let mut entries: Vec<HistoryEntry> = load_all()?
+        .into_iter()
+        .filter(|entry| entry.serial == serial)
+        .collect();
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Print the history, limited to one device when a serial is given
+pub fn show_history(serial: Option<&str>) -> AppResult<()> {
+    let entries = load_all()?;
+
+    let mut serials: Vec<&str> = entries.iter().map(|entry| entry.serial.as_str()).collect();
+    serials.sort_unstable();
+    serials.dedup();
+    if let Some(serial) = serial {
+        serials.retain(|s| *s == serial);
+    }
+
+    if serials.is_empty() {
+        println!("{}", "No proxy history recorded".yellow());
+        return Ok(());
+    }
+
+    for serial in serials {
+        println!("\n{}", format!("=== {} ===", serial).blue().bold());
+        let device_entries = entries.iter().rev().filter(|entry| entry.serial == serial);
+        for (index, entry) in device_entries.enumerate() {
+            println!(
+                "{:>3}. {}  {:<7} {} -> {}  ({})",
+                index + 1,
+                format_timestamp(entry.timestamp),
+                entry.action.to_string(),
+                ProxyState::parse(&entry.previous).to_string().yellow(),
+                ProxyState::parse(&entry.new).to_string().green(),
+                entry.user
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Internal helper functions
+
+fn load_all() -> AppResult<Vec<HistoryEntry>> {
+    let path = data_file(HISTORY_FILE)?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let content = fs::read_to_string(&path).map_err(|e| AppError::state_file_error(&path, e))?;
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|e| AppError::state_file_error(&path, e)))
+        .collect()
+}
+
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::paths::TestDataDir;
+
+    #[test]
+    fn entries_are_kept_per_device_most_recent_first() {
+        let _data = TestDataDir::new("history");
+        assert!(entries_for("emulator-5554").unwrap().is_empty());
+
+        record(&HistoryEntry::new("emulator-5554", HistoryAction::Set, ":0", "10.0.2.2:8083")).unwrap();
+        record(&HistoryEntry::new("R58M123ABC", HistoryAction::Set, ":0", "192.168.1.20:8888")).unwrap();
+        record(&HistoryEntry::new("emulator-5554", HistoryAction::Clear, "10.0.2.2:8083", ":0")).unwrap();
+
+        let entries = entries_for("emulator-5554").unwrap();
+        let changes: Vec<(HistoryAction, &str, &str)> = entries
+            .iter()
+            .map(|entry| (entry.action, entry.previous.as_str(), entry.new.as_str()))
+            .collect();
+        assert_eq!(
+            changes,
+            [(HistoryAction::Clear, "10.0.2.2:8083", ":0"), (HistoryAction::Set, ":0", "10.0.2.2:8083")]
+        );
+        assert_eq!(entries_for("R58M123ABC").unwrap().len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn undo_reverts_the_latest_change() {
+        use crate::adb::fake::FakeAdb;
+        use crate::proxy::manager::{undo_proxy, ProxyOptions};
+        use std::time::Duration;
+
+        let _data = TestDataDir::new("undo");
+        let adb = FakeAdb::install("undo");
+        adb.set("http_proxy", "10.0.2.2:8083");
+        record(&HistoryEntry::new("emulator-5554", HistoryAction::Set, ":0", "10.0.2.2:8083")).unwrap();
+        let options = ProxyOptions {
+            verify_timeout: Duration::from_secs(1),
+            force: false,
+            ttl: None,
+            keep_lease: false,
+        };
+
+        undo_proxy("emulator-5554", &options).unwrap();
+
+        assert_eq!(adb.get("http_proxy").as_deref(), Some(":0"));
+        let latest = &entries_for("emulator-5554").unwrap()[0];
+        assert_eq!((latest.action, latest.previous.as_str(), latest.new.as_str()), (HistoryAction::Undo, "10.0.2.2:8083", ":0"));
+    }
+}
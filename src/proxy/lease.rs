@@ -0,0 +1,331 @@
+//! Time-limited proxies that are cleared again when their lease expires
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use crate::config::duration::format_duration;
+use crate::config::paths::{data_file, read_state_file, write_state_file};
+use crate::error::{AppError, AppResult};
+use crate::config::time::unix_now;
+use crate::proxy::manager::{expire_lease, ProxyOptions};
+use crate::proxy::state::ProxyState;
+
+/// File name of the lease store inside the state directory
+const LEASES_FILE: &str = "leases.json";
+
+/// File the detached watchers write their output to
+const WATCHER_LOG_FILE: &str = "lease-watch.log";
+
+/// Longest sleep of a watcher between two looks at its lease
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A proxy that should be cleared from a device at a given time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    /// Identifies the lease, so a watcher can tell when it was replaced
+    pub id: u64,
+    /// Raw `http_proxy` value the lease applies to
+    pub proxy: String,
+    /// Seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+impl Lease {
+    /// Time left before expiry, `None` once the lease has expired
+    pub fn remaining(&self) -> Option<Duration> {
+        let now = unix_now();
+        (self.expires_at > now).then(|| Duration::from_secs(self.expires_at - now))
+    }
+
+    /// Whether the lease covers the proxy currently on the device
+    pub fn covers(&self, state: &ProxyState) -> bool {
+        ProxyState::parse(&self.proxy) == *state
+    }
+}
+
+/// Leases by device serial
+pub type Leases = BTreeMap<String, Lease>;
+
+/// Get the lease of a device, if any
+pub fn get_lease(serial: &str) -> AppResult<Option<Lease>> {
+    Ok(load_leases()?.remove(serial))
+}
+
+/// Record a lease for the proxy just set and start a watcher for it
+pub fn grant_lease(serial: &str, state: &ProxyState, ttl: Duration) -> AppResult<Lease> {
+    let lease = record_lease(serial, state, ttl)?;
+    spawn_watcher(serial, lease.id)?;
+    Ok(lease)
+}
+
+/// Point the lease of a device at the proxy that took over from the one it covered
+///
+/// The lease keeps its id and expiry, so its watcher stays in charge and
+/// clears the new proxy at the time the old one would have been cleared.
+pub fn move_lease(serial: &str, state: &ProxyState) -> AppResult<Option<Lease>> {
+    let mut leases = load_leases()?;
+    let lease = match leases.get_mut(serial) {
+        Some(lease) => {
+            lease.proxy = state.to_setting_value();
+            lease.clone()
+        }
+        None => return Ok(None),
+    };
+    store(&leases)?;
+    Ok(Some(lease))
+}
+
+/// Drop the lease of a device, the proxy it covered having been replaced or cleared
+pub fn release_lease(serial: &str) -> AppResult<()> {
+    let mut leases = load_leases()?;
+    if leases.remove(serial).is_some() {
+        store(&leases)?;
+    }
+    Ok(())
+}
+
+/// Print the time left on the lease of the proxy shown for a device
+pub fn print_lease(serial: &str, state: &ProxyState) {
+    match get_lease(serial) {
+        Ok(Some(lease)) if lease.covers(state) => match lease.remaining() {
+            Some(remaining) => println!("Lease: clears in {}", format_duration(remaining).yellow()),
+            None => println!("Lease: {}", "expired, clearing on next use".red()),
+        },
+        Ok(_) => {}
+        Err(e) => println!("{} {}", "⚠️ Failed to read proxy lease:".yellow(), e),
+    }
+}
+
+/// Wait for a lease to expire and clear the proxy it covers
+///
+/// Runs as the detached `lease-watch` process. Returns once the lease is
+/// enforced, released, or replaced by a newer one with its own watcher.
+pub fn watch_lease(serial: &str, id: u64, options: &ProxyOptions) -> AppResult<()> {
+    loop {
+        let lease = match get_lease(serial)? {
+            Some(lease) if lease.id == id => lease,
+            _ => return Ok(()),
+        };
+
+        match lease.remaining() {
+            Some(remaining) => thread::sleep(remaining.min(WATCH_INTERVAL)),
+            None => match expire_lease(serial, options) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    // The device may be offline, try again until it is back
+                    println!("{} {}", "⚠️ Failed to clear expired proxy:".yellow(), e);
+                    thread::sleep(WATCH_INTERVAL);
+                }
+            },
+        }
+    }
+}
+
+// Internal helper functions
+
+/// Store a new lease of the device, replacing any previous one
+fn record_lease(serial: &str, state: &ProxyState, ttl: Duration) -> AppResult<Lease> {
+    let lease = Lease {
+        id: lease_id(),
+        proxy: state.to_setting_value(),
+        expires_at: unix_now().saturating_add(ttl.as_secs()),
+    };
+
+    let mut leases = load_leases()?;
+    leases.insert(serial.to_string(), lease.clone());
+    store(&leases)?;
+    Ok(lease)
+}
+
+fn load_leases() -> AppResult<Leases> {
+    let path = data_file(LEASES_FILE)?;
+    match read_state_file(&path)? {
+        Some(content) => serde_json::from_str(&content).map_err(|e| AppError::state_file_error(&path, e)),
+        None => Ok(Leases::new()),
+    }
+}
+
+fn store(leases: &Leases) -> AppResult<()> {
+    let path = data_file(LEASES_FILE)?;
+    let content = serde_json::to_string_pretty(leases).map_err(|e| AppError::state_file_error(&path, e))?;
+    write_state_file(&path, &content)
+}
+
+fn lease_id() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Start `lease-watch` for the lease in the background, detached from this terminal
+fn spawn_watcher(serial: &str, id: u64) -> AppResult<()> {
+    let exe = std::env::current_exe()?;
+    let log_path = data_file(WATCHER_LOG_FILE)?;
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(|e| AppError::state_file_error(&log_path, e))?;
+
+    let mut command = Command::new(exe);
+    command
+        .args(["lease-watch", serial, "--lease", &id.to_string()])
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+
+    // Keep the watcher alive when the terminal sends Ctrl-C to our process group
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    command.spawn()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::paths::TestDataDir;
+
+    /// Move the lease of a device `seconds` into the past
+    fn backdate(serial: &str, seconds: u64) {
+        let mut leases = load_leases().unwrap();
+        let lease = leases.get_mut(serial).unwrap();
+        lease.expires_at -= seconds;
+        store(&leases).unwrap();
+    }
+
+    #[test]
+    fn leases_are_recorded_replaced_and_released() {
+        let _data = TestDataDir::new("leases");
+        let state = ProxyState::parse("192.168.1.20:8888");
+
+        let first = record_lease("emulator-5554", &state, Duration::from_secs(1800)).unwrap();
+        let remaining = first.remaining().unwrap();
+        assert!(remaining > Duration::from_secs(1790) && remaining <= Duration::from_secs(1800));
+        assert!(first.covers(&state));
+        assert!(!first.covers(&ProxyState::Unset));
+
+        let second = record_lease("emulator-5554", &state, Duration::from_secs(60)).unwrap();
+        record_lease("R58M123ABC", &state, Duration::from_secs(60)).unwrap();
+        assert_eq!(get_lease("emulator-5554").unwrap().unwrap().id, second.id);
+
+        release_lease("emulator-5554").unwrap();
+        assert!(get_lease("emulator-5554").unwrap().is_none());
+        assert!(get_lease("R58M123ABC").unwrap().is_some());
+        release_lease("emulator-5554").unwrap();
+
+        let forever = record_lease("R58M123ABC", &state, Duration::from_secs(u64::MAX)).unwrap();
+        assert_eq!(forever.expires_at, u64::MAX);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expired_leases_clear_the_proxy_they_cover() {
+        use crate::adb::fake::FakeAdb;
+
+        let _data = TestDataDir::new("lease-expiry");
+        let adb = FakeAdb::install("lease-expiry");
+        let options = ProxyOptions {
+            verify_timeout: Duration::from_secs(1),
+            force: false,
+            ttl: None,
+            keep_lease: false,
+        };
+        let state = ProxyState::parse("192.168.1.20:8888");
+        adb.set("http_proxy", "192.168.1.20:8888");
+
+        // Not expired yet: nothing happens
+        record_lease("emulator-5554", &state, Duration::from_secs(60)).unwrap();
+        expire_lease("emulator-5554", &options).unwrap();
+        assert_eq!(adb.get("http_proxy").as_deref(), Some("192.168.1.20:8888"));
+
+        // Expired: the watcher clears the proxy and drops the lease
+        backdate("emulator-5554", 120);
+        let id = get_lease("emulator-5554").unwrap().unwrap().id;
+        watch_lease("emulator-5554", id, &options).unwrap();
+        assert_eq!(adb.get("http_proxy").as_deref(), Some(":0"));
+        assert!(get_lease("emulator-5554").unwrap().is_none());
+
+        // A proxy changed since the lease was granted is left alone
+        record_lease("emulator-5554", &state, Duration::from_secs(60)).unwrap();
+        backdate("emulator-5554", 120);
+        adb.set("http_proxy", "10.0.2.2:8083");
+        expire_lease("emulator-5554", &options).unwrap();
+        assert_eq!(adb.get("http_proxy").as_deref(), Some("10.0.2.2:8083"));
+        assert!(get_lease("emulator-5554").unwrap().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_repointed_proxy_keeps_its_expiry() {
+        use crate::adb::fake::FakeAdb;
+        use crate::proxy::manager::set_proxy;
+        use crate::proxy::settings::ProxySettings;
+        use std::net::TcpListener;
+
+        let _data = TestDataDir::new("lease-move");
+        let adb = FakeAdb::install("lease-move");
+        adb.set("http_proxy", "192.168.1.20:8888");
+        assert!(move_lease("emulator-5554", &ProxyState::Unset).unwrap().is_none());
+        let granted = record_lease("emulator-5554", &ProxyState::parse("192.168.1.20:8888"), Duration::from_secs(1800)).unwrap();
+
+        // `follow` moving the proxy to another address this computer listens on
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = ProxySettings::usb(listener.local_addr().unwrap().port()).unwrap();
+        let options = ProxyOptions {
+            verify_timeout: Duration::from_secs(1),
+            force: false,
+            ttl: None,
+            keep_lease: true,
+        };
+        set_proxy("emulator-5554", &settings, &options).unwrap();
+
+        let moved = get_lease("emulator-5554").unwrap().unwrap();
+        assert_eq!((moved.id, moved.expires_at), (granted.id, granted.expires_at));
+        assert!(moved.covers(&ProxyState::parse(&settings.to_proxy_string())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_proxy_reapplied_after_a_reboot_keeps_its_expiry() {
+        use crate::adb::fake::FakeAdb;
+        use crate::proxy::reboot::{check_reboot, record_boot, RebootPolicy};
+        use std::net::TcpListener;
+
+        let _data = TestDataDir::new("lease-reboot");
+        let adb = FakeAdb::install("lease-reboot");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let state = ProxyState::parse(&proxy);
+
+        adb.set("boot_id", "first-boot");
+        adb.set("http_proxy", &proxy);
+        record_boot("emulator-5554", &state).unwrap();
+        let granted = record_lease("emulator-5554", &state, Duration::from_secs(1800)).unwrap();
+
+        // The device comes back without its proxy; this run was given a --ttl of its own
+        adb.set("boot_id", "second-boot");
+        adb.set("http_proxy", ":0");
+        let options = ProxyOptions {
+            verify_timeout: Duration::from_secs(1),
+            force: false,
+            ttl: Some(Duration::from_secs(60)),
+            keep_lease: false,
+        };
+        check_reboot("emulator-5554", RebootPolicy::Reapply, &options).unwrap();
+
+        assert_eq!(adb.get("http_proxy").as_deref(), Some(proxy.as_str()));
+        let kept = get_lease("emulator-5554").unwrap().unwrap();
+        assert_eq!((kept.id, kept.expires_at), (granted.id, granted.expires_at));
+        assert!(kept.covers(&state));
+    }
+}
@@ -1,43 +1,241 @@
 //! Proxy management operations
 
-use std::thread;
 use std::time::Duration;
 use colored::*;
+use crate::config::time::format_timestamp;
 use crate::error::{AppError, AppResult};
-use crate::adb::commands::{AdbCommand, execute_adb_command, execute_adb_command_string};
+use crate::adb::commands::{AdbCommand, execute_device_command, execute_device_command_string};
+use crate::adb::mapping::{add_reverse_port, remove_reverse_port};
+use crate::proxy::history::{self, HistoryAction, HistoryEntry};
+use crate::proxy::lease::{self, print_lease};
+use crate::proxy::preflight::check_proxy_reachable;
+use crate::proxy::reboot::record_boot;
 use crate::proxy::settings::ProxySettings;
+use crate::proxy::state::ProxyState;
+use crate::proxy::verify::wait_for_state;
+
+/// How long to wait for a change to become visible on the device by default
+pub const DEFAULT_VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options shared by the operations that change the device proxy
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    /// Deadline for the new value to be read back from the device
+    pub verify_timeout: Duration,
+    /// Apply the proxy even when the reachability checks fail
+    pub force: bool,
+    /// Clear the proxy again after this long
+    pub ttl: Option<Duration>,
+    /// Move the device's lease to the new proxy instead of granting or releasing one
+    ///
+    /// Set when the tool replaces a proxy on its own, such as `follow`
+    /// re-pointing it or a re-apply after a reboot, so any `--ttl` the user
+    /// chose still ends it at the original time.
+    pub keep_lease: bool,
+}
 
 /// Set proxy on Android device
-pub fn set_proxy(settings: &ProxySettings) -> AppResult<()> {
+pub fn set_proxy(serial: &str, settings: &ProxySettings, options: &ProxyOptions) -> AppResult<()> {
+    apply_proxy(serial, settings, options, HistoryAction::Set)
+}
+
+/// Clear proxy settings on Android device
+pub fn clear_proxy(serial: &str, options: &ProxyOptions) -> AppResult<()> {
+    remove_proxy(serial, options, HistoryAction::Clear)
+}
+
+/// Clear the proxy for a while, leaving its history, lease and boot record as they are
+///
+/// Used by the guard, which puts the proxy back with [`resume_proxy`], so the
+/// interruption is not recorded as a change made by the user.
+pub fn suspend_proxy(serial: &str, options: &ProxyOptions) -> AppResult<()> {
+    clear_proxy_internal(serial)?;
+    wait_for_state(serial, &ProxyState::Unset, options.verify_timeout)?;
+    Ok(())
+}
+
+/// Put back a proxy cleared by [`suspend_proxy`]
+pub fn resume_proxy(serial: &str, target: &ProxyState, options: &ProxyOptions) -> AppResult<()> {
+    execute_device_command(serial, AdbCommand::SetProxy(target.to_setting_value()))?;
+    wait_for_state(serial, target, options.verify_timeout)?;
+    Ok(())
+}
+
+/// Revert the most recent recorded change on the device
+pub fn undo_proxy(serial: &str, options: &ProxyOptions) -> AppResult<()> {
+    let entries = history::entries_for(serial)?;
+    let last = entries.first().ok_or_else(|| AppError::HistoryEmpty {
+        serial: serial.to_string(),
+    })?;
+    let target = ProxyState::parse(&last.previous);
+
+    println!(
+        "Undoing {} from {} (restoring {})",
+        last.action.to_string().yellow(),
+        format_timestamp(last.timestamp),
+        target.to_string().green()
+    );
+
+    apply_state(serial, &target, options, HistoryAction::Undo)
+}
+
+/// Re-apply the value set by history entry `index` (1 being the most recent)
+pub fn restore_proxy(serial: &str, index: usize, options: &ProxyOptions) -> AppResult<()> {
+    let entries = history::entries_for(serial)?;
+    let entry = index
+        .checked_sub(1)
+        .and_then(|i| entries.get(i))
+        .ok_or_else(|| AppError::HistoryEntryNotFound {
+            serial: serial.to_string(),
+            index,
+        })?;
+    let target = ProxyState::parse(&entry.new);
+
+    println!(
+        "Restoring entry #{} from {} ({})",
+        index,
+        format_timestamp(entry.timestamp),
+        target.to_string().green()
+    );
+
+    apply_state(serial, &target, options, HistoryAction::Restore)
+}
+
+/// Set the proxy a device is expected to have again, e.g. after it rebooted
+pub fn reapply_proxy(serial: &str, target: &ProxyState, options: &ProxyOptions) -> AppResult<()> {
+    apply_state(serial, target, options, HistoryAction::Reapply)
+}
+
+/// View current proxy settings
+pub fn view_proxy(serial: &str) -> AppResult<()> {
+    println!(
+        "{}",
+        "Checking current Android device proxy settings...".blue()
+    );
+
+    let state = read_proxy_state(serial)?;
+
+    println!("\n{}", "=== Current Proxy Settings ===".blue().bold());
+    print_proxy_state(&state);
+    print_lease(serial, &state);
+
+    println!("\nPress Enter to continue...");
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input)?;
+
+    Ok(())
+}
+
+/// View current proxy settings without waiting for user input
+pub fn view_proxy_direct(serial: &str) -> AppResult<()> {
+    let state = read_proxy_state(serial)?;
+
+    println!("Current Android Proxy Settings:");
+    print_proxy_state(&state);
+    print_lease(serial, &state);
+
+    Ok(())
+}
+
+/// Clear the proxy of an expired lease
+///
+/// The proxy is only cleared if it is still the one the lease was granted
+/// for; either way the lease is dropped.
+pub fn expire_lease(serial: &str, options: &ProxyOptions) -> AppResult<()> {
+    let lease = match lease::get_lease(serial)? {
+        Some(lease) if lease.remaining().is_none() => lease,
+        _ => return Ok(()),
+    };
+
+    let current = read_proxy_state(serial)?;
+    if lease.covers(&current) {
+        println!(
+            "{}",
+            format!("Proxy lease on {} expired, clearing {}", serial, current).yellow()
+        );
+        remove_proxy(serial, options, HistoryAction::Expire)?;
+    }
+
+    lease::release_lease(serial)
+}
+
+/// Read the proxy currently configured on the device
+///
+/// `http_proxy` takes precedence; the PAC URL is only consulted when no
+/// plain proxy is set.
+pub fn read_proxy_state(serial: &str) -> AppResult<ProxyState> {
+    let state = ProxyState::parse(&get_setting(serial, AdbCommand::GetProxy)?);
+    if state.is_set() {
+        return Ok(state);
+    }
+
+    match ProxyState::parse(&get_setting(serial, AdbCommand::GetPacUrl)?) {
+        pac @ ProxyState::Pac(_) => Ok(pac),
+        _ => Ok(ProxyState::Unset),
+    }
+}
+
+// Internal helper functions
+
+fn apply_proxy(
+    serial: &str,
+    settings: &ProxySettings,
+    options: &ProxyOptions,
+    action: HistoryAction,
+) -> AppResult<()> {
     let proxy_string = settings.to_proxy_string();
+    let expected = ProxyState::parse(&proxy_string);
+    let previous = read_proxy_state(serial)?;
+
+    // In USB mode the device reaches this machine through adb reverse
+    if settings.is_loopback() {
+        println!(
+            "Forwarding device port {} to this computer over USB (adb reverse)...",
+            settings.port.to_string().green()
+        );
+        add_reverse_port(serial, settings.port)?;
+    }
+
+    // A dead proxy cuts the device off the network, so check before writing
+    if let Err(e) = check_proxy_reachable(serial, settings, options.force) {
+        if settings.is_loopback() && previous.loopback_port() != Some(settings.port) {
+            let _ = remove_reverse_port(serial, settings.port);
+        }
+        return Err(e);
+    }
 
     println!(
-        "Preparing to set Android device proxy to {}:{}",
-        settings.ip.green(),
-        settings.port.to_string().green()
+        "Preparing to set Android device proxy to {}",
+        settings.to_proxy_string().green()
     );
 
     // Clear existing proxy settings first
     println!("Clearing existing proxy settings...");
-    let _ = clear_proxy_internal(); // Ignore errors for clearing
-
-    // Wait briefly to ensure clearing is complete
-    thread::sleep(Duration::from_millis(500));
+    // Ignore errors for clearing, the new value is verified below
+    if clear_proxy_internal(serial).is_ok() {
+        let _ = wait_for_state(serial, &ProxyState::Unset, options.verify_timeout);
+    }
 
     // Set new proxy
-    println!(
-        "Setting new proxy to {}:{}",
-        settings.ip.green(),
-        settings.port.to_string().green()
-    );
+    println!("Setting new proxy to {}", proxy_string.green());
 
-    execute_adb_command(AdbCommand::SetProxy(proxy_string.clone()))?;
+    execute_device_command(serial, AdbCommand::SetProxy(proxy_string))?;
 
     // Wait for setting to take effect
-    thread::sleep(Duration::from_millis(500));
+    println!("Verifying proxy settings...");
+    let current = wait_for_state(serial, &expected, options.verify_timeout)?;
+    println!("Current proxy settings: {}", current.to_string().green());
 
-    // Verify proxy settings
-    verify_proxy_settings(&proxy_string)?;
+    record_history(serial, action, &previous, &expected);
+    update_lease(serial, &expected, options);
+    update_boot_record(serial, &expected);
+
+    // Drop the mapping of a previous USB-mode proxy that is no longer used
+    if let Some(old_port) = previous.loopback_port() {
+        if !settings.is_loopback() || old_port != settings.port {
+            remove_reverse_mapping(serial, old_port);
+        }
+    }
 
     println!(
         "{}",
@@ -47,17 +245,25 @@ pub fn set_proxy(settings: &ProxySettings) -> AppResult<()> {
     Ok(())
 }
 
-/// Clear proxy settings on Android device
-pub fn clear_proxy() -> AppResult<()> {
+fn remove_proxy(serial: &str, options: &ProxyOptions, action: HistoryAction) -> AppResult<()> {
     println!("{}", "Clearing Android device proxy settings...".yellow());
 
-    clear_proxy_internal()?;
+    let previous = read_proxy_state(serial)?;
+
+    clear_proxy_internal(serial)?;
 
     // Wait for clearing to take effect
-    thread::sleep(Duration::from_millis(500));
+    println!("Verifying proxy settings...");
+    wait_for_state(serial, &ProxyState::Unset, options.verify_timeout)?;
+    println!("Current proxy settings: {}", "Not set".green());
 
-    // Verify proxy is cleared
-    verify_proxy_cleared()?;
+    record_history(serial, action, &previous, &ProxyState::Unset);
+    release_lease(serial);
+    update_boot_record(serial, &ProxyState::Unset);
+
+    if let Some(port) = previous.loopback_port() {
+        remove_reverse_mapping(serial, port);
+    }
 
     println!(
         "{}",
@@ -67,128 +273,131 @@ pub fn clear_proxy() -> AppResult<()> {
     Ok(())
 }
 
-/// View current proxy settings
-pub fn view_proxy() -> AppResult<()> {
-    println!(
-        "{}",
-        "Checking current Android device proxy settings...".blue()
-    );
-
-    let proxy_setting = get_current_proxy_setting()?;
-
-    println!("\n{}", "=== Current Proxy Settings ===".blue().bold());
-    if proxy_setting.is_empty() || proxy_setting == ":0" {
-        println!("Global HTTP Proxy: {}", "Not set".red());
-    } else {
-        // Split the proxy setting into IP and port
-        if let Some((ip, port)) = proxy_setting.split_once(':') {
-            println!("Global HTTP Proxy: {}", proxy_setting.green());
-            println!("IP Address: {}", ip.green());
-            println!("Port: {}", port.green());
-        } else {
-            println!("Global HTTP Proxy: {}", proxy_setting.green());
-            println!("(Unable to parse IP and port separately)");
+fn apply_state(
+    serial: &str,
+    target: &ProxyState,
+    options: &ProxyOptions,
+    action: HistoryAction,
+) -> AppResult<()> {
+    match target {
+        ProxyState::Unset => remove_proxy(serial, options, action),
+        ProxyState::HostPort { .. } => {
+            // Re-validate recorded values, the history file may have been edited by hand
+            let settings: ProxySettings = target.to_setting_value().parse()?;
+            apply_proxy(serial, &settings, options, action)
         }
+        other => Err(AppError::proxy_get_failed(format!(
+            "cannot re-apply recorded proxy value '{}'",
+            other
+        ))),
     }
+}
 
-    println!("\nPress Enter to continue...");
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
-
+/// Clear both the plain proxy and the PAC URL, as either one counts as a proxy
+fn clear_proxy_internal(serial: &str) -> AppResult<()> {
+    for command in [AdbCommand::ClearProxy, AdbCommand::ClearPacUrl] {
+        execute_device_command(serial, command).map_err(|e| AppError::proxy_clear_failed(e.to_string()))?;
+    }
     Ok(())
 }
 
-/// View current proxy settings without waiting for user input
-pub fn view_proxy_direct() -> AppResult<()> {
-    let proxy_setting = get_current_proxy_setting()?;
+fn get_setting(serial: &str, command: AdbCommand) -> AppResult<String> {
+    execute_device_command_string(serial, command)
+        .map_err(|e| AppError::proxy_get_failed(e.to_string()))
+}
 
-    println!("Current Android Proxy Settings:");
-    if proxy_setting.is_empty() || proxy_setting == ":0" {
-        println!("Global HTTP Proxy: {}", "Not set".red());
-    } else {
-        // Split the proxy setting into IP and port
-        if let Some((ip, port)) = proxy_setting.split_once(':') {
-            println!("Global HTTP Proxy: {}", proxy_setting.green());
-            println!("IP Address: {}", ip.green());
-            println!("Port: {}", port.green());
-        } else {
-            println!("Global HTTP Proxy: {}", proxy_setting.green());
-        }
+fn remove_reverse_mapping(serial: &str, port: u16) {
+    match remove_reverse_port(serial, port) {
+        Ok(()) => println!("Removed USB port mapping for device port {}", port),
+        Err(e) => println!("{} {}", "⚠️ Failed to remove USB port mapping:".yellow(), e),
     }
-
-    Ok(())
 }
 
-/// Get proxy information as a string (for GUI mode)
-pub fn get_proxy_info() -> AppResult<String> {
-    let proxy_setting = get_current_proxy_setting()?;
+fn print_proxy_state(state: &ProxyState) {
+    match state {
+        ProxyState::Unset => println!("Global HTTP Proxy: {}", "Not set".red()),
+        ProxyState::HostPort { host, port } => {
+            println!("Global HTTP Proxy: {}", state.to_string().green());
+            println!("IP Address: {}", host.green());
+            println!("Port: {}", port.to_string().green());
+        }
+        ProxyState::Pac(url) => println!("Proxy auto-config URL: {}", url.green()),
+        ProxyState::Malformed(raw) => {
+            println!("Global HTTP Proxy: {}", raw.yellow());
+            println!("(Unable to parse IP and port separately)");
+        }
+    }
+}
 
-    let mut info = String::from("Current Proxy Settings:\n");
-    if proxy_setting.is_empty() || proxy_setting == ":0" {
-        info.push_str("Global HTTP Proxy: Not set");
+fn update_lease(serial: &str, state: &ProxyState, options: &ProxyOptions) {
+    let result = if options.keep_lease {
+        lease::move_lease(serial, state).map(|lease| {
+            if let Some(lease) = lease {
+                println!(
+                    "Proxy will still be cleared at {}",
+                    format_timestamp(lease.expires_at).yellow()
+                );
+            }
+        })
     } else {
-        // Split the proxy setting into IP and port
-        if let Some((ip, port)) = proxy_setting.split_once(':') {
-            info.push_str(&format!("Global HTTP Proxy: {}\n", proxy_setting));
-            info.push_str(&format!("IP Address: {}\n", ip));
-            info.push_str(&format!("Port: {}", port));
-        } else {
-            info.push_str(&format!("Global HTTP Proxy: {}\n", proxy_setting));
-            info.push_str("(Unable to parse IP and port separately)");
+        match options.ttl {
+            Some(ttl) => lease::grant_lease(serial, state, ttl).map(|lease| {
+                println!(
+                    "Proxy will be cleared at {}",
+                    format_timestamp(lease.expires_at).yellow()
+                );
+            }),
+            None => lease::release_lease(serial),
         }
+    };
+    if let Err(e) = result {
+        println!("{} {}", "⚠️ Failed to update proxy lease:".yellow(), e);
     }
-
-    Ok(info)
 }
 
-// Internal helper functions
-
-fn clear_proxy_internal() -> AppResult<()> {
-    execute_adb_command(AdbCommand::ClearProxy)
-        .map_err(|e| AppError::proxy_clear_failed(e.to_string()))
-        .map(|_| ())
+fn release_lease(serial: &str) {
+    if let Err(e) = lease::release_lease(serial) {
+        println!("{} {}", "⚠️ Failed to update proxy lease:".yellow(), e);
+    }
 }
 
-fn get_current_proxy_setting() -> AppResult<String> {
-    execute_adb_command_string(AdbCommand::GetProxy)
-        .map_err(|e| AppError::proxy_get_failed(e.to_string()))
+fn update_boot_record(serial: &str, state: &ProxyState) {
+    if let Err(e) = record_boot(serial, state) {
+        println!("{} {}", "⚠️ Failed to record device boot:".yellow(), e);
+    }
 }
 
-fn verify_proxy_settings(expected_proxy: &str) -> AppResult<()> {
-    println!("Verifying proxy settings...");
-    let current_proxy = get_current_proxy_setting()?;
-
-    if current_proxy == expected_proxy {
-        println!("Current proxy settings: {}", current_proxy.green());
-    } else {
-        println!(
-            "{}",
-            "⚠️ Proxy settings may be incorrect, please verify manually"
-                .yellow()
-                .bold()
-        );
-        println!("Expected: {}", expected_proxy.green());
-        println!("Actual: {}", current_proxy.yellow());
+fn record_history(serial: &str, action: HistoryAction, previous: &ProxyState, new: &ProxyState) {
+    let entry = HistoryEntry::new(
+        serial,
+        action,
+        &previous.to_setting_value(),
+        &new.to_setting_value(),
+    );
+    if let Err(e) = history::record(&entry) {
+        println!("{} {}", "⚠️ Failed to record proxy history:".yellow(), e);
     }
-
-    Ok(())
 }
 
-fn verify_proxy_cleared() -> AppResult<()> {
-    println!("Verifying proxy settings...");
-    let current_proxy = get_current_proxy_setting()?;
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::adb::fake::FakeAdb;
 
-    if current_proxy.is_empty() || current_proxy == ":0" {
-        println!("Current proxy settings: {}", "Not set".green());
-    } else {
-        println!("Current proxy settings: {}", current_proxy);
-        println!(
-            "{}",
-            "⚠️ Proxy settings may not be cleared properly, please verify manually"
-                .yellow()
-                .bold()
+    #[test]
+    fn clearing_removes_a_pac_url() {
+        let adb = FakeAdb::install("clear-pac");
+        adb.set("global_proxy_pac_url", "http://wpad.example/proxy.pac");
+
+        assert_eq!(
+            read_proxy_state("emulator-5554").unwrap(),
+            ProxyState::Pac("http://wpad.example/proxy.pac".to_string())
         );
+
+        clear_proxy_internal("emulator-5554").unwrap();
+        assert_eq!(read_proxy_state("emulator-5554").unwrap(), ProxyState::Unset);
+        assert_eq!(adb.get("http_proxy").as_deref(), Some(":0"));
+        assert_eq!(adb.get("global_proxy_pac_url"), None);
     }
 
-    Ok(())
-}
\ No newline at end of file
+}
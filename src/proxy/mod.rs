@@ -1,4 +1,11 @@
 //! Proxy management module
 
+pub mod detect;
+pub mod history;
+pub mod lease;
 pub mod manager;
-pub mod settings;
\ No newline at end of file
+pub mod preflight;
+pub mod reboot;
+pub mod settings;
+pub mod state;
+pub mod verify;
@@ -0,0 +1,255 @@
+//! Reachability checks of the proxy endpoint before it is applied
+
+use std::fmt;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use colored::*;
+use crate::adb::commands::{AdbCommand, execute_device_command_string};
+use crate::emulator::detect::is_emulator_host_alias;
+use crate::error::{AppError, AppResult};
+use crate::proxy::settings::ProxySettings;
+
+/// Connection timeout used by both probes
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Marker printed by the device-side probe on success
+const PROBE_OK: &str = "APS_PROBE_OK";
+
+/// Marker printed by the device-side probe when there is no `nc`
+const PROBE_UNAVAILABLE: &str = "APS_PROBE_UNAVAILABLE";
+
+/// Marker printed by the device-side probe before the output of a failed `nc`
+const PROBE_FAILED: &str = "APS_PROBE_FAILED";
+
+/// Outcome of a single reachability probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reachability {
+    Reachable,
+    Unreachable(String),
+    /// The probe could not be run, so nothing is known
+    Unknown(String),
+}
+
+impl fmt::Display for Reachability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reachability::Reachable => write!(f, "reachable"),
+            Reachability::Unreachable(reason) => write!(f, "unreachable ({})", reason),
+            Reachability::Unknown(reason) => write!(f, "unknown ({})", reason),
+        }
+    }
+}
+
+/// Check that the proxy accepts TCP connections from this machine and from the device
+///
+/// A failed check is an error unless `force` is set, in which case it is
+/// only reported. Checks that cannot be run on the device are reported but
+/// never block.
+pub fn check_proxy_reachable(serial: &str, settings: &ProxySettings, force: bool) -> AppResult<()> {
+    let endpoint = settings.to_proxy_string();
+    println!("Checking that {} accepts connections...", endpoint.green());
+
+    // An emulator's host alias is this computer's loopback interface
+    let host_side = if is_emulator_host_alias(serial, &settings.ip) {
+        ProxySettings::usb(settings.port)?
+    } else {
+        settings.clone()
+    };
+
+    let checks = [
+        ("this computer", probe_from_host(&host_side)),
+        ("the device", probe_from_device(serial, settings)),
+    ];
+
+    for (from, result) in checks {
+        match &result {
+            Reachability::Reachable => println!("  From {}: {}", from, result.to_string().green()),
+            Reachability::Unknown(reason) => println!(
+                "  From {}: {}",
+                from,
+                format!("not checked, {}", reason).yellow()
+            ),
+            Reachability::Unreachable(reason) => {
+                println!("  From {}: {}", from, result.to_string().red());
+                if !force {
+                    return Err(AppError::ProxyUnreachable {
+                        endpoint,
+                        from: from.to_string(),
+                        reason: reason.clone(),
+                    });
+                }
+                println!(
+                    "{}",
+                    "⚠️ Setting the proxy anyway because of --force".yellow().bold()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Try to open a TCP connection to the proxy from this machine
+pub fn probe_from_host(settings: &ProxySettings) -> Reachability {
+    let addresses = match settings.resolve() {
+        Ok(addresses) => addresses,
+        Err(e) => return Reachability::Unreachable(e.to_string()),
+    };
+
+    let mut last_error = String::from("no addresses to try");
+    for ip in addresses {
+        match TcpStream::connect_timeout(&SocketAddr::new(ip, settings.port), PROBE_TIMEOUT) {
+            Ok(_) => return Reachability::Reachable,
+            Err(e) => last_error = e.to_string(),
+        }
+    }
+
+    Reachability::Unreachable(last_error)
+}
+
+/// Try to open a TCP connection to the proxy from the device
+///
+/// Uses `nc`, or the `nc` applet of toybox or busybox when it is not on the
+/// device's `PATH`. Devices without any, or with an older `nc` that has no
+/// `-z`, give an unknown result rather than an unreachable one.
+pub fn probe_from_device(serial: &str, settings: &ProxySettings) -> Reachability {
+    match execute_device_command_string(serial, AdbCommand::Shell(probe_script(settings))) {
+        Ok(output) => parse_probe_output(&output),
+        Err(e) => Reachability::Unknown(e.to_string()),
+    }
+}
+
+// Internal helper functions
+
+/// Shell script run on the device, printing one of the probe markers
+///
+/// Only uses shell builtins to find `nc`, as `grep` and `tr` may be
+/// missing too.
+fn probe_script(settings: &ProxySettings) -> String {
+    format!(
+        "has_nc() {{ for applet in $1; do [ \"$applet\" = nc ] && return 0; done; return 1; }}; \
+         if command -v nc >/dev/null 2>&1; then nc=nc; \
+         elif has_nc \"$(toybox 2>/dev/null)\"; then nc=\"toybox nc\"; \
+         elif has_nc \"$(busybox --list 2>/dev/null)\"; then nc=\"busybox nc\"; \
+         else nc=; fi; \
+         if [ -n \"$nc\" ]; then \
+            out=$($nc -z -w {timeout} {host} {port} </dev/null 2>&1) && echo {ok} || echo \"{failed} $out\"; \
+         else \
+            echo {unavailable}; \
+         fi; true",
+        timeout = PROBE_TIMEOUT.as_secs(),
+        host = settings.ip,
+        port = settings.port,
+        ok = PROBE_OK,
+        failed = PROBE_FAILED,
+        unavailable = PROBE_UNAVAILABLE,
+    )
+}
+
+fn parse_probe_output(output: &str) -> Reachability {
+    if output.contains(PROBE_OK) {
+        return Reachability::Reachable;
+    }
+    if output.contains(PROBE_UNAVAILABLE) {
+        return Reachability::Unknown("no nc, toybox nc or busybox nc on the device".to_string());
+    }
+    match output.split_once(PROBE_FAILED) {
+        Some((_, message)) => classify_nc_failure(message),
+        None => Reachability::Unknown(format!("unexpected probe output '{}'", output)),
+    }
+}
+
+/// Tell a failed connection from an `nc` that does not understand the probe
+fn classify_nc_failure(message: &str) -> Reachability {
+    let message = message.trim();
+    let lower = message.to_lowercase();
+    let usage_error = ["usage", "unknown option", "invalid option", "illegal option", "--help"]
+        .iter()
+        .any(|marker| lower.contains(marker));
+
+    if usage_error {
+        Reachability::Unknown("nc on the device does not support -z".to_string())
+    } else if message.is_empty() {
+        Reachability::Unreachable("connection refused or timed out".to_string())
+    } else {
+        Reachability::Unreachable(message.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run the probe script with `sh` and only `dir` on `PATH`
+    #[cfg(unix)]
+    fn run_probe(dir: &std::path::Path, port: u16) -> Reachability {
+        let settings = ProxySettings::new(port, Some("127.0.0.1".to_string())).unwrap();
+        let output = std::process::Command::new("/bin/sh")
+            .env("PATH", dir)
+            .arg("-c")
+            .arg(probe_script(&settings))
+            .output()
+            .unwrap();
+        parse_probe_output(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Install an executable script `name` in `dir`
+    #[cfg(unix)]
+    fn install(dir: &std::path::Path, name: &str, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn probe_falls_back_to_toybox_and_busybox() {
+        let dir = std::env::temp_dir().join(format!("aps-probe-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert!(matches!(run_probe(&dir, 8083), Reachability::Unknown(reason) if reason.contains("no nc")));
+
+        // busybox nc connecting only to port 8083
+        install(&dir, "busybox", "[ \"$1\" = --list ] && { echo sh; echo nc; exit 0; }\n[ \"$6\" = 8083 ]");
+        assert_eq!(run_probe(&dir, 8083), Reachability::Reachable);
+        assert!(matches!(run_probe(&dir, 9000), Reachability::Unreachable(_)));
+
+        // toybox lists its applets on one line and comes first
+        install(&dir, "toybox", "[ $# = 0 ] && { echo \"cat nc sh\"; exit 0; }\necho \"nc: connect: Connection refused\" >&2; exit 1");
+        assert_eq!(
+            run_probe(&dir, 8083),
+            Reachability::Unreachable("nc: connect: Connection refused".to_string())
+        );
+
+        // A toybox without nc is skipped
+        install(&dir, "toybox", "[ $# = 0 ] && echo \"cat nctl sh\"");
+        assert_eq!(run_probe(&dir, 8083), Reachability::Reachable);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nc_usage_errors_are_unknown() {
+        for message in [
+            "nc: Unknown option 'z' (see \"nc --help\")",
+            "usage: nc [-iptwLl46] [-s addr] {IPADDR PORTNUM|-f FILENAME|COMMAND...}",
+            "nc: invalid option -- 'z'",
+        ] {
+            assert!(matches!(classify_nc_failure(message), Reachability::Unknown(_)), "{}", message);
+        }
+    }
+
+    #[test]
+    fn nc_connection_errors_are_unreachable() {
+        assert_eq!(
+            classify_nc_failure(" nc: connect: Connection refused"),
+            Reachability::Unreachable("nc: connect: Connection refused".to_string())
+        );
+        assert_eq!(
+            classify_nc_failure(""),
+            Reachability::Unreachable("connection refused or timed out".to_string())
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Detecting device reboots and checking the proxy survived them
+
+use std::collections::BTreeMap;
+use clap::ValueEnum;
+use colored::*;
+use serde::{Deserialize, Serialize};
+use crate::adb::commands::{AdbCommand, execute_device_command_string};
+use crate::config::paths::{data_file, read_state_file, write_state_file};
+use crate::error::{AppError, AppResult};
+use crate::config::time::unix_now;
+use crate::proxy::manager::{clear_proxy, read_proxy_state, reapply_proxy, ProxyOptions};
+use crate::proxy::state::ProxyState;
+
+/// File name of the boot record store inside the state directory
+const BOOTS_FILE: &str = "boots.json";
+
+/// What to do when a rebooted device no longer has the intended proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RebootPolicy {
+    /// Set the intended proxy again
+    Reapply,
+    /// Clear whatever proxy the device came back with
+    Clear,
+    /// Only print the difference
+    Report,
+}
+
+/// The boot of a device during which the tool last changed its proxy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootRecord {
+    /// `/proc/sys/kernel/random/boot_id`, new on every boot
+    pub boot_id: String,
+    /// `ro.boot.bootreason`, empty when the ROM does not set it
+    pub boot_reason: String,
+    /// Raw `http_proxy` value the tool left on the device
+    pub proxy: String,
+    /// Seconds since the Unix epoch
+    pub recorded_at: u64,
+}
+
+/// Boot records by device serial
+pub type BootRecords = BTreeMap<String, BootRecord>;
+
+/// Remember the current boot of the device together with the proxy just applied
+pub fn record_boot(serial: &str, state: &ProxyState) -> AppResult<()> {
+    let record = BootRecord {
+        boot_id: read_boot_id(serial)?,
+        boot_reason: read_boot_reason(serial),
+        proxy: state.to_setting_value(),
+        recorded_at: unix_now(),
+    };
+
+    let mut records = load_records()?;
+    records.insert(serial.to_string(), record);
+    store(&records)
+}
+
+/// Check whether the device rebooted since the proxy was last changed and apply the policy
+pub fn check_reboot(serial: &str, policy: RebootPolicy, options: &ProxyOptions) -> AppResult<()> {
+    let mut records = load_records()?;
+    let record = match records.get(serial) {
+        Some(record) => record.clone(),
+        None => return Ok(()),
+    };
+
+    let boot_id = read_boot_id(serial)?;
+    if boot_id == record.boot_id {
+        return Ok(());
+    }
+
+    let boot_reason = read_boot_reason(serial);
+    println!(
+        "{}",
+        format!(
+            "{} rebooted since its proxy was last changed (boot reason: {})",
+            serial,
+            if boot_reason.is_empty() { "unknown" } else { &boot_reason }
+        )
+        .yellow()
+    );
+
+    let intended = ProxyState::parse(&record.proxy);
+    let current = read_proxy_state(serial)?;
+    if current == intended {
+        println!("Proxy {} survived the reboot", current.to_string().green());
+        return update_boot(&mut records, serial, boot_id, boot_reason);
+    }
+
+    println!(
+        "Proxy is {}, expected {}",
+        current.to_string().red(),
+        intended.to_string().green()
+    );
+    match policy {
+        // Both record the new boot along with the proxy change. A lease that
+        // survived the reboot still ends the re-applied proxy.
+        RebootPolicy::Reapply => {
+            let options = ProxyOptions {
+                keep_lease: true,
+                ..options.clone()
+            };
+            reapply_proxy(serial, &intended, &options)
+        }
+        RebootPolicy::Clear if current.is_set() => clear_proxy(serial, options),
+        _ => {
+            println!("Leaving it as is (--on-reboot {})", policy_name(policy));
+            update_boot(&mut records, serial, boot_id, boot_reason)
+        }
+    }
+}
+
+// Internal helper functions
+
+fn update_boot(records: &mut BootRecords, serial: &str, boot_id: String, boot_reason: String) -> AppResult<()> {
+    if let Some(record) = records.get_mut(serial) {
+        record.boot_id = boot_id;
+        record.boot_reason = boot_reason;
+    }
+    store(records)
+}
+
+fn policy_name(policy: RebootPolicy) -> String {
+    policy
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+fn read_boot_id(serial: &str) -> AppResult<String> {
+    let boot_id = execute_device_command_string(
+        serial,
+        AdbCommand::Shell("cat /proc/sys/kernel/random/boot_id".to_string()),
+    )?;
+    if boot_id.is_empty() {
+        return Err(AppError::BootIdUnavailable {
+            serial: serial.to_string(),
+        });
+    }
+    Ok(boot_id)
+}
+
+fn read_boot_reason(serial: &str) -> String {
+    execute_device_command_string(serial, AdbCommand::GetProp("ro.boot.bootreason".to_string()))
+        .unwrap_or_default()
+}
+
+fn load_records() -> AppResult<BootRecords> {
+    let path = data_file(BOOTS_FILE)?;
+    match read_state_file(&path)? {
+        Some(content) => serde_json::from_str(&content).map_err(|e| AppError::state_file_error(&path, e)),
+        None => Ok(BootRecords::new()),
+    }
+}
+
+fn store(records: &BootRecords) -> AppResult<()> {
+    let path = data_file(BOOTS_FILE)?;
+    let content = serde_json::to_string_pretty(records).map_err(|e| AppError::state_file_error(&path, e))?;
+    write_state_file(&path, &content)
+}
@@ -1,34 +1,274 @@
 //! Proxy settings and state management
 
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::str::FromStr;
 use local_ip_address::local_ip;
 use crate::error::{AppError, AppResult};
 
+/// Port used when none is given
+pub const DEFAULT_PORT: u16 = 8083;
+
+/// Proxy host used in USB mode, reaching this machine through `adb reverse`
+pub const LOOPBACK_HOST: &str = "127.0.0.1";
+
 /// Proxy settings configuration
 #[derive(Debug, Clone)]
 pub struct ProxySettings {
+    /// IP address or hostname of the proxy
     pub ip: String,
     pub port: u16,
 }
 
 impl ProxySettings {
     /// Create new proxy settings with automatic IP detection
+    ///
+    /// A custom address may carry its own port (`host:port`, `[v6]:port`),
+    /// which takes precedence over `port`.
     pub fn new(port: u16, custom_ip: Option<String>) -> AppResult<Self> {
-        let ip = match custom_ip {
-            Some(ip) => ip,
+        let port = validate_port(port)?;
+
+        let (ip, port) = match custom_ip {
+            Some(address) => {
+                let (host, explicit_port) = parse_address(&address)?;
+                (host, explicit_port.unwrap_or(port))
+            }
             None => {
                 let local_ip = local_ip()
                     .map_err(|e| AppError::LocalIpError { reason: e.to_string() })?;
-                local_ip.to_string()
+                (local_ip.to_string(), port)
             }
         };
 
         Ok(Self { ip, port })
     }
 
-    /// Get the proxy string in format "ip:port"
+    /// Get the proxy string in format "ip:port", bracketing IPv6 addresses
     pub fn to_proxy_string(&self) -> String {
-        format!("{}:{}", self.ip, self.port)
+        if self.ip.contains(':') {
+            format!("[{}]:{}", self.ip, self.port)
+        } else {
+            format!("{}:{}", self.ip, self.port)
+        }
+    }
+
+    /// Settings for USB mode, where the device reaches this machine through `adb reverse`
+    pub fn usb(port: u16) -> AppResult<Self> {
+        Ok(Self {
+            ip: LOOPBACK_HOST.to_string(),
+            port: validate_port(port)?,
+        })
+    }
+
+    /// Whether the proxy points at the device itself, which only works through `adb reverse`
+    pub fn is_loopback(&self) -> bool {
+        is_loopback_host(&self.ip)
+    }
+
+    /// Check that a hostname resolves, returning the resolved addresses
+    ///
+    /// IP literals are returned as-is without a lookup.
+    pub fn resolve(&self) -> AppResult<Vec<IpAddr>> {
+        if let Ok(ip) = self.ip.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+
+        let addresses: Vec<IpAddr> = (self.ip.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| AppError::HostResolutionFailed {
+                host: self.ip.clone(),
+                reason: e.to_string(),
+            })?
+            .map(|addr| addr.ip())
+            .collect();
+
+        if addresses.is_empty() {
+            return Err(AppError::HostResolutionFailed {
+                host: self.ip.clone(),
+                reason: "no addresses returned".to_string(),
+            });
+        }
+
+        Ok(addresses)
+    }
+}
+
+impl FromStr for ProxySettings {
+    type Err = AppError;
+
+    /// Parse `host:port`, `[v6]:port` or a bare host, which uses [`DEFAULT_PORT`]
+    fn from_str(s: &str) -> AppResult<Self> {
+        let (ip, port) = parse_address(s)?;
+        Ok(Self {
+            ip,
+            port: port.unwrap_or(DEFAULT_PORT),
+        })
+    }
+}
+
+/// Validate a proxy port, rejecting 0
+pub fn validate_port(port: u16) -> AppResult<u16> {
+    if port == 0 {
+        return Err(AppError::InvalidPort {
+            input: port.to_string(),
+        });
+    }
+    Ok(port)
+}
+
+/// Validate an IPv4 address, IPv6 address or hostname, returning its normalized form
+pub fn validate_host(host: &str) -> AppResult<String> {
+    let invalid = |reason: &str| AppError::InvalidHost {
+        host: host.to_string(),
+        reason: reason.to_string(),
+    };
+
+    if host.is_empty() {
+        return Err(invalid("host is empty"));
+    }
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Ok(ip.to_string());
+    }
+    if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        return Ok(ip.to_string());
+    }
+    if host.contains(':') {
+        return Err(invalid("not a valid IPv6 address"));
+    }
+    if host.len() > 253 {
+        return Err(invalid("hostname longer than 253 characters"));
+    }
+
+    let labels: Vec<&str> = host.trim_end_matches('.').split('.').collect();
+    if labels.iter().all(|label| label.chars().all(|c| c.is_ascii_digit())) {
+        return Err(invalid("not a valid IPv4 address"));
+    }
+    for label in labels {
+        if label.is_empty() || label.len() > 63 {
+            return Err(invalid("hostname labels must be 1-63 characters"));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(invalid("hostname labels cannot start or end with '-'"));
+        }
+        if !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid("hostnames may only contain letters, digits, '-' and '_'"));
+        }
     }
 
+    Ok(host.trim_end_matches('.').to_ascii_lowercase())
 }
 
+/// Whether a proxy host refers to the loopback interface
+pub fn is_loopback_host(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false)
+}
+
+// Internal helper functions
+
+fn parse_address(input: &str) -> AppResult<(String, Option<u16>)> {
+    let input = input.trim();
+    let invalid = |reason: &str| AppError::InvalidProxyAddress {
+        input: input.to_string(),
+        reason: reason.to_string(),
+    };
+
+    // Bracketed IPv6, with or without a port
+    if let Some(rest) = input.strip_prefix('[') {
+        let (host, tail) = rest
+            .split_once(']')
+            .ok_or_else(|| invalid("missing closing ']'"))?;
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(invalid("brackets must contain an IPv6 address"));
+        }
+        let port = match tail {
+            "" => None,
+            _ => Some(parse_port(
+                tail.strip_prefix(':').ok_or_else(|| invalid("expected ':' after ']'"))?,
+            )?),
+        };
+        return Ok((validate_host(host)?, port));
+    }
+
+    // A bare IPv6 address has several colons and no port
+    if input.matches(':').count() > 1 {
+        return Ok((validate_host(input)?, None));
+    }
+
+    match input.split_once(':') {
+        Some((host, port)) => Ok((validate_host(host)?, Some(parse_port(port)?))),
+        None => Ok((validate_host(input)?, None)),
+    }
+}
+
+fn parse_port(input: &str) -> AppResult<u16> {
+    let port = input.parse::<u16>().map_err(|_| AppError::InvalidPort {
+        input: input.to_string(),
+    })?;
+    validate_port(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> AppResult<(String, u16)> {
+        input.parse::<ProxySettings>().map(|settings| (settings.ip, settings.port))
+    }
+
+    #[test]
+    fn addresses_with_and_without_ports() {
+        let cases = [
+            ("192.168.1.20:8888", ("192.168.1.20", 8888)),
+            ("192.168.1.20", ("192.168.1.20", DEFAULT_PORT)),
+            (" proxy.corp.example:3128 ", ("proxy.corp.example", 3128)),
+            ("Build_Box.local", ("build_box.local", DEFAULT_PORT)),
+            ("[::1]:8080", ("::1", 8080)),
+            ("[::1]", ("::1", DEFAULT_PORT)),
+            ("::1", ("::1", DEFAULT_PORT)),
+            ("fe80:0:0:0:0:0:0:1", ("fe80::1", DEFAULT_PORT)),
+            ("localhost:65535", ("localhost", 65535)),
+        ];
+        for (input, (ip, port)) in cases {
+            assert_eq!(parse(input).unwrap(), (ip.to_string(), port), "{}", input);
+        }
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        let cases = [
+            ("192.168.1.20:0", "invalid proxy port"),
+            ("192.168.1.20:65536", "invalid proxy port"),
+            ("192.168.1.20:", "invalid proxy port"),
+            ("host:port", "invalid proxy port"),
+            ("", "host is empty"),
+            (":8080", "host is empty"),
+            ("[::1", "missing closing ']'"),
+            ("[::1]8080", "expected ':' after ']'"),
+            ("[192.168.1.20]:8080", "brackets must contain an IPv6 address"),
+            ("1:2:3", "not a valid IPv6 address"),
+            ("256.1.1.1", "not a valid IPv4 address"),
+            ("-proxy.example", "cannot start or end with '-'"),
+            ("proxy-.example", "cannot start or end with '-'"),
+            ("proxy..example", "1-63 characters"),
+            ("prox y.example", "may only contain"),
+        ];
+        for (input, reason) in cases {
+            let error = parse(input).unwrap_err().to_string();
+            assert!(error.to_lowercase().contains(&reason.to_lowercase()), "{}: {}", input, error);
+        }
+        assert!(validate_host(&format!("{}a", "a.".repeat(126))).is_ok());
+        assert!(validate_host(&format!("{}aa", "a.".repeat(126))).is_err());
+        assert!(validate_host(&format!("{}.example", "a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn custom_addresses_override_the_port() {
+        let settings = ProxySettings::new(8083, Some("10.0.2.2:9000".to_string())).unwrap();
+        assert_eq!((settings.ip.as_str(), settings.port), ("10.0.2.2", 9000));
+        let settings = ProxySettings::new(8083, Some("::1".to_string())).unwrap();
+        assert_eq!(settings.to_proxy_string(), "[::1]:8083");
+        assert!(settings.is_loopback());
+        assert!(ProxySettings::new(0, Some("10.0.2.2".to_string())).is_err());
+        assert!(ProxySettings::usb(0).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+//! Typed view of the proxy value stored on the device
+
+use std::fmt;
+use std::net::Ipv6Addr;
+use crate::proxy::settings::{is_loopback_host, validate_host};
+
+/// Proxy state as read from the device's global settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyState {
+    /// No proxy configured (`null`, empty or `:0`)
+    Unset,
+    /// Plain HTTP proxy at `host:port`
+    HostPort { host: String, port: u16 },
+    /// Proxy auto-config URL
+    Pac(String),
+    /// A value that could not be interpreted
+    Malformed(String),
+}
+
+impl ProxyState {
+    /// Parse a raw value from `settings get global http_proxy` or `global_proxy_pac_url`
+    pub fn parse(raw: &str) -> Self {
+        let value = raw.trim();
+
+        if value.is_empty() || value == "null" || value == ":0" {
+            return ProxyState::Unset;
+        }
+
+        if value.contains("://") {
+            return ProxyState::Pac(value.to_string());
+        }
+
+        match split_host_port(value) {
+            Some((_, 0)) => ProxyState::Malformed(value.to_string()),
+            Some((host, port)) => ProxyState::HostPort { host, port },
+            None => ProxyState::Malformed(value.to_string()),
+        }
+    }
+
+    /// Whether a proxy of any kind is configured
+    pub fn is_set(&self) -> bool {
+        !matches!(self, ProxyState::Unset)
+    }
+
+    /// Port of a proxy on the device's loopback interface, as set up in USB mode
+    pub fn loopback_port(&self) -> Option<u16> {
+        match self {
+            ProxyState::HostPort { host, port } if is_loopback_host(host) => Some(*port),
+            _ => None,
+        }
+    }
+
+    /// Value in the form stored in `http_proxy`, as recorded in the history
+    pub fn to_setting_value(&self) -> String {
+        match self {
+            ProxyState::Unset => ":0".to_string(),
+            ProxyState::HostPort { .. } => self.to_string(),
+            ProxyState::Pac(url) => url.clone(),
+            ProxyState::Malformed(raw) => raw.clone(),
+        }
+    }
+}
+
+impl fmt::Display for ProxyState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyState::Unset => write!(f, "Not set"),
+            ProxyState::HostPort { host, port } if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            ProxyState::HostPort { host, port } => write!(f, "{}:{}", host, port),
+            ProxyState::Pac(url) => write!(f, "PAC {}", url),
+            ProxyState::Malformed(raw) => write!(f, "{} (unrecognized)", raw),
+        }
+    }
+}
+
+// Internal helper functions
+
+fn split_host_port(value: &str) -> Option<(String, u16)> {
+    // Bracketed IPv6: [addr]:port
+    if let Some(rest) = value.strip_prefix('[') {
+        let (host, port) = rest.split_once("]:")?;
+        let addr: Ipv6Addr = host.parse().ok()?;
+        return Some((addr.to_string(), port.parse().ok()?));
+    }
+
+    // Unbracketed IPv6 is ambiguous, `::1:8080` is itself an address
+    let (host, port) = value.split_once(':').filter(|(_, port)| !port.contains(':'))?;
+    let port = port.trim().parse().ok()?;
+    validate_host(host.trim()).ok().map(|host| (host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_port(host: &str, port: u16) -> ProxyState {
+        ProxyState::HostPort {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn unset_values() {
+        for raw in ["", "   ", "\n", "null", "null\r\n", ":0", " :0 "] {
+            assert_eq!(ProxyState::parse(raw), ProxyState::Unset, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn host_and_port_values() {
+        let cases = [
+            ("192.168.1.20:8888", host_port("192.168.1.20", 8888)),
+            ("  10.0.2.2:8080\r\n", host_port("10.0.2.2", 8080)),
+            ("127.0.0.1:8083", host_port("127.0.0.1", 8083)),
+            ("proxy.corp.example:3128", host_port("proxy.corp.example", 3128)),
+            ("localhost:8080", host_port("localhost", 8080)),
+            ("My_Proxy.local.:8080", host_port("my_proxy.local", 8080)),
+            ("[::1]:8080", host_port("::1", 8080)),
+            ("[fe80:0:0:0:0:0:0:1]:3128", host_port("fe80::1", 3128)),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(ProxyState::parse(raw), expected, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn pac_urls() {
+        for raw in ["http://wpad.example/proxy.pac", " https://corp.example/pac?user=1 \n", "file:///sdcard/proxy.pac"] {
+            assert_eq!(ProxyState::parse(raw), ProxyState::Pac(raw.trim().to_string()), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn malformed_values() {
+        for raw in [
+            "192.168.1.20",
+            "192.168.1.20:",
+            "192.168.1.20:0",
+            "192.168.1.20:65536",
+            "192.168.1.20:port",
+            ":8080",
+            "::1:8080",
+            "[::1]",
+            "[::1]8080",
+            "[not-ipv6]:8080",
+            "bad host:8080",
+            "-leading.example:8080",
+        ] {
+            assert_eq!(ProxyState::parse(raw), ProxyState::Malformed(raw.to_string()), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn values_round_trip_through_the_setting() {
+        for raw in ["192.168.1.20:8888", "[::1]:8080", "http://wpad.example/proxy.pac", ":0"] {
+            let state = ProxyState::parse(raw);
+            assert_eq!(ProxyState::parse(&state.to_setting_value()), state, "{:?}", raw);
+        }
+        assert_eq!(host_port("::1", 8080).to_string(), "[::1]:8080");
+        assert_eq!(host_port("127.0.0.1", 8083).loopback_port(), Some(8083));
+        assert_eq!(host_port("192.168.1.20", 8083).loopback_port(), None);
+    }
+}
@@ -0,0 +1,88 @@
+//! Polling the device until a proxy change is visible
+
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::error::{AppError, AppResult};
+use crate::proxy::manager::read_proxy_state;
+use crate::proxy::state::ProxyState;
+
+/// First delay between two reads
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Upper bound for the delay between two reads
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Poll the device until its proxy state equals `expected`
+///
+/// Reads are retried with exponential backoff until `deadline` has elapsed.
+/// Returns the last state read, or an error when it never converged.
+pub fn wait_for_state(serial: &str, expected: &ProxyState, deadline: Duration) -> AppResult<ProxyState> {
+    let started = Instant::now();
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let current = read_proxy_state(serial)?;
+        if &current == expected {
+            return Ok(current);
+        }
+
+        let elapsed = started.elapsed();
+        if elapsed >= deadline {
+            return Err(AppError::ProxyVerifyFailed {
+                expected: expected.to_string(),
+                actual: current.to_string(),
+                waited_ms: elapsed.as_millis() as u64,
+            });
+        }
+
+        thread::sleep(backoff.min(deadline - elapsed));
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::adb::fake::FakeAdb;
+
+    #[test]
+    fn waits_for_a_delayed_change() {
+        let adb = FakeAdb::install("verify-delayed");
+        adb.set("http_proxy", ":0");
+        let setting = adb.path("http_proxy");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(400));
+            fs::write(setting, "10.0.2.2:8083").unwrap();
+        });
+
+        let expected = ProxyState::parse("10.0.2.2:8083");
+        let started = Instant::now();
+        let current = wait_for_state("emulator-5554", &expected, Duration::from_secs(5)).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(current, expected);
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(400) && waited < Duration::from_secs(3), "{:?}", waited);
+    }
+
+    #[test]
+    fn gives_up_at_the_deadline() {
+        let adb = FakeAdb::install("verify-timeout");
+        adb.set("http_proxy", "192.168.1.20:8888");
+
+        let started = Instant::now();
+        let error = wait_for_state("emulator-5554", &ProxyState::Unset, Duration::from_millis(700)).unwrap_err();
+        let waited = started.elapsed();
+
+        match error {
+            AppError::ProxyVerifyFailed { expected, actual, waited_ms } => {
+                assert_eq!(expected, "Not set");
+                assert_eq!(actual, "192.168.1.20:8888");
+                assert!(waited_ms >= 700, "{}", waited_ms);
+            }
+            other => panic!("unexpected error {}", other),
+        }
+        assert!(waited < Duration::from_secs(2), "{:?}", waited);
+    }
+}
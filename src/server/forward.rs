@@ -0,0 +1,1060 @@
+//! HTTP forward proxy handling absolute-URI requests and CONNECT tunnels
+//!
+//! CONNECT tunnels carrying TLS can optionally be intercepted, see
+//! [`crate::server::intercept`]. Requests the proxy can see are subject to
+//! the rules of [`crate::server::rules`], and all traffic to the network
+//! conditions of [`crate::server::shaping`].
+
+use std::cell::Cell;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use colored::*;
+use crate::error::{AppError, AppResult};
+use crate::server::har::{self, HarRecorder};
+use crate::server::intercept::Interceptor;
+use crate::server::http::{
+    copy_body, decode_content, read_request_head, read_response_head, reason_phrase, BodyCapture, BodyFraming, Headers, RequestHead, ResponseHead,
+    MAX_REWRITTEN_BODY,
+};
+use crate::server::rules::{apply_header_changes, Fault, MapLocal, Plan, Replacement, RulesFile};
+use crate::server::shaping::{Failure, Random, Shaper, Throttled};
+use crate::server::tunnel::tunnel;
+use crate::server::upstream::UpstreamProxy;
+
+/// How long to wait for the destination server to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reply to a CONNECT request once the tunnel is usable
+const CONNECTION_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+
+/// First byte of a TLS handshake record
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// How long a timeout fault holds a request, longer than clients usually wait
+const FAULT_TIMEOUT_HOLD: Duration = Duration::from_secs(120);
+
+/// What the proxy does besides forwarding
+#[derive(Default)]
+pub struct ProxyContext {
+    /// Archive receiving every exchange
+    pub har: Option<HarRecorder>,
+    /// Decrypts HTTPS in CONNECT tunnels when set
+    pub interceptor: Option<Interceptor>,
+    /// Proxy every connection goes through when set
+    pub upstream: Option<UpstreamProxy>,
+    /// Map-local, rewrite and fault rules applied to each request
+    pub rules: Option<RulesFile>,
+    /// Simulated network conditions when set
+    pub shaper: Option<Shaper>,
+    /// Random decisions of the shaper and of faults with a probability
+    pub random: Random,
+}
+
+/// A listening forward proxy
+pub struct ForwardProxy {
+    listener: TcpListener,
+    context: Arc<ProxyContext>,
+}
+
+impl ForwardProxy {
+    /// Listen on `bind` (an address such as 0.0.0.0) and `port`
+    pub fn bind(bind: &str, port: u16, context: ProxyContext) -> AppResult<Self> {
+        let listener = TcpListener::bind((bind, port))
+            .map_err(|e| AppError::proxy_server(format!("cannot listen on {} port {}: {}", bind, port, e)))?;
+        Ok(Self {
+            listener,
+            context: Arc::new(context),
+        })
+    }
+
+    /// Address the proxy is listening on
+    pub fn local_addr(&self) -> AppResult<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accept connections in a background thread, one thread per client
+    pub fn spawn(self) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for client in self.listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        println!("{} {}", "⚠️ Failed to accept connection:".yellow(), e);
+                        continue;
+                    }
+                };
+                let context = Arc::clone(&self.context);
+                thread::spawn(move || {
+                    let peer = client
+                        .peer_addr()
+                        .map(|peer| peer.to_string())
+                        .unwrap_or_else(|_| "unknown client".to_string());
+                    if let Err(e) = handle_client(client, &context) {
+                        println!("{} {}: {}", "⚠️ Connection from".yellow(), peer, e);
+                    }
+                });
+            }
+        })
+    }
+}
+
+// Internal helper functions
+
+/// Anything a request can be forwarded over, plain TCP or TLS
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// Where a request is forwarded to
+struct Target {
+    host: String,
+    port: u16,
+    /// Origin-form path sent to the server
+    path: String,
+    /// Name verified in the server certificate, set for intercepted HTTPS
+    tls_name: Option<String>,
+    /// URL shown in the log and the archive
+    url: String,
+}
+
+impl Target {
+    /// Target of an absolute-URI request, `None` when it is not `http://...`
+    fn from_absolute_uri(uri: &str) -> Option<Self> {
+        let (host, port, path) = parse_absolute_uri(uri)?;
+        Some(Self {
+            host,
+            port,
+            path,
+            tls_name: None,
+            url: uri.to_string(),
+        })
+    }
+
+    /// Target of an origin-form request read from an intercepted tunnel
+    fn https(host: &str, port: u16, tls_name: &str, path: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            tls_name: Some(tls_name.to_string()),
+            url: format!("https://{}{}", authority(tls_name, port, 443), path),
+        }
+    }
+
+    /// Target after the host and path rewrites of `plan`, `None` when it has none
+    fn rewrite(&self, plan: &Plan) -> Option<Self> {
+        if plan.host.is_none() && plan.path.is_empty() {
+            return None;
+        }
+
+        let (host, port) = match &plan.host {
+            Some((host, port)) => (host.clone(), port.unwrap_or(self.port)),
+            None => (self.host.clone(), self.port),
+        };
+        let mut path = plan.rewrite_path(&self.path);
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        // A new host of intercepted HTTPS must present a certificate for its own name
+        let tls_name = match (&self.tls_name, &plan.host) {
+            (Some(_), Some(_)) => Some(host.clone()),
+            (tls_name, _) => tls_name.clone(),
+        };
+        let url = match &tls_name {
+            Some(name) => format!("https://{}{}", authority(name, port, 443), path),
+            None => format!("http://{}{}", authority(&host, port, 80), path),
+        };
+
+        Some(Self {
+            host,
+            port,
+            path,
+            tls_name,
+            url,
+        })
+    }
+
+    /// Value of the Host header when the client sent none
+    fn host_header(&self) -> String {
+        match &self.tls_name {
+            Some(name) => authority(name, self.port, 443),
+            None => authority(&self.host, self.port, 80),
+        }
+    }
+}
+
+/// The TCP connection of a client, kept to break it on purpose
+struct ClientSocket {
+    socket: TcpStream,
+    broken: Cell<bool>,
+}
+
+impl ClientSocket {
+    fn new(client: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            socket: client.try_clone()?,
+            broken: Cell::new(false),
+        })
+    }
+
+    /// Have the connection end with `failure` once it is dropped, without a TLS close_notify
+    fn break_with(&self, failure: Failure) -> io::Result<()> {
+        if failure == Failure::Reset {
+            // Closing with a zero linger time sends RST instead of FIN
+            socket2::SockRef::from(&self.socket).set_linger(Some(Duration::ZERO))?;
+        }
+        self.broken.set(true);
+        Ok(())
+    }
+
+    fn is_broken(&self) -> bool {
+        self.broken.get()
+    }
+}
+
+/// An open connection to the destination server
+struct Upstream {
+    stream: Box<dyn Stream>,
+    server_ip: Option<String>,
+    /// Whether this is a connection to the upstream proxy taking absolute-form requests
+    via_proxy: bool,
+    /// Duration of the TLS handshake, part of the connect time
+    handshake: Option<Duration>,
+}
+
+/// Serve the requests of one client connection
+fn handle_client(client: TcpStream, context: &ProxyContext) -> io::Result<()> {
+    let socket = ClientSocket::new(&client)?;
+    let mut client = BufReader::new(client);
+    if let Some(shaper) = &context.shaper {
+        shaper.delay();
+    }
+
+    loop {
+        let request = match read_request_head(&mut client)? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+
+        if request.method.eq_ignore_ascii_case("CONNECT") {
+            return connect_tunnel(&request, client, &socket, context);
+        }
+
+        let target = match Target::from_absolute_uri(&request.target) {
+            Some(target) => target,
+            None => {
+                return respond_error(client.get_mut(), 400, "Bad Request", "this is a proxy, requests need an absolute http:// URI");
+            }
+        };
+        if !forward_request(&request, &target, &mut client, &socket, context)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Handle a CONNECT request
+///
+/// With interception on, TLS inside the tunnel is terminated and every
+/// request is forwarded and recorded on its own. Otherwise the tunnel is
+/// opaque and only its metadata is recorded.
+fn connect_tunnel(
+    request: &RequestHead,
+    mut client: BufReader<TcpStream>,
+    socket: &ClientSocket,
+    context: &ProxyContext,
+) -> io::Result<()> {
+    let (host, port) = match split_authority(&request.target, 443) {
+        Some(authority) => authority,
+        None => return respond_error(client.get_mut(), 400, "Bad Request", "invalid CONNECT target"),
+    };
+    if let Some(shaper) = &context.shaper {
+        if let Some(failure) = shaper.failure(&mut context.random.stream(&format!("CONNECT {}", request.target))) {
+            let note = failure_note(shaper, failure);
+            println!("{} {} {}", "CONNECT".blue(), request.target, note.red());
+            record_failure(context, SystemTime::now(), request, &format!("https://{}", request.target), Duration::ZERO, &io::Error::other(note));
+            return socket.break_with(failure);
+        }
+        shaper.delay();
+    }
+
+    let established = match &context.interceptor {
+        Some(interceptor) => {
+            // The client only starts its handshake once the tunnel is up
+            client.get_mut().write_all(CONNECTION_ESTABLISHED)?;
+            if client.fill_buf()?.first() == Some(&TLS_HANDSHAKE) {
+                return intercept_tunnel(interceptor, request, (&host, port), client, socket, context);
+            }
+            true
+        }
+        None => false,
+    };
+
+    let started = SystemTime::now();
+    let url = format!("https://{}", request.target);
+    let connecting = Instant::now();
+    let opened = match &context.upstream {
+        Some(proxy) => proxy.open_tunnel(&host, port),
+        None => connect(&host, port),
+    };
+    let upstream = match opened {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            println!("{} {} {}", "CONNECT".blue(), request.target, e.to_string().red());
+            record_failure(context, started, request, &url, connecting.elapsed(), &e);
+            if established {
+                return Ok(());
+            }
+            return respond_error(client.get_mut(), 502, "Bad Gateway", &e.to_string());
+        }
+    };
+    let connect_time = connecting.elapsed();
+    let server_ip = upstream.peer_addr().ok().map(|address| address.ip().to_string());
+
+    if !established {
+        client.get_mut().write_all(CONNECTION_ESTABLISHED)?;
+    }
+    println!("{} {}", "CONNECT".blue(), request.target);
+
+    let relaying = Instant::now();
+    let pending = client.buffer().to_vec();
+    let (up_rate, down_rate) = rates(context);
+    let (sent, received) = tunnel(client.into_inner(), upstream, &pending, up_rate, down_rate)?;
+
+    if let Some(recorder) = &context.har {
+        let timings = har::Timings::new(connect_time, Duration::ZERO, Duration::ZERO, relaying.elapsed());
+        let entry = har::Entry::new(
+            started,
+            har::Request::new("CONNECT", &url, &request.version, &request.headers, sent, &BodyCapture::default()),
+            har::Response::new(200, "Connection Established", "HTTP/1.1", &Headers::default(), received, &BodyCapture::default()),
+            timings,
+        )
+        .server_ip(server_ip)
+        .comment("CONNECT tunnel, contents not intercepted");
+        record(recorder, entry);
+    }
+
+    Ok(())
+}
+
+/// Terminate TLS in a tunnel to `host` and forward the requests inside it
+fn intercept_tunnel(
+    interceptor: &Interceptor,
+    request: &RequestHead,
+    (host, port): (&str, u16),
+    client: BufReader<TcpStream>,
+    socket: &ClientSocket,
+    context: &ProxyContext,
+) -> io::Result<()> {
+    let pending = client.buffer().to_vec();
+    let (tls_name, client) = match interceptor.accept(host, client.into_inner(), &pending) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            // Usually an app that does not trust the CA or pins its certificates
+            println!("{} {} {} {}", "CONNECT".blue(), request.target, "TLS handshake failed:".red(), e);
+            return Ok(());
+        }
+    };
+    println!("{} {} {}", "CONNECT".blue(), request.target, "(intercepted)".cyan());
+
+    let mut client = BufReader::new(client);
+    while let Some(request) = read_request_head(&mut client)? {
+        let target = Target::https(host, port, &tls_name, &request.target);
+        if !forward_request(&request, &target, &mut client, socket, context)? {
+            break;
+        }
+    }
+
+    if socket.is_broken() {
+        return Ok(());
+    }
+    client.get_mut().close()
+}
+
+/// Forward one request, returning whether the client connection stays open
+fn forward_request<S: Read + Write>(
+    request: &RequestHead,
+    target: &Target,
+    client: &mut BufReader<S>,
+    socket: &ClientSocket,
+    context: &ProxyContext,
+) -> io::Result<bool> {
+    let started = SystemTime::now();
+
+    // After a 101 the connection would carry another protocol, which the proxy cannot frame
+    if let Some(protocol) = request.headers.get("upgrade") {
+        let message = format!("upgrading to {} is not supported by this proxy, use a CONNECT tunnel (wss://) instead", protocol);
+        println!("{} {} {}", request.method.blue(), target.url, "upgrade refused (501)".red());
+        copy_body(client, &mut io::sink(), request.framing(), &mut BodyCapture::default())?;
+        respond_error(client.get_mut(), 501, "Not Implemented", &message)?;
+        return Ok(false);
+    }
+
+    let plan = match &context.rules {
+        Some(rules) => rules.current().plan(&request.method, &target.url),
+        None => Plan::default(),
+    };
+    let random = &mut context.random.stream(&format!("{} {}", request.method, target.url));
+    let failure = context.shaper.as_ref().and_then(|shaper| shaper.failure(random).map(|failure| (shaper, failure)));
+    if let Some((shaper, failure)) = failure {
+        let note = failure_note(shaper, failure);
+        return fail_request(request, &target.url, client, socket, failure, &note, context);
+    }
+    let fault = plan
+        .faults
+        .iter()
+        .find(|rule| random.chance(rule.probability))
+        .map(|rule| rule.fault);
+    match fault {
+        Some(Fault::Status(status)) => return inject_status(request, target, client, status, context, started),
+        Some(Fault::Timeout) => {
+            println!("{} {} {}", request.method.blue(), target.url, "held (timeout fault)".yellow());
+            thread::sleep(FAULT_TIMEOUT_HOLD);
+            let note = format!("closed after {} s without an answer (timeout fault)", FAULT_TIMEOUT_HOLD.as_secs());
+            return fail_request(request, &target.url, client, socket, Failure::Drop, &note, context);
+        }
+        _ => {}
+    }
+    let truncate = fault == Some(Fault::Truncate);
+
+    if let Some(map_local) = &plan.map_local {
+        return serve_local(request, target, map_local, &plan, client, context, started);
+    }
+    // The log and the archive show the request as the client sent it
+    let rewritten = target.rewrite(&plan);
+    let destination = rewritten.as_ref().unwrap_or(target);
+
+    let connecting = Instant::now();
+    let upstream = match open_upstream(destination, context) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            println!("{} {} {}", request.method.blue(), target.url, e.to_string().red());
+            record_failure(context, started, request, &target.url, connecting.elapsed(), &e);
+            respond_error(client.get_mut(), 502, "Bad Gateway", &e.to_string())?;
+            return Ok(false);
+        }
+    };
+    let connect_time = connecting.elapsed();
+    let mut upstream_reader = BufReader::new(upstream.stream);
+
+    // One request per upstream connection keeps response framing simple
+    let mut headers = request.headers.end_to_end();
+    if headers.get("host").is_none() || rewritten.is_some() {
+        headers.set("Host", &destination.host_header());
+    }
+    apply_header_changes(&mut headers, &plan.request_headers);
+    if !plan.replace_body.is_empty() {
+        // Bodies are rewritten as text, so ask for them uncompressed
+        headers.set("Accept-Encoding", "identity");
+    }
+    headers.set("Connection", "close");
+    let proxy = context.upstream.as_ref().filter(|_| upstream.via_proxy);
+    if let Some(proxy) = proxy {
+        proxy.authorize(&mut headers);
+    }
+    let outgoing = RequestHead {
+        method: request.method.clone(),
+        target: if proxy.is_some() { destination.url.clone() } else { destination.path.clone() },
+        version: "HTTP/1.1".to_string(),
+        headers,
+    };
+
+    let sending = Instant::now();
+    let mut request_body = body_capture(context);
+    let (up_rate, down_rate) = rates(context);
+    let upstream_writer = &mut Throttled::new(upstream_reader.get_mut(), up_rate);
+    outgoing.write_to(upstream_writer)?;
+    let request_size = copy_body(client, upstream_writer, request.framing(), &mut request_body)?;
+    upstream_writer.flush()?;
+    let send_time = sending.elapsed();
+
+    let writer = &mut Throttled::new(client.get_mut(), down_rate);
+    let waiting = Instant::now();
+    let mut response = read_response_head(&mut upstream_reader)?;
+    while response.status < 200 && response.status != 101 {
+        // Interim responses such as 100 Continue are passed through as they are
+        response.write_to(writer)?;
+        response = read_response_head(&mut upstream_reader)?;
+    }
+    if let Some(shaper) = &context.shaper {
+        shaper.delay();
+    }
+    let wait_time = waiting.elapsed();
+    if let Some(proxy) = proxy {
+        proxy.check_response(&response);
+    }
+
+    let framing = response.framing(&request.method);
+    let close = request.wants_close() || framing == BodyFraming::UntilClose;
+
+    let mut outgoing = ResponseHead {
+        headers: response.headers.end_to_end(),
+        ..response.clone()
+    };
+    if close {
+        outgoing.headers.set("Connection", "close");
+    }
+    apply_header_changes(&mut outgoing.headers, &plan.response_headers);
+
+    let receiving = Instant::now();
+    let mut response_body = body_capture(context);
+    let mut truncated = None;
+    let response_size = match framing {
+        _ if (plan.replace_body.is_empty() && !truncate) || framing == BodyFraming::Empty => {
+            outgoing.write_to(writer)?;
+            copy_body(&mut upstream_reader, writer, framing, &mut response_body)?
+        }
+        BodyFraming::Length(length) if plan.replace_body.is_empty() || length > MAX_REWRITTEN_BODY as u64 => {
+            if !plan.replace_body.is_empty() {
+                println!("{} {} is larger than {} bytes, body not rewritten", "⚠️".yellow(), target.url, MAX_REWRITTEN_BODY);
+            }
+            outgoing.write_to(writer)?;
+
+            // A truncated body announces its full length and stops halfway
+            let sent = if truncate { length / 2 } else { length };
+            if truncate {
+                truncated = Some((sent, length));
+            }
+            copy_body(&mut upstream_reader, writer, BodyFraming::Length(sent), &mut response_body)?
+        }
+        _ => {
+            let mut original = BodyCapture::new(MAX_REWRITTEN_BODY);
+            let mut held = HeldBody::new(&outgoing, writer);
+            let size = copy_body(&mut upstream_reader, &mut held, framing, &mut original)?;
+            if held.passed() {
+                println!("{} {} is larger than {} bytes, passed on unchanged", "⚠️".yellow(), target.url, MAX_REWRITTEN_BODY);
+                response_body.record(original.data());
+                size
+            } else {
+                let body = if plan.replace_body.is_empty() {
+                    original.data().to_vec()
+                } else {
+                    rewrite_body(original.data(), &mut outgoing.headers, &plan.replace_body, &target.url)
+                };
+                outgoing.headers.remove("Transfer-Encoding");
+                outgoing.headers.set("Content-Length", &body.len().to_string());
+                outgoing.write_to(writer)?;
+
+                let sent = if truncate { &body[..body.len() / 2] } else { &body[..] };
+                if truncate {
+                    truncated = Some((sent.len() as u64, body.len() as u64));
+                }
+                copy_body(&mut &sent[..], writer, BodyFraming::Length(sent.len() as u64), &mut response_body)?
+            }
+        }
+    };
+    writer.flush()?;
+    let receive_time = receiving.elapsed();
+
+    let rewrite_note = rewritten
+        .as_ref()
+        .map(|destination| format!(" → {}", destination.url).cyan().to_string())
+        .unwrap_or_default();
+    let truncate_note = truncated
+        .map(|(sent, total)| format!(" (truncated after {} of {} bytes)", sent, total).yellow().to_string())
+        .unwrap_or_default();
+    println!(
+        "{} {}{} {}{}",
+        request.method.blue(),
+        target.url,
+        rewrite_note,
+        status_colored(response.status),
+        truncate_note
+    );
+
+    if let Some(recorder) = &context.har {
+        let mut timings = har::Timings::new(connect_time, send_time, wait_time, receive_time);
+        if let Some(handshake) = upstream.handshake {
+            timings = timings.ssl(handshake);
+        }
+        // Rules changing the response are recorded as the client saw it
+        let response_headers = if plan.response_headers.is_empty() && plan.replace_body.is_empty() {
+            &response.headers
+        } else {
+            &outgoing.headers
+        };
+        let mut entry = har::Entry::new(
+            started,
+            har::Request::new(&request.method, &target.url, &request.version, &request.headers, request_size, &request_body),
+            har::Response::new(response.status, &response.reason, &response.version, response_headers, response_size, &response_body),
+            timings,
+        )
+        .server_ip(upstream.server_ip);
+        if let Some(destination) = &rewritten {
+            entry = entry.comment(format!("rewritten to {}", destination.url));
+        }
+        if let Some((sent, total)) = truncated {
+            entry = entry.comment(format!("truncated after {} of {} bytes (fault)", sent, total));
+        }
+        record(recorder, entry);
+    }
+
+    if truncated.is_some() {
+        socket.break_with(Failure::Drop)?;
+        return Ok(false);
+    }
+    Ok(!close)
+}
+
+/// Connect to the server of `target`, over TLS when it is intercepted HTTPS,
+/// and through the upstream proxy when there is one
+fn open_upstream(target: &Target, context: &ProxyContext) -> io::Result<Upstream> {
+    // Plain HTTP goes to an HTTP proxy as it is, HTTPS and anything through SOCKS in a tunnel
+    let (socket, via_proxy) = match (&context.upstream, &target.tls_name) {
+        (Some(proxy), None) if proxy.takes_requests() => (proxy.connect()?, true),
+        (Some(proxy), _) => (proxy.open_tunnel(&target.host, target.port)?, false),
+        (None, _) => (connect(&target.host, target.port)?, false),
+    };
+    let server_ip = socket.peer_addr().ok().map(|address| address.ip().to_string());
+
+    let (stream, handshake): (Box<dyn Stream>, _) = match (&target.tls_name, &context.interceptor) {
+        (Some(tls_name), Some(interceptor)) => {
+            let handshaking = Instant::now();
+            let stream = interceptor.connect(tls_name, socket)?;
+            (Box::new(stream), Some(handshaking.elapsed()))
+        }
+        _ => (Box::new(socket), None),
+    };
+
+    Ok(Upstream {
+        stream,
+        server_ip,
+        via_proxy,
+        handshake,
+    })
+}
+
+/// Answer a request with the file of a map-local rule instead of forwarding it
+fn serve_local<S: Read + Write>(
+    request: &RequestHead,
+    target: &Target,
+    map_local: &MapLocal,
+    plan: &Plan,
+    client: &mut BufReader<S>,
+    context: &ProxyContext,
+    started: SystemTime,
+) -> io::Result<bool> {
+    // The request body is read so the connection can serve the next request
+    let mut request_body = body_capture(context);
+    let request_size = copy_body(client, &mut io::sink(), request.framing(), &mut request_body)?;
+
+    let content = match fs::read(&map_local.file) {
+        Ok(content) => content,
+        Err(e) => {
+            let message = format!("cannot read {}: {}", map_local.file.display(), e);
+            println!("{} {} {}", request.method.blue(), target.url, message.red());
+            record_failure(context, started, request, &target.url, Duration::ZERO, &io::Error::other(message.clone()));
+            respond_error(client.get_mut(), 404, "Not Found", &message)?;
+            return Ok(false);
+        }
+    };
+
+    let mut response = ResponseHead::new(map_local.status, reason_phrase(map_local.status));
+    response.headers.push("Content-Type", &map_local.content_type);
+    response.headers.push("Content-Length", &content.len().to_string());
+    if request.wants_close() {
+        response.headers.push("Connection", "close");
+    }
+    apply_header_changes(&mut response.headers, &plan.response_headers);
+
+    if let Some(shaper) = &context.shaper {
+        shaper.delay();
+    }
+    let writer = &mut Throttled::new(client.get_mut(), rates(context).1);
+    let mut response_body = body_capture(context);
+    response.write_to(writer)?;
+    let framing = response.framing(&request.method);
+    let response_size = copy_body(&mut content.as_slice(), writer, framing, &mut response_body)?;
+    writer.flush()?;
+
+    println!(
+        "{} {} {} {}",
+        request.method.blue(),
+        target.url,
+        status_colored(response.status),
+        format!("(map local {})", map_local.file.display()).cyan()
+    );
+
+    if let Some(recorder) = &context.har {
+        let entry = har::Entry::new(
+            started,
+            har::Request::new(&request.method, &target.url, &request.version, &request.headers, request_size, &request_body),
+            har::Response::new(response.status, &response.reason, &response.version, &response.headers, response_size, &response_body),
+            har::Timings::new(Duration::ZERO, Duration::ZERO, Duration::ZERO, Duration::ZERO),
+        )
+        .comment(format!("served from {}", map_local.file.display()));
+        record(recorder, entry);
+    }
+
+    Ok(!request.wants_close())
+}
+
+/// Answer nothing and break the connection, as a shaping profile or a fault decided
+fn fail_request<S: Read + Write>(
+    request: &RequestHead,
+    url: &str,
+    client: &mut BufReader<S>,
+    socket: &ClientSocket,
+    failure: Failure,
+    note: &str,
+    context: &ProxyContext,
+) -> io::Result<bool> {
+    // Unread request bytes would turn a plain close into a reset
+    copy_body(client, &mut io::sink(), request.framing(), &mut BodyCapture::default())?;
+
+    println!("{} {} {}", request.method.blue(), url, note.red());
+    record_failure(context, SystemTime::now(), request, url, Duration::ZERO, &io::Error::other(note));
+    socket.break_with(failure)?;
+    Ok(false)
+}
+
+/// Answer with an error status from a fault rule, without asking the server
+fn inject_status<S: Read + Write>(
+    request: &RequestHead,
+    target: &Target,
+    client: &mut BufReader<S>,
+    status: u16,
+    context: &ProxyContext,
+    started: SystemTime,
+) -> io::Result<bool> {
+    let mut request_body = body_capture(context);
+    let request_size = copy_body(client, &mut io::sink(), request.framing(), &mut request_body)?;
+
+    let reason = reason_phrase(status);
+    respond_error(client.get_mut(), status, reason, "fault injected by the proxy rules")?;
+    println!(
+        "{} {} {} {}",
+        request.method.blue(),
+        target.url,
+        status_colored(status),
+        "(fault)".yellow()
+    );
+
+    if let Some(recorder) = &context.har {
+        let entry = har::Entry::new(
+            started,
+            har::Request::new(&request.method, &target.url, &request.version, &request.headers, request_size, &request_body),
+            har::Response::new(status, reason, "HTTP/1.1", &Headers::default(), 0, &BodyCapture::default()),
+            har::Timings::new(Duration::ZERO, Duration::ZERO, Duration::ZERO, Duration::ZERO),
+        )
+        .comment(format!("fault injected: {}", status));
+        record(recorder, entry);
+    }
+
+    Ok(false)
+}
+
+/// How a failure of a shaping profile shows in the log
+fn failure_note(shaper: &Shaper, failure: Failure) -> String {
+    let what = match failure {
+        Failure::Drop => "dropped",
+        Failure::Reset => "reset",
+    };
+    format!("{} by the {} profile", what, shaper.name())
+}
+
+/// Throughput limits towards the server and towards the client
+fn rates(context: &ProxyContext) -> (Option<u64>, Option<u64>) {
+    match &context.shaper {
+        Some(shaper) => (Some(shaper.up_rate()), Some(shaper.down_rate())),
+        None => (None, None),
+    }
+}
+
+/// Response body after the body replacements, unchanged when it is not text
+///
+/// A compressed body is decoded first and sent uncompressed, with the
+/// `Content-Encoding` header removed. One in an unsupported encoding is
+/// sent unchanged.
+fn rewrite_body(body: &[u8], headers: &mut Headers, replacements: &[Replacement], url: &str) -> Vec<u8> {
+    let decoded = match headers.get("content-encoding").filter(|encoding| !encoding.eq_ignore_ascii_case("identity")) {
+        None => None,
+        Some(encoding) => match decode_content(body, encoding) {
+            Some(Ok(decoded)) => Some(decoded),
+            Some(Err(e)) => {
+                println!("{} {} could not be decoded ({}), body not rewritten", "⚠️".yellow(), url, e);
+                return body.to_vec();
+            }
+            None => {
+                println!("{} {} is {}-encoded, body not rewritten", "⚠️".yellow(), url, encoding);
+                return body.to_vec();
+            }
+        },
+    };
+
+    match std::str::from_utf8(decoded.as_deref().unwrap_or(body)) {
+        Ok(text) => {
+            if decoded.is_some() {
+                headers.remove("Content-Encoding");
+            }
+            replacements
+                .iter()
+                .fold(text.to_string(), |text, replacement| replacement.apply(&text))
+                .into_bytes()
+        }
+        Err(_) => {
+            println!("{} {} is not UTF-8 text, body not rewritten", "⚠️".yellow(), url);
+            body.to_vec()
+        }
+    }
+}
+
+/// Writer holding a response body back until it is complete, to rewrite it
+///
+/// A body growing past [`MAX_REWRITTEN_BODY`] is passed on unchanged from
+/// then on, head first, so memory stays bounded whatever the server sends.
+struct HeldBody<'a, W: Write> {
+    head: &'a ResponseHead,
+    writer: &'a mut W,
+    held: Vec<u8>,
+    passing: bool,
+}
+
+impl<'a, W: Write> HeldBody<'a, W> {
+    fn new(head: &'a ResponseHead, writer: &'a mut W) -> Self {
+        Self {
+            head,
+            writer,
+            held: Vec::new(),
+            passing: false,
+        }
+    }
+
+    /// Whether the body was too large to hold and went to the client as it came
+    fn passed(&self) -> bool {
+        self.passing
+    }
+}
+
+impl<W: Write> Write for HeldBody<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.passing {
+            if self.held.len() + buf.len() <= MAX_REWRITTEN_BODY {
+                self.held.extend_from_slice(buf);
+                return Ok(buf.len());
+            }
+            self.passing = true;
+            self.head.write_to(self.writer)?;
+            self.writer.write_all(&std::mem::take(&mut self.held))?;
+        }
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.passing {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Capture for a body, keeping nothing when no archive is written
+fn body_capture(context: &ProxyContext) -> BodyCapture {
+    context
+        .har
+        .as_ref()
+        .map(HarRecorder::body_capture)
+        .unwrap_or_default()
+}
+
+/// Record an exchange that failed before a response arrived
+fn record_failure(
+    context: &ProxyContext,
+    started: SystemTime,
+    request: &RequestHead,
+    url: &str,
+    connect_time: Duration,
+    error: &io::Error,
+) {
+    if let Some(recorder) = &context.har {
+        let entry = har::Entry::new(
+            started,
+            har::Request::new(&request.method, url, &request.version, &request.headers, 0, &BodyCapture::default()),
+            har::Response::missing(),
+            har::Timings::new(connect_time, Duration::ZERO, Duration::ZERO, Duration::ZERO),
+        )
+        .comment(error.to_string());
+        record(recorder, entry);
+    }
+}
+
+fn record(recorder: &HarRecorder, entry: har::Entry) {
+    if let Err(e) = recorder.record(entry) {
+        println!("{} {}", "⚠️ Failed to write HAR file:".yellow(), e);
+    }
+}
+
+/// Send a short plain-text response generated by the proxy
+fn respond_error(writer: &mut impl Write, status: u16, reason: &str, message: &str) -> io::Result<()> {
+    let body = format!("{}\n", message);
+    let mut response = ResponseHead::new(status, reason);
+    response.headers.push("Content-Type", "text/plain; charset=utf-8");
+    response.headers.push("Content-Length", &body.len().to_string());
+    response.headers.push("Connection", "close");
+    response.write_to(writer)?;
+    writer.write_all(body.as_bytes())?;
+    writer.flush()
+}
+
+/// Open a TCP connection, trying each resolved address in turn
+pub fn connect(host: &str, port: u16) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", host));
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Split `http://host[:port]/path` into host, port and origin-form path
+fn parse_absolute_uri(target: &str) -> Option<(String, u16, String)> {
+    let rest = target
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &target[7..])?;
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(index) => (&rest[..index], rest[index..].to_string()),
+        None => (rest, "/".to_string()),
+    };
+    let path = if path.starts_with('?') { format!("/{}", path) } else { path };
+    let (host, port) = split_authority(authority, 80)?;
+    Some((host, port, path))
+}
+
+/// Split `host[:port]` or `[v6][:port]`
+pub fn split_authority(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    // Credentials in the URI are not forwarded
+    let authority = authority.rsplit('@').next()?;
+
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, after) = rest.split_once(']')?;
+        (host, after.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port))
+}
+
+/// Format `host:port`, bracketing IPv6 hosts and leaving out `default_port`
+pub fn authority(host: &str, port: u16, default_port: u16) -> String {
+    let host = if host.contains(':') { format!("[{}]", host) } else { host.to_string() };
+    if port == default_port { host } else { format!("{}:{}", host, port) }
+}
+
+fn status_colored(status: u16) -> ColoredString {
+    match status {
+        200..=399 => status.to_string().green(),
+        400..=499 => status.to_string().yellow(),
+        _ => status.to_string().red(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Stand-in origin answering one request with `body` as its Content-Length
+    fn spawn_origin(body: Vec<u8>) -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            read_request_head(&mut reader).unwrap();
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+            let stream = reader.get_mut();
+            // The proxy may hang up before the whole body is sent
+            let _ = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(&body));
+        });
+        port
+    }
+
+    #[test]
+    fn truncated_bodies_stop_halfway() {
+        let rules = std::env::temp_dir().join(format!("aps-truncate-{}.toml", std::process::id()));
+        fs::write(&rules, "[[rule]]\nurl = \"*\"\nfault = \"truncate\"\n").unwrap();
+        let context = ProxyContext {
+            rules: Some(RulesFile::load(&rules).unwrap()),
+            ..ProxyContext::default()
+        };
+        fs::remove_file(&rules).unwrap();
+        let proxy = ForwardProxy::bind("127.0.0.1", 0, context).unwrap();
+        let address = proxy.local_addr().unwrap();
+        proxy.spawn();
+
+        let origin = spawn_origin(vec![b'x'; 1000]);
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(client, "GET http://127.0.0.1:{}/data HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n", origin).unwrap();
+        let mut response = BufReader::new(client);
+        let head = read_response_head(&mut response).unwrap();
+        let mut body = Vec::new();
+        response.read_to_end(&mut body).unwrap();
+
+        assert_eq!(head.headers.get("content-length"), Some("1000"));
+        assert_eq!(body.len(), 500);
+    }
+
+    #[test]
+    fn upgrades_are_refused() {
+        let proxy = ForwardProxy::bind("127.0.0.1", 0, ProxyContext::default()).unwrap();
+        let address = proxy.local_addr().unwrap();
+        proxy.spawn();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(
+            client,
+            "GET http://127.0.0.1:9/chat HTTP/1.1\r\nHost: 127.0.0.1:9\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = BufReader::new(client);
+        let head = read_response_head(&mut response).unwrap();
+        let mut body = String::new();
+        response.read_to_string(&mut body).unwrap();
+
+        assert_eq!(head.status, 501);
+        assert!(body.contains("upgrading to websocket is not supported"), "{}", body);
+    }
+
+    #[test]
+    fn held_bodies_are_passed_on_once_too_large() {
+        let head = ResponseHead {
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            reason: "OK".to_string(),
+            headers: Headers::default(),
+        };
+
+        let mut small = Vec::new();
+        let mut held = HeldBody::new(&head, &mut small);
+        held.write_all(b"hello").unwrap();
+        assert!(!held.passed());
+        assert!(small.is_empty());
+
+        let mut large = Vec::new();
+        let mut held = HeldBody::new(&head, &mut large);
+        let chunk = vec![b'x'; MAX_REWRITTEN_BODY / 4];
+        for _ in 0..5 {
+            held.write_all(&chunk).unwrap();
+        }
+        assert!(held.passed());
+        assert!(large.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert_eq!(large.iter().filter(|&&byte| byte == b'x').count(), 5 * chunk.len());
+    }
+}
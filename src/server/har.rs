@@ -0,0 +1,401 @@
+//! Recording proxied traffic as a HAR 1.2 archive
+
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use serde::Serialize;
+use crate::config::time::format_iso8601;
+use crate::error::{AppError, AppResult};
+use crate::server::http::{BodyCapture, Headers};
+
+/// End of the archive, kept after the last entry so the file is always valid JSON
+const ARCHIVE_END: &str = "\n]}}\n";
+
+/// Writes the exchanges of a proxy session to a HAR file
+///
+/// Each entry is appended as it completes, in place of the closing brackets
+/// which are written again after it, so the file is valid even when the
+/// proxy is killed and entries are not kept in memory.
+pub struct HarRecorder {
+    path: PathBuf,
+    body_limit: usize,
+    archive: Mutex<Archive>,
+}
+
+struct Archive {
+    file: File,
+    entries: usize,
+}
+
+impl HarRecorder {
+    /// Record to `path`, keeping bodies up to `body_limit` bytes (0 keeps none)
+    pub fn create(path: &Path, body_limit: usize) -> AppResult<Self> {
+        let creator = Creator {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+        };
+        let creator = serde_json::to_string(&creator).map_err(|e| AppError::state_file_error(path, e))?;
+        let start = format!("{{\"log\":{{\"version\":\"1.2\",\"creator\":{},\"entries\":[", creator);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| AppError::state_file_error(path, e))?;
+        file.write_all(format!("{}{}", start, ARCHIVE_END).as_bytes())
+            .map_err(|e| AppError::state_file_error(path, e))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            body_limit,
+            archive: Mutex::new(Archive { file, entries: 0 }),
+        })
+    }
+
+    /// A capture for one body, honoring the configured limit
+    pub fn body_capture(&self) -> BodyCapture {
+        BodyCapture::new(self.body_limit)
+    }
+
+    /// Append an entry to the archive
+    pub fn record(&self, entry: Entry) -> AppResult<()> {
+        let json = serde_json::to_string(&entry).map_err(|e| AppError::state_file_error(&self.path, e))?;
+
+        // A panicking connection thread must not stop the recording
+        let mut archive = self.archive.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let separator = if archive.entries == 0 { "\n" } else { ",\n" };
+        let tail = format!("{}{}{}", separator, json, ARCHIVE_END);
+
+        archive
+            .file
+            .seek(SeekFrom::End(-(ARCHIVE_END.len() as i64)))
+            .and_then(|_| archive.file.write_all(tail.as_bytes()))
+            .map_err(|e| AppError::state_file_error(&self.path, e))?;
+        archive.entries += 1;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Creator {
+    name: &'static str,
+    version: &'static str,
+}
+
+/// One request and its response
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Entry {
+    started_date_time: String,
+    /// Total time in milliseconds
+    time: f64,
+    request: Request,
+    response: Response,
+    cache: Cache,
+    timings: Timings,
+    #[serde(rename = "serverIPAddress", skip_serializing_if = "Option::is_none")]
+    server_ip_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+impl Entry {
+    /// Start an entry for a request made at `started`
+    pub fn new(started: SystemTime, request: Request, response: Response, timings: Timings) -> Self {
+        Self {
+            started_date_time: format_iso8601(started),
+            time: timings.total(),
+            request,
+            response,
+            cache: Cache {},
+            timings,
+            server_ip_address: None,
+            comment: None,
+        }
+    }
+
+    /// Address of the server that was connected to
+    pub fn server_ip(mut self, ip: Option<String>) -> Self {
+        self.server_ip_address = ip;
+        self
+    }
+
+    /// Free-form note, e.g. why the exchange failed
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    method: String,
+    url: String,
+    http_version: String,
+    cookies: Vec<Cookie>,
+    headers: Vec<Header>,
+    query_string: Vec<Header>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    post_data: Option<PostData>,
+    headers_size: i64,
+    body_size: i64,
+}
+
+impl Request {
+    /// Describe a request; `body` is only kept when it holds any bytes
+    pub fn new(method: &str, url: &str, http_version: &str, headers: &Headers, body_size: u64, body: &BodyCapture) -> Self {
+        let post_data = (!body.data().is_empty()).then(|| {
+            let (text, encoding) = encode_body(body.data());
+            PostData {
+                mime_type: headers.get("content-type").unwrap_or("").to_string(),
+                text,
+                comment: body_comment(encoding, body.truncated()),
+            }
+        });
+
+        Self {
+            method: method.to_string(),
+            url: url.to_string(),
+            http_version: http_version.to_string(),
+            cookies: parse_cookies(headers),
+            headers: har_headers(headers),
+            query_string: query_string(url),
+            post_data,
+            headers_size: -1,
+            body_size: body_size as i64,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Response {
+    status: u16,
+    status_text: String,
+    http_version: String,
+    cookies: Vec<Cookie>,
+    headers: Vec<Header>,
+    content: Content,
+    #[serde(rename = "redirectURL")]
+    redirect_url: String,
+    headers_size: i64,
+    body_size: i64,
+}
+
+impl Response {
+    /// Describe a response; `body` is only kept when it holds any bytes
+    pub fn new(status: u16, status_text: &str, http_version: &str, headers: &Headers, body_size: u64, body: &BodyCapture) -> Self {
+        let (text, encoding) = match body.data() {
+            [] => (None, None),
+            data => {
+                let (text, encoding) = encode_body(data);
+                (Some(text), encoding)
+            }
+        };
+
+        Self {
+            status,
+            status_text: status_text.to_string(),
+            http_version: http_version.to_string(),
+            cookies: Vec::new(),
+            headers: har_headers(headers),
+            content: Content {
+                size: body_size as i64,
+                mime_type: headers.get("content-type").unwrap_or("").to_string(),
+                text,
+                encoding,
+                comment: body_comment(None, body.truncated()),
+            },
+            redirect_url: headers.get("location").unwrap_or("").to_string(),
+            headers_size: -1,
+            body_size: body_size as i64,
+        }
+    }
+
+    /// A response that never arrived, e.g. because the server was unreachable
+    pub fn missing() -> Self {
+        Self::new(0, "", "", &Headers::default(), 0, &BodyCapture::default())
+    }
+}
+
+/// Phases of an exchange in milliseconds, -1 when they do not apply
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Timings {
+    blocked: f64,
+    dns: f64,
+    connect: f64,
+    send: f64,
+    wait: f64,
+    receive: f64,
+    ssl: f64,
+}
+
+impl Timings {
+    /// Timings from measured phases; DNS lookups are part of `connect`
+    pub fn new(connect: Duration, send: Duration, wait: Duration, receive: Duration) -> Self {
+        Self {
+            blocked: -1.0,
+            dns: -1.0,
+            connect: millis(connect),
+            send: millis(send),
+            wait: millis(wait),
+            receive: millis(receive),
+            ssl: -1.0,
+        }
+    }
+
+    /// Time spent on the TLS handshake, which HAR counts as part of `connect`
+    pub fn ssl(mut self, ssl: Duration) -> Self {
+        self.ssl = millis(ssl);
+        self
+    }
+
+    fn total(&self) -> f64 {
+        [self.blocked, self.dns, self.connect, self.send, self.wait, self.receive]
+            .iter()
+            .filter(|phase| **phase > 0.0)
+            .sum()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Cache {}
+
+#[derive(Debug, Serialize)]
+struct Header {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Cookie {
+    name: String,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PostData {
+    mime_type: String,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Content {
+    size: i64,
+    mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    comment: Option<String>,
+}
+
+// Internal helper functions
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000.0
+}
+
+fn har_headers(headers: &Headers) -> Vec<Header> {
+    headers
+        .iter()
+        .map(|(name, value)| Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+        .collect()
+}
+
+fn parse_cookies(headers: &Headers) -> Vec<Cookie> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("cookie"))
+        .flat_map(|(_, value)| value.split(';'))
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| Cookie {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+        .collect()
+}
+
+fn query_string(url: &str) -> Vec<Header> {
+    let query = match url.split_once('?') {
+        Some((_, query)) => query.split('#').next().unwrap_or(""),
+        None => return Vec::new(),
+    };
+
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Header {
+                name: name.to_string(),
+                value: value.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Body as text, base64-encoded when it is not valid UTF-8
+fn encode_body(data: &[u8]) -> (String, Option<&'static str>) {
+    match std::str::from_utf8(data) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (base64::engine::general_purpose::STANDARD.encode(data), Some("base64")),
+    }
+}
+
+fn body_comment(encoding: Option<&str>, truncated: bool) -> Option<String> {
+    match (encoding, truncated) {
+        (Some(encoding), true) => Some(format!("{}-encoded, truncated", encoding)),
+        (Some(encoding), false) => Some(format!("{}-encoded", encoding)),
+        (None, true) => Some("truncated".to_string()),
+        (None, false) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn entry(url: &str) -> Entry {
+        let request = Request::new("GET", url, "HTTP/1.1", &Headers::default(), 0, &BodyCapture::default());
+        let timings = Timings::new(Duration::ZERO, Duration::ZERO, Duration::from_millis(5), Duration::ZERO);
+        Entry::new(SystemTime::now(), request, Response::missing(), timings)
+    }
+
+    #[test]
+    fn archive_stays_valid_after_each_entry() {
+        let path = std::env::temp_dir().join(format!("aps-har-test-{}.har", std::process::id()));
+        let recorder = HarRecorder::create(&path, 0).unwrap();
+
+        let read = || serde_json::from_str::<serde_json::Value>(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(read()["log"]["version"], "1.2");
+        assert_eq!(read()["log"]["entries"].as_array().unwrap().len(), 0);
+
+        recorder.record(entry("http://example.com/one?page=2")).unwrap();
+        recorder.record(entry("http://example.com/two")).unwrap();
+
+        let har = read();
+        let entries = har["log"]["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["request"]["url"], "http://example.com/one?page=2");
+        assert_eq!(entries[0]["request"]["queryString"][0]["value"], "2");
+        assert_eq!(entries[1]["request"]["url"], "http://example.com/two");
+        assert_eq!(har["log"]["creator"]["name"], env!("CARGO_PKG_NAME"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
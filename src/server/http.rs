@@ -0,0 +1,548 @@
+//! Minimal HTTP/1.x message parsing for the forward proxy
+
+use std::io::{self, BufRead, Read, Write};
+
+/// Largest request or response head accepted, in bytes
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Largest body held in memory to be rewritten, before and after decoding
+pub const MAX_REWRITTEN_BODY: usize = 16 * 1024 * 1024;
+
+/// Headers that only apply to a single connection and are not forwarded
+///
+/// `Transfer-Encoding` is kept since bodies are relayed with their framing.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Header fields in their original order and case
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// First value of a header, matched case-insensitively
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a comma-separated header contains `token`
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .flat_map(|(_, value)| value.split(','))
+            .any(|part| part.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Append a header
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove every header with this name
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// Replace every header with this name by a single value
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.push(name, value);
+    }
+
+    /// Iterate over the headers as name and value pairs
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Copy without the hop-by-hop headers, including those listed in `Connection`
+    pub fn end_to_end(&self) -> Headers {
+        let listed: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(|part| part.trim().to_ascii_lowercase())
+            .collect();
+
+        Headers(
+            self.0
+                .iter()
+                .filter(|(key, _)| {
+                    let key = key.to_ascii_lowercase();
+                    !HOP_BY_HOP.contains(&key.as_str()) && !listed.contains(&key)
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Request line and headers
+#[derive(Debug, Clone)]
+pub struct RequestHead {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    /// Whether the client wants the connection closed after this exchange
+    pub fn wants_close(&self) -> bool {
+        if self.version == "HTTP/1.0" {
+            !self.headers.has_token("connection", "keep-alive")
+                && !self.headers.has_token("proxy-connection", "keep-alive")
+        } else {
+            self.headers.has_token("connection", "close")
+                || self.headers.has_token("proxy-connection", "close")
+        }
+    }
+
+    /// How the request body is delimited
+    pub fn framing(&self) -> BodyFraming {
+        if self.headers.has_token("transfer-encoding", "chunked") {
+            return BodyFraming::Chunked;
+        }
+        match content_length(&self.headers) {
+            Some(length) => BodyFraming::Length(length),
+            None => BodyFraming::Empty,
+        }
+    }
+
+    /// Write the head in wire format
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut head = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        write_headers(&mut head, &self.headers);
+        writer.write_all(head.as_bytes())
+    }
+}
+
+/// Status line and headers
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    /// A response generated by the proxy itself
+    pub fn new(status: u16, reason: &str) -> Self {
+        Self {
+            version: "HTTP/1.1".to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: Headers::default(),
+        }
+    }
+
+    /// How the response body is delimited, given the method of the request
+    pub fn framing(&self, method: &str) -> BodyFraming {
+        if method.eq_ignore_ascii_case("HEAD") || self.status < 200 || self.status == 204 || self.status == 304 {
+            return BodyFraming::Empty;
+        }
+        if self.headers.has_token("transfer-encoding", "chunked") {
+            return BodyFraming::Chunked;
+        }
+        match content_length(&self.headers) {
+            Some(length) => BodyFraming::Length(length),
+            None => BodyFraming::UntilClose,
+        }
+    }
+
+    /// Write the head in wire format
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, self.reason);
+        write_headers(&mut head, &self.headers);
+        writer.write_all(head.as_bytes())
+    }
+}
+
+/// How a message body is delimited on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFraming {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+/// Keeps the first bytes of a relayed body
+#[derive(Debug, Clone, Default)]
+pub struct BodyCapture {
+    limit: usize,
+    data: Vec<u8>,
+    truncated: bool,
+}
+
+impl BodyCapture {
+    /// Keep up to `limit` bytes, nothing when the limit is 0
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Bytes kept so far
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether the body was longer than the limit
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Keep the bytes that still fit, noting any that do not
+    pub fn record(&mut self, bytes: &[u8]) {
+        let room = self.limit - self.data.len();
+        if bytes.len() > room {
+            self.truncated = true;
+        }
+        self.data.extend_from_slice(&bytes[..bytes.len().min(room)]);
+    }
+}
+
+/// Read a request head, `None` when the client closed the connection first
+pub fn read_request_head(reader: &mut impl BufRead) -> io::Result<Option<RequestHead>> {
+    let lines = match read_head_lines(reader)? {
+        Some(lines) => lines,
+        None => return Ok(None),
+    };
+
+    let mut parts = lines[0].split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            (method.to_string(), target.to_string(), version.to_string())
+        }
+        _ => return Err(invalid_data(format!("malformed request line '{}'", lines[0]))),
+    };
+
+    Ok(Some(RequestHead {
+        method,
+        target,
+        version,
+        headers: parse_headers(&lines[1..])?,
+    }))
+}
+
+/// Read a response head
+pub fn read_response_head(reader: &mut impl BufRead) -> io::Result<ResponseHead> {
+    let lines = read_head_lines(reader)?
+        .ok_or_else(|| invalid_data("connection closed before the response".to_string()))?;
+
+    let mut parts = lines[0].splitn(3, ' ');
+    let (version, status, reason) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(version), Some(status), reason) if version.starts_with("HTTP/") => {
+            (version, status, reason.unwrap_or(""))
+        }
+        _ => return Err(invalid_data(format!("malformed status line '{}'", lines[0]))),
+    };
+    let status = status
+        .parse()
+        .map_err(|_| invalid_data(format!("malformed status line '{}'", lines[0])))?;
+
+    Ok(ResponseHead {
+        version: version.to_string(),
+        status,
+        reason: reason.to_string(),
+        headers: parse_headers(&lines[1..])?,
+    })
+}
+
+/// Standard reason phrase of a status code, empty for uncommon ones
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+/// Relay a message body, keeping its framing, and return the number of payload bytes
+///
+/// The payload, without chunk framing, is also handed to `capture`.
+pub fn copy_body(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    framing: BodyFraming,
+    capture: &mut BodyCapture,
+) -> io::Result<u64> {
+    let mut writer = CaptureWriter { inner: writer, capture };
+    match framing {
+        BodyFraming::Empty => Ok(0),
+        BodyFraming::Length(length) => {
+            let copied = io::copy(&mut reader.take(length), &mut writer)?;
+            if copied < length {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early"));
+            }
+            Ok(copied)
+        }
+        BodyFraming::UntilClose => io::copy(reader, &mut writer),
+        BodyFraming::Chunked => copy_chunked(reader, &mut writer),
+    }
+}
+
+/// Decode a body sent with a `Content-Encoding`, `None` for an encoding that is not supported
+///
+/// Handles gzip, deflate (zlib-wrapped or raw) and br. Bodies that decode to
+/// more than [`MAX_REWRITTEN_BODY`] bytes are refused.
+pub fn decode_content(body: &[u8], encoding: &str) -> Option<io::Result<Vec<u8>>> {
+    let encoding = encoding.trim().to_ascii_lowercase();
+    let decoded = match encoding.as_str() {
+        "gzip" | "x-gzip" => read_limited(flate2::read::MultiGzDecoder::new(body)),
+        "deflate" => read_limited(flate2::read::ZlibDecoder::new(body))
+            .or_else(|_| read_limited(flate2::read::DeflateDecoder::new(body))),
+        "br" => read_limited(brotli_decompressor::Decompressor::new(body, 4096)),
+        _ => return None,
+    };
+    Some(decoded)
+}
+
+// Internal helper functions
+
+/// Writer passing payload bytes on to a capture
+struct CaptureWriter<'a, W: Write> {
+    inner: &'a mut W,
+    capture: &'a mut BodyCapture,
+}
+
+impl<W: Write> CaptureWriter<'_, W> {
+    /// Write bytes that are framing rather than payload
+    fn write_framing(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.inner.write_all(bytes)
+    }
+}
+
+impl<W: Write> Write for CaptureWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.capture.record(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn copy_chunked<W: Write>(reader: &mut impl BufRead, writer: &mut CaptureWriter<'_, W>) -> io::Result<u64> {
+    let mut total = 0;
+    loop {
+        let line = read_line(reader)?;
+        writer.write_framing(line.as_bytes())?;
+        writer.write_framing(b"\r\n")?;
+
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = u64::from_str_radix(size, 16)
+            .map_err(|_| invalid_data(format!("malformed chunk size '{}'", line)))?;
+
+        if size == 0 {
+            // Trailers up to the empty line
+            loop {
+                let trailer = read_line(reader)?;
+                writer.write_framing(trailer.as_bytes())?;
+                writer.write_framing(b"\r\n")?;
+                if trailer.is_empty() {
+                    return Ok(total);
+                }
+            }
+        }
+
+        if io::copy(&mut reader.take(size), writer)? < size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk ended early"));
+        }
+        total += size;
+
+        let end = read_line(reader)?;
+        if !end.is_empty() {
+            return Err(invalid_data("missing CRLF after chunk".to_string()));
+        }
+        writer.write_framing(b"\r\n")?;
+    }
+}
+
+fn read_head_lines(reader: &mut impl BufRead) -> io::Result<Option<Vec<String>>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+
+    loop {
+        let mut line = String::new();
+        let read = read_line_within(reader, &mut line, MAX_HEAD_SIZE - size)?;
+        if read == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "head ended early"));
+        }
+
+        size += read;
+        if size > MAX_HEAD_SIZE {
+            return Err(invalid_data("head too large".to_string()));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            // Tolerate empty lines before the start line
+            if lines.is_empty() {
+                continue;
+            }
+            return Ok(Some(lines));
+        }
+        lines.push(line);
+    }
+}
+
+fn read_line(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut line = String::new();
+    let read = read_line_within(reader, &mut line, MAX_HEAD_SIZE)?;
+    if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "body ended early"));
+    }
+    if read > MAX_HEAD_SIZE {
+        return Err(invalid_data("line too long".to_string()));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Read a line, stopping one byte past `limit` so a line without an end
+/// cannot grow without bound; a result above `limit` means it was too long
+fn read_line_within(reader: &mut impl BufRead, line: &mut String, limit: usize) -> io::Result<usize> {
+    reader.by_ref().take(limit as u64 + 1).read_line(line)
+}
+
+fn parse_headers(lines: &[String]) -> io::Result<Headers> {
+    let mut headers = Headers::default();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_data(format!("malformed header '{}'", line)))?;
+        headers.push(name.trim(), value.trim());
+    }
+    Ok(headers)
+}
+
+fn content_length(headers: &Headers) -> Option<u64> {
+    headers.get("content-length").and_then(|value| value.trim().parse().ok())
+}
+
+fn write_headers(head: &mut String, headers: &Headers) {
+    for (name, value) in headers.iter() {
+        head.push_str(name);
+        head.push_str(": ");
+        head.push_str(value);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+}
+
+fn read_limited(decoder: impl Read) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    decoder.take(MAX_REWRITTEN_BODY as u64 + 1).read_to_end(&mut decoded)?;
+    if decoded.len() > MAX_REWRITTEN_BODY {
+        return Err(invalid_data(format!("decodes to more than {} bytes", MAX_REWRITTEN_BODY)));
+    }
+    Ok(decoded)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    #[test]
+    fn compressed_bodies_are_decoded() {
+        use flate2::write::{GzEncoder, ZlibEncoder};
+        use flate2::Compression;
+
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(b"{\"flag\":false}").unwrap();
+        let gzip = gzip.finish().unwrap();
+        assert_eq!(decode_content(&gzip, "gzip").unwrap().unwrap(), b"{\"flag\":false}");
+
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(b"deflated").unwrap();
+        let zlib = zlib.finish().unwrap();
+        assert_eq!(decode_content(&zlib, "Deflate").unwrap().unwrap(), b"deflated");
+
+        // "hello" compressed with brotli
+        let br = [0x0b, 0x02, 0x80, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x03];
+        assert_eq!(decode_content(&br, "br").unwrap().unwrap(), b"hello");
+
+        assert!(decode_content(b"garbage", "gzip").unwrap().is_err());
+        assert!(decode_content(b"", "zstd").is_none());
+    }
+
+    #[test]
+    fn request_heads_are_parsed() {
+        let mut reader = Cursor::new(b"\r\nGET http://example.com/a?b=1 HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n".to_vec());
+
+        let head = read_request_head(&mut reader).unwrap().unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.target, "http://example.com/a?b=1");
+        assert_eq!(head.headers.get("accept"), Some("*/*"));
+    }
+
+    #[test]
+    fn endless_lines_are_rejected_without_buffering_them() {
+        let mut reader = BufReader::new(io::repeat(b'a'));
+
+        let error = read_request_head(&mut reader).err().unwrap();
+        assert_eq!(error.to_string(), "head too large");
+    }
+
+    #[test]
+    fn heads_over_the_limit_are_rejected() {
+        let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+        while head.len() <= MAX_HEAD_SIZE {
+            head.extend_from_slice(b"X-Filler: 0123456789abcdef0123456789abcdef\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+
+        let error = read_request_head(&mut Cursor::new(head)).err().unwrap();
+        assert_eq!(error.to_string(), "head too large");
+    }
+
+    #[test]
+    fn endless_chunk_size_lines_are_rejected() {
+        let mut reader = BufReader::new(io::repeat(b'f'));
+        let mut sink = Vec::new();
+
+        let error = copy_body(&mut reader, &mut sink, BodyFraming::Chunked, &mut BodyCapture::new(0)).err().unwrap();
+        assert_eq!(error.to_string(), "line too long");
+    }
+}
@@ -0,0 +1,311 @@
+//! Terminating TLS inside CONNECT tunnels so HTTPS exchanges can be read
+//!
+//! The device talks TLS to the proxy, which presents a certificate minted by
+//! the local CA, and the proxy opens its own verified TLS connection to the
+//! real server.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::server::Acceptor;
+use rustls::{ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConfig, ServerConnection, SideData, StreamOwned};
+use crate::cert::authority::CertificateAuthority;
+use crate::error::{AppError, AppResult};
+
+/// Only HTTP/1.1 is spoken on either side
+const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// Mints certificates for intercepted hosts and connects to the real servers
+pub struct Interceptor {
+    authority: CertificateAuthority,
+    provider: Arc<CryptoProvider>,
+    server_configs: Mutex<HashMap<String, Arc<ServerConfig>>>,
+    client_config: Arc<ClientConfig>,
+}
+
+impl Interceptor {
+    /// Intercept with `authority`, trusting the public roots and the
+    /// certificates in `upstream_ca` (a PEM file) for upstream servers
+    pub fn new(authority: CertificateAuthority, upstream_ca: Option<&Path>) -> AppResult<Self> {
+        let provider = Arc::new(ring::default_provider());
+
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if let Some(path) = upstream_ca {
+            let certificates = CertificateDer::pem_file_iter(path)
+                .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| AppError::certificate(format!("cannot read {}: {}", path.display(), e)))?;
+            if certificates.is_empty() {
+                return Err(AppError::certificate(format!("no certificates in {}", path.display())));
+            }
+            for certificate in certificates {
+                roots.add(certificate).map_err(tls_error)?;
+            }
+        }
+
+        let mut client_config = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+
+        Ok(Self {
+            authority,
+            provider,
+            server_configs: Mutex::new(HashMap::new()),
+            client_config: Arc::new(client_config),
+        })
+    }
+
+    /// Complete the TLS handshake with a client that connected through a tunnel to `host`
+    ///
+    /// `pending` holds bytes of the handshake already read from `client`.
+    /// The certificate is issued for the name the client asked for (SNI),
+    /// falling back to `host`, which is also returned.
+    pub fn accept(&self, host: &str, client: TcpStream, pending: &[u8]) -> io::Result<(String, TlsStream<ServerConnection>)> {
+        let mut acceptor = Acceptor::default();
+        let mut input = pending.chain(client);
+        let accepted = loop {
+            if acceptor.read_tls(&mut input)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "client closed during the TLS handshake"));
+            }
+            if let Some(accepted) = acceptor.accept().map_err(|(e, _)| io::Error::other(e))? {
+                break accepted;
+            }
+        };
+
+        let server_name = accepted
+            .client_hello()
+            .server_name()
+            .unwrap_or(host)
+            .to_string();
+        let config = self.server_config(&server_name).map_err(io::Error::other)?;
+        let mut connection = accepted.into_connection(config).map_err(|(e, _)| io::Error::other(e))?;
+
+        let (mut rest, mut client) = input.into_inner();
+        while !rest.is_empty() {
+            connection.read_tls(&mut rest)?;
+            connection.process_new_packets().map_err(io::Error::other)?;
+        }
+        while connection.is_handshaking() {
+            connection.complete_io(&mut client)?;
+        }
+
+        Ok((server_name, TlsStream::new(connection, client)))
+    }
+
+    /// Open a verified TLS connection to the server named `server_name`
+    pub fn connect(&self, server_name: &str, mut upstream: TcpStream) -> io::Result<TlsStream<ClientConnection>> {
+        let name = ServerName::try_from(server_name.to_string()).map_err(io::Error::other)?;
+        let mut connection = ClientConnection::new(Arc::clone(&self.client_config), name).map_err(io::Error::other)?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut upstream)?;
+        }
+        Ok(TlsStream::new(connection, upstream))
+    }
+
+    /// The server configuration presenting a certificate for `host`, minted on first use
+    fn server_config(&self, host: &str) -> AppResult<Arc<ServerConfig>> {
+        let mut configs = self
+            .server_configs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(config) = configs.get(host) {
+            return Ok(Arc::clone(config));
+        }
+
+        let leaf = self.authority.issue_leaf(host)?;
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&self.provider))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_single_cert(leaf.chain, leaf.key)
+            .map_err(tls_error)?;
+        config.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+
+        let config = Arc::new(config);
+        configs.insert(host.to_string(), Arc::clone(&config));
+        Ok(config)
+    }
+}
+
+/// A TLS connection over TCP
+///
+/// Many servers and clients close without sending close_notify; like
+/// browsers, that is treated as the end of the stream rather than an error.
+pub struct TlsStream<C> {
+    inner: StreamOwned<C, TcpStream>,
+}
+
+impl<C, S> TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn new(connection: C, socket: TcpStream) -> Self {
+        Self {
+            inner: StreamOwned::new(connection, socket),
+        }
+    }
+
+    /// Tell the peer no more data follows
+    pub fn close(&mut self) -> io::Result<()> {
+        self.inner.conn.send_close_notify();
+        self.inner.flush()
+    }
+}
+
+impl<C, S> Read for TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl<C, S> Write for TlsStream<C>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Internal helper functions
+
+fn tls_error(error: impl std::fmt::Display) -> AppError {
+    AppError::certificate(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+    use x509_parser::extensions::GeneralName;
+    use x509_parser::parse_x509_certificate;
+    use crate::cert::authority::CA_COMMON_NAME;
+    use crate::server::forward::{ForwardProxy, ProxyContext};
+
+    /// TLS origin answering one request with `hello`, returning the request head it read
+    fn spawn_origin(authority: &CertificateAuthority) -> (u16, thread::JoinHandle<String>) {
+        let leaf = authority.issue_leaf("localhost").unwrap();
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(leaf.chain, leaf.key)
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = BufReader::new(StreamOwned::new(connection, socket));
+
+            let mut head = String::new();
+            while !head.ends_with("\r\n\r\n") {
+                assert!(stream.read_line(&mut head).unwrap() > 0, "request ended early");
+            }
+            let tls = stream.get_mut();
+            tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Type: text/plain\r\n\r\nhello").unwrap();
+            tls.flush().unwrap();
+            head
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn intercepted_tunnel_decrypts_the_exchange() {
+        let dir = std::env::temp_dir().join(format!("aps-intercept-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let proxy_ca = CertificateAuthority::generate(&dir.join("proxy-ca.pem"), &dir.join("proxy-ca.key")).unwrap();
+        let origin_ca = CertificateAuthority::generate(&dir.join("origin-ca.pem"), &dir.join("origin-ca.key")).unwrap();
+        let proxy_ca_der = proxy_ca.cert_der().clone();
+
+        let (origin_port, origin) = spawn_origin(&origin_ca);
+        let context = ProxyContext {
+            interceptor: Some(Interceptor::new(proxy_ca, Some(&dir.join("origin-ca.pem"))).unwrap()),
+            ..ProxyContext::default()
+        };
+        let proxy = ForwardProxy::bind("127.0.0.1", 0, context).unwrap();
+        let proxy_port = proxy.local_addr().unwrap().port();
+        proxy.spawn();
+
+        // The client trusts nothing but the proxy's CA
+        let mut socket = TcpStream::connect(("127.0.0.1", proxy_port)).unwrap();
+        write!(socket, "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n", origin_port).unwrap();
+        let mut reader = BufReader::new(socket.try_clone().unwrap());
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        let mut line = String::new();
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots.add(proxy_ca_der).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut tls = StreamOwned::new(connection, socket);
+        write!(tls, "GET /greeting?lang=en HTTP/1.1\r\nHost: localhost:{}\r\nConnection: close\r\n\r\n", origin_port).unwrap();
+        let mut response = String::new();
+        tls.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+
+        let leaf = tls.conn.peer_certificates().unwrap()[0].clone();
+        let (_, leaf) = parse_x509_certificate(&leaf).unwrap();
+        let names: Vec<String> = leaf
+            .subject_alternative_name()
+            .unwrap()
+            .unwrap()
+            .value
+            .general_names
+            .iter()
+            .map(|name| match name {
+                GeneralName::DNSName(name) => name.to_string(),
+                other => format!("{:?}", other),
+            })
+            .collect();
+        assert_eq!(names, ["localhost"]);
+        assert!(leaf.issuer().to_string().contains(CA_COMMON_NAME));
+
+        let request = origin.join().unwrap();
+        assert!(request.starts_with("GET /greeting?lang=en HTTP/1.1\r\n"), "{}", request);
+        assert!(request.contains(&format!("Host: localhost:{}\r\n", origin_port)), "{}", request);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,10 @@
+//! Built-in proxy server
+
+pub mod forward;
+pub mod har;
+pub mod http;
+pub mod intercept;
+pub mod rules;
+pub mod shaping;
+pub mod tunnel;
+pub mod upstream;
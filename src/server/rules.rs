@@ -0,0 +1,529 @@
+//! Map-local, rewrite and fault injection rules applied by the forward proxy
+//!
+//! Rules are read from a TOML file, one `[[rule]]` table each, and the file
+//! is read again whenever it changes so edits apply to the next request.
+//! Every rule whose URL pattern matches a request applies, in file order.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use colored::*;
+use regex::Regex;
+use serde::Deserialize;
+use crate::error::{AppError, AppResult};
+use crate::server::forward::split_authority;
+use crate::server::http::Headers;
+
+/// A rules file, reloaded when its modification time changes
+pub struct RulesFile {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+struct Loaded {
+    modified: Option<SystemTime>,
+    rules: Arc<RuleSet>,
+}
+
+impl RulesFile {
+    /// Read the rules in `path`, failing on any invalid rule
+    pub fn load(path: &Path) -> AppResult<Self> {
+        let modified = modified(path);
+        let rules = RuleSet::read(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            loaded: Mutex::new(Loaded {
+                modified,
+                rules: Arc::new(rules),
+            }),
+        })
+    }
+
+    /// Number of rules currently in effect
+    pub fn rule_count(&self) -> usize {
+        self.lock().rules.rules.len()
+    }
+
+    /// Rules in effect, reading the file again when it changed since the last call
+    ///
+    /// An edit that breaks the file is reported and the previous rules stay in effect.
+    pub fn current(&self) -> Arc<RuleSet> {
+        let mut loaded = self.lock();
+        let modified = modified(&self.path);
+        if modified != loaded.modified {
+            loaded.modified = modified;
+            match RuleSet::read(&self.path) {
+                Ok(rules) => {
+                    println!(
+                        "{} {} rule{} from {}",
+                        "🔄 Reloaded".cyan(),
+                        rules.rules.len(),
+                        if rules.rules.len() == 1 { "" } else { "s" },
+                        self.path.display()
+                    );
+                    loaded.rules = Arc::new(rules);
+                }
+                Err(e) => println!("{} {}", "⚠️ Keeping the previous rules:".yellow(), e),
+            }
+        }
+        Arc::clone(&loaded.rules)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Loaded> {
+        self.loaded.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The rules of one version of the file
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// What the rules matching `method` and `url` do to the exchange
+    pub fn plan(&self, method: &str, url: &str) -> Plan {
+        let mut plan = Plan::default();
+        for rule in &self.rules {
+            let method_matches = rule.method.as_deref().is_none_or(|m| m.eq_ignore_ascii_case(method));
+            if !method_matches || !rule.url.is_match(url) {
+                continue;
+            }
+
+            if plan.map_local.is_none() {
+                plan.map_local = rule.map_local.clone();
+            }
+            if rule.host.is_some() {
+                plan.host = rule.host.clone();
+            }
+            plan.path.extend(rule.path.iter().cloned());
+            plan.request_headers.extend(rule.request_headers.iter().cloned());
+            plan.response_headers.extend(rule.response_headers.iter().cloned());
+            plan.replace_body.extend(rule.replace_body.iter().cloned());
+            plan.faults.extend(rule.fault);
+        }
+        plan
+    }
+
+    fn read(path: &Path) -> AppResult<Self> {
+        let content = fs::read_to_string(path).map_err(|e| AppError::invalid_rules(path, e))?;
+        let file: RulesConfig = toml::from_str(&content).map_err(|e| AppError::invalid_rules(path, e))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+
+        let rules = file
+            .rule
+            .into_iter()
+            .enumerate()
+            .map(|(index, config)| {
+                Rule::compile(config, base)
+                    .map_err(|reason| AppError::invalid_rules(path, format!("rule {}: {}", index + 1, reason)))
+            })
+            .collect::<AppResult<Vec<_>>>()?;
+
+        Ok(Self { rules })
+    }
+}
+
+/// Combined effect of the rules matching one request
+#[derive(Default)]
+pub struct Plan {
+    /// Answer with a local file instead of forwarding, from the first matching rule
+    pub map_local: Option<MapLocal>,
+    /// New host and optional port, from the last matching rule
+    pub host: Option<(String, Option<u16>)>,
+    /// Replacements applied to the origin-form path, in order
+    pub path: Vec<Replacement>,
+    pub request_headers: Vec<HeaderChange>,
+    pub response_headers: Vec<HeaderChange>,
+    /// Replacements applied to the response body, in order
+    pub replace_body: Vec<Replacement>,
+    /// Faults to inject, the first one whose probability comes up applies
+    pub faults: Vec<FaultRule>,
+}
+
+impl Plan {
+    /// Path after the path replacements
+    pub fn rewrite_path(&self, path: &str) -> String {
+        self.path
+            .iter()
+            .fold(path.to_string(), |path, replacement| replacement.apply(&path))
+    }
+}
+
+/// A local file served in place of the server's response
+#[derive(Debug, Clone)]
+pub struct MapLocal {
+    pub file: PathBuf,
+    pub status: u16,
+    pub content_type: String,
+}
+
+/// A regex and its replacement, which may refer to groups as `$1` or `${name}`
+#[derive(Debug, Clone)]
+pub struct Replacement {
+    pattern: Regex,
+    replace: String,
+}
+
+impl Replacement {
+    /// `text` with every match replaced
+    pub fn apply(&self, text: &str) -> String {
+        self.pattern.replace_all(text, self.replace.as_str()).into_owned()
+    }
+
+    fn compile(config: ReplacementConfig) -> Result<Self, String> {
+        let pattern = Regex::new(&config.pattern).map_err(|e| format!("invalid pattern '{}': {}", config.pattern, e))?;
+        Ok(Self {
+            pattern,
+            replace: config.replace,
+        })
+    }
+}
+
+/// A failure injected in place of the normal exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Answer with this status without asking the server
+    Status(u16),
+    /// Never answer, until the client gives up
+    Timeout,
+    /// Cut the response body halfway and close the connection
+    Truncate,
+}
+
+/// A fault and how often it is injected
+#[derive(Debug, Clone, Copy)]
+pub struct FaultRule {
+    pub fault: Fault,
+    /// Share of matching requests affected, from 0 to 1
+    pub probability: f64,
+}
+
+/// A header added (replacing any with the same name) or removed
+#[derive(Debug, Clone)]
+pub enum HeaderChange {
+    Set(String, String),
+    Remove(String),
+}
+
+/// Apply header changes in order
+pub fn apply_header_changes(headers: &mut Headers, changes: &[HeaderChange]) {
+    for change in changes {
+        match change {
+            HeaderChange::Set(name, value) => headers.set(name, value),
+            HeaderChange::Remove(name) => headers.remove(name),
+        }
+    }
+}
+
+// Internal helper functions
+
+/// Layout of the rules file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesConfig {
+    #[serde(default)]
+    rule: Vec<RuleConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    url: String,
+    method: Option<String>,
+    map_local: Option<PathBuf>,
+    status: Option<u16>,
+    content_type: Option<String>,
+    host: Option<String>,
+    path: Option<ReplacementConfig>,
+    #[serde(default)]
+    add_request_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    remove_request_headers: Vec<String>,
+    #[serde(default)]
+    add_response_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    remove_response_headers: Vec<String>,
+    #[serde(default)]
+    replace_body: Vec<ReplacementConfig>,
+    fault: Option<String>,
+    probability: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReplacementConfig {
+    pattern: String,
+    replace: String,
+}
+
+/// One compiled rule
+struct Rule {
+    url: Regex,
+    method: Option<String>,
+    map_local: Option<MapLocal>,
+    host: Option<(String, Option<u16>)>,
+    path: Option<Replacement>,
+    request_headers: Vec<HeaderChange>,
+    response_headers: Vec<HeaderChange>,
+    replace_body: Vec<Replacement>,
+    fault: Option<FaultRule>,
+}
+
+impl Rule {
+    /// Check and compile a rule, resolving `map_local` against `base`
+    fn compile(config: RuleConfig, base: &Path) -> Result<Self, String> {
+        if config.map_local.is_none() && (config.status.is_some() || config.content_type.is_some()) {
+            return Err("status and content_type only apply to map_local".to_string());
+        }
+        let fault = match config.fault {
+            Some(fault) => {
+                let probability = config.probability.unwrap_or(1.0);
+                if !(0.0..=1.0).contains(&probability) {
+                    return Err(format!("probability {} is not between 0 and 1", probability));
+                }
+                Some(FaultRule {
+                    fault: parse_fault(&fault)?,
+                    probability,
+                })
+            }
+            None if config.probability.is_some() => return Err("probability only applies to fault".to_string()),
+            None => None,
+        };
+
+        let map_local = config.map_local.map(|file| {
+            let file = base.join(file);
+            MapLocal {
+                content_type: config.content_type.unwrap_or_else(|| content_type_for(&file).to_string()),
+                status: config.status.unwrap_or(200),
+                file,
+            }
+        });
+        let host = match config.host {
+            Some(host) => {
+                // Without a port the request keeps its own
+                let (name, port) = split_authority(&host, 0).ok_or_else(|| format!("invalid host '{}'", host))?;
+                Some((name, Some(port).filter(|port| *port != 0)))
+            }
+            None => None,
+        };
+
+        let mut request_headers: Vec<HeaderChange> = config
+            .remove_request_headers
+            .into_iter()
+            .map(HeaderChange::Remove)
+            .collect();
+        request_headers.extend(config.add_request_headers.into_iter().map(|(name, value)| HeaderChange::Set(name, value)));
+        let mut response_headers: Vec<HeaderChange> = config
+            .remove_response_headers
+            .into_iter()
+            .map(HeaderChange::Remove)
+            .collect();
+        response_headers.extend(config.add_response_headers.into_iter().map(|(name, value)| HeaderChange::Set(name, value)));
+
+        let rule = Self {
+            url: url_pattern(&config.url)?,
+            method: config.method,
+            map_local,
+            host,
+            path: config.path.map(Replacement::compile).transpose()?,
+            request_headers,
+            response_headers,
+            replace_body: config
+                .replace_body
+                .into_iter()
+                .map(Replacement::compile)
+                .collect::<Result<_, _>>()?,
+            fault,
+        };
+        if rule.map_local.is_none()
+            && rule.host.is_none()
+            && rule.path.is_none()
+            && rule.request_headers.is_empty()
+            && rule.response_headers.is_empty()
+            && rule.replace_body.is_empty()
+            && rule.fault.is_none()
+        {
+            return Err(format!("'{}' has no action", config.url));
+        }
+        Ok(rule)
+    }
+}
+
+/// Regex matching whole URLs against a pattern where `*` stands for any text
+fn url_pattern(pattern: &str) -> Result<Regex, String> {
+    let parts: Vec<String> = pattern.split('*').map(regex::escape).collect();
+    Regex::new(&format!("^{}$", parts.join(".*"))).map_err(|e| format!("invalid url '{}': {}", pattern, e))
+}
+
+/// Fault from `timeout`, `truncate` or an HTTP error status such as `503`
+fn parse_fault(fault: &str) -> Result<Fault, String> {
+    match fault {
+        "timeout" => Ok(Fault::Timeout),
+        "truncate" => Ok(Fault::Truncate),
+        status => match status.parse::<u16>() {
+            Ok(status) if (400..=599).contains(&status) => Ok(Fault::Status(status)),
+            _ => Err(format!(
+                "invalid fault '{}', expected an HTTP error status (400-599), timeout or truncate",
+                fault
+            )),
+        },
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Content type of a mapped file, from its extension
+fn content_type_for(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "json" => "application/json",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "js" => "text/javascript",
+        "css" => "text/css",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn rules(toml: &str) -> AppResult<RuleSet> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("aps-rules-{}-{}.toml", std::process::id(), count));
+        fs::write(&path, toml).unwrap();
+        let rules = RuleSet::read(&path);
+        fs::remove_file(path).unwrap();
+        rules
+    }
+
+    fn write_rules(path: &Path, toml: &str, modified: SystemTime) {
+        fs::write(path, toml).unwrap();
+        fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn url_patterns_match_whole_urls() {
+        let pattern = url_pattern("https://api.example.com/v1/*").unwrap();
+        assert!(pattern.is_match("https://api.example.com/v1/users?id=1"));
+        assert!(!pattern.is_match("https://api.example.com/v2/users"));
+        assert!(!pattern.is_match("http://evil.test/?https://api.example.com/v1/"));
+
+        // Regex syntax other than `*` is literal
+        let pattern = url_pattern("http://a.test/search?q=(x)*").unwrap();
+        assert!(pattern.is_match("http://a.test/search?q=(x)&page=2"));
+        assert!(!pattern.is_match("http://a.test/searchXq=(x)"));
+    }
+
+    #[test]
+    fn faults_are_parsed() {
+        assert_eq!(parse_fault("503"), Ok(Fault::Status(503)));
+        assert_eq!(parse_fault("timeout"), Ok(Fault::Timeout));
+        assert_eq!(parse_fault("truncate"), Ok(Fault::Truncate));
+        for invalid in ["200", "600", "slow", ""] {
+            assert!(parse_fault(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let cases = [
+            ("url = \"*\"", "has no action"),
+            ("url = \"*\"\nstatus = 404", "only apply to map_local"),
+            ("url = \"*\"\nfault = \"503\"\nprobability = 1.5", "not between 0 and 1"),
+            ("url = \"*\"\nhost = \"a.test\"\nprobability = 0.5", "only applies to fault"),
+            ("url = \"*\"\nfault = \"200\"", "invalid fault"),
+            ("url = \"*\"\nreplace_body = [{ pattern = \"(\", replace = \"\" }]", "invalid pattern"),
+            ("url = \"*\"\nhots = \"a.test\"", "unknown field"),
+        ];
+        for (rule, reason) in cases {
+            let error = rules(&format!("[[rule]]\n{}\n", rule)).err().unwrap().to_string();
+            assert!(error.contains(reason), "{}: {}", rule, error);
+        }
+    }
+
+    #[test]
+    fn matching_rules_combine_in_file_order() {
+        let rules = rules(
+            r#"
+            [[rule]]
+            url = "http://a.test/*"
+            map_local = "first.json"
+            host = "staging.a.test"
+            path = { pattern = "^/v1/", replace = "/v2/" }
+            add_response_headers = { X-Rule = "one" }
+
+            [[rule]]
+            url = "http://a.test/api/*"
+            method = "post"
+            map_local = "second.json"
+            host = "localhost:8080"
+            path = { pattern = "/v2/", replace = "/v3/" }
+            remove_response_headers = ["X-Rule"]
+            fault = "truncate"
+
+            [[rule]]
+            url = "http://other.test/*"
+            fault = "503"
+            "#,
+        )
+        .unwrap();
+
+        let post = rules.plan("POST", "http://a.test/api/v1/items");
+        assert_eq!(post.map_local.as_ref().unwrap().file.file_name().unwrap(), "first.json");
+        assert_eq!(post.host, Some(("localhost".to_string(), Some(8080))));
+        assert_eq!(post.rewrite_path("/v1/items"), "/v3/items");
+        let mut headers = Headers::default();
+        apply_header_changes(&mut headers, &post.response_headers);
+        assert_eq!(headers.get("x-rule"), None);
+        assert_eq!(post.faults.iter().map(|rule| rule.fault).collect::<Vec<_>>(), [Fault::Truncate]);
+
+        let get = rules.plan("GET", "http://a.test/api/v1/items");
+        assert_eq!(get.host, Some(("staging.a.test".to_string(), None)));
+        assert_eq!(get.rewrite_path("/v1/items"), "/v2/items");
+        assert!(get.faults.is_empty());
+
+        let other = rules.plan("GET", "http://unrelated.test/");
+        assert!(other.map_local.is_none() && other.host.is_none() && other.faults.is_empty());
+    }
+
+    #[test]
+    fn rules_reload_when_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("aps-rules-reload-{}.toml", std::process::id()));
+        let start = SystemTime::now() - Duration::from_secs(60);
+        write_rules(&path, "[[rule]]\nurl = \"*\"\nfault = \"503\"\n", start);
+        let file = RulesFile::load(&path).unwrap();
+        assert_eq!(file.rule_count(), 1);
+
+        write_rules(
+            &path,
+            "[[rule]]\nurl = \"*\"\nfault = \"503\"\n[[rule]]\nurl = \"*\"\nfault = \"timeout\"\n",
+            start + Duration::from_secs(10),
+        );
+        assert_eq!(file.current().plan("GET", "http://a.test/").faults.len(), 2);
+
+        // A broken edit keeps the rules in effect
+        write_rules(&path, "[[rule]]\nurl = \n", start + Duration::from_secs(20));
+        assert_eq!(file.current().plan("GET", "http://a.test/").faults.len(), 2);
+        assert_eq!(file.rule_count(), 2);
+
+        fs::remove_file(path).unwrap();
+    }
+}
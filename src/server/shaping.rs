@@ -0,0 +1,341 @@
+//! Simulated network conditions for the forward proxy
+//!
+//! A profile adds latency, throttles both directions and randomly breaks
+//! connections. Random decisions come from a seeded generator, so the same
+//! seed and the same requests give the same failures.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use clap::ValueEnum;
+
+/// Largest write passed on at once, keeping throttled transfers smooth
+const THROTTLE_CHUNK: usize = 4 * 1024;
+
+/// Most bytes a throttled writer saves up while idle
+const THROTTLE_BURST: usize = 16 * 1024;
+
+/// Network conditions to simulate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ShapingProfile {
+    #[value(name = "3g")]
+    ThreeG,
+    Edge,
+    LossyWifi,
+}
+
+impl ShapingProfile {
+    /// Conditions of the profile
+    pub fn conditions(self) -> Conditions {
+        match self {
+            ShapingProfile::ThreeG => Conditions {
+                latency: Duration::from_millis(300),
+                down_rate: 200 * 1024,
+                up_rate: 96 * 1024,
+                drop_rate: 0.01,
+                reset_rate: 0.01,
+            },
+            ShapingProfile::Edge => Conditions {
+                latency: Duration::from_millis(600),
+                down_rate: 30 * 1024,
+                up_rate: 24 * 1024,
+                drop_rate: 0.02,
+                reset_rate: 0.02,
+            },
+            ShapingProfile::LossyWifi => Conditions {
+                latency: Duration::from_millis(40),
+                down_rate: 1024 * 1024,
+                up_rate: 512 * 1024,
+                drop_rate: 0.05,
+                reset_rate: 0.05,
+            },
+        }
+    }
+}
+
+/// Latency, throughput and failure rates of a profile
+#[derive(Debug, Clone, Copy)]
+pub struct Conditions {
+    /// Added when a connection or tunnel opens and before each response
+    pub latency: Duration,
+    /// Bytes per second towards the client
+    pub down_rate: u64,
+    /// Bytes per second towards the server
+    pub up_rate: u64,
+    /// Share of requests and tunnels closed without an answer
+    pub drop_rate: f64,
+    /// Share of requests and tunnels answered with a TCP reset
+    pub reset_rate: f64,
+}
+
+/// How a connection is broken on purpose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Closed without an answer
+    Drop,
+    /// Reset (RST) without an answer
+    Reset,
+}
+
+/// Applies a profile to the traffic of the proxy
+pub struct Shaper {
+    profile: ShapingProfile,
+    conditions: Conditions,
+}
+
+impl Shaper {
+    /// Shape traffic according to `profile`
+    pub fn new(profile: ShapingProfile) -> Self {
+        Self {
+            profile,
+            conditions: profile.conditions(),
+        }
+    }
+
+    /// Name and conditions of the profile, for the startup message
+    pub fn describe(&self) -> String {
+        let conditions = &self.conditions;
+        format!(
+            "{}: {} ms latency, {} KB/s down, {} KB/s up, {}% drops, {}% resets",
+            profile_name(self.profile),
+            conditions.latency.as_millis(),
+            conditions.down_rate / 1024,
+            conditions.up_rate / 1024,
+            conditions.drop_rate * 100.0,
+            conditions.reset_rate * 100.0
+        )
+    }
+
+    /// Name of the profile, for the log
+    pub fn name(&self) -> String {
+        profile_name(self.profile)
+    }
+
+    /// Wait for the latency of the profile
+    pub fn delay(&self) {
+        thread::sleep(self.conditions.latency);
+    }
+
+    /// Bytes per second towards the client
+    pub fn down_rate(&self) -> u64 {
+        self.conditions.down_rate
+    }
+
+    /// Bytes per second towards the server
+    pub fn up_rate(&self) -> u64 {
+        self.conditions.up_rate
+    }
+
+    /// Whether to break the connection instead of handling the next request
+    pub fn failure(&self, random: &mut RandomStream) -> Option<Failure> {
+        let roll = random.next_f64();
+        if roll < self.conditions.drop_rate {
+            Some(Failure::Drop)
+        } else if roll < self.conditions.drop_rate + self.conditions.reset_rate {
+            Some(Failure::Reset)
+        } else {
+            None
+        }
+    }
+}
+
+/// Seeded random numbers (SplitMix64), one stream per request
+///
+/// Each request or tunnel draws from its own stream, derived from the seed,
+/// a key naming the request and how often that key was seen before. Replaying
+/// a session with the same seed therefore makes the same decisions even when
+/// connections are served in a different order; only identical requests
+/// racing each other can swap their outcomes.
+pub struct Random {
+    seed: u64,
+    /// Occurrences so far, by hash of the key
+    occurrences: Mutex<HashMap<u64, u64>>,
+}
+
+impl Random {
+    /// Numbers from `seed`, or from a seed taken from the clock
+    pub fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_nanos() as u64)
+                .unwrap_or_default()
+        });
+        Self {
+            seed,
+            occurrences: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The seed, to replay a session with `--seed`
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Numbers for the next occurrence of `key`, such as `GET <url>`
+    pub fn stream(&self, key: &str) -> RandomStream {
+        let key = fnv1a(key.as_bytes());
+        let mut occurrences = self.occurrences.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let count = occurrences.entry(key).or_default();
+        let occurrence = *count;
+        *count += 1;
+
+        RandomStream {
+            state: mix(self.seed ^ mix(key ^ mix(occurrence))),
+        }
+    }
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// Random numbers for the decisions about one request
+pub struct RandomStream {
+    state: u64,
+}
+
+impl RandomStream {
+    /// Whether an event of the given probability happens
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+
+    /// Next number in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        (mix(self.state) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Writer passing bytes on no faster than a rate, unlimited without one
+///
+/// The rate is enforced with a token bucket: time spent idle refills at
+/// most `THROTTLE_BURST` bytes, so a quiet connection cannot send a burst
+/// far above the rate once it wakes up.
+pub struct Throttled<W: Write> {
+    inner: W,
+    rate: Option<u64>,
+    /// Bytes that may be written without waiting, negative when in debt
+    allowance: f64,
+    refilled: Instant,
+}
+
+impl<W: Write> Throttled<W> {
+    /// Limit `inner` to `rate` bytes per second
+    pub fn new(inner: W, rate: Option<u64>) -> Self {
+        Self {
+            inner,
+            rate,
+            allowance: THROTTLE_BURST as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// The writer without the limit
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let rate = match self.rate {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return self.inner.write(buf),
+        };
+
+        let now = Instant::now();
+        let idle = now.duration_since(self.refilled).as_secs_f64();
+        self.allowance = (self.allowance + idle * rate).min(THROTTLE_BURST as f64);
+        self.refilled = now;
+
+        let written = self.inner.write(&buf[..buf.len().min(THROTTLE_CHUNK)])?;
+        self.allowance -= written as f64;
+        if self.allowance < 0.0 {
+            thread::sleep(Duration::from_secs_f64(-self.allowance / rate));
+            self.allowance = 0.0;
+            self.refilled = Instant::now();
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Internal helper functions
+
+/// SplitMix64 output function
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// FNV-1a hash, stable across builds unlike the standard hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3))
+}
+
+fn profile_name(profile: ShapingProfile) -> String {
+    profile
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_time_does_not_allow_a_burst() {
+        let rate = 200 * 1024;
+        let mut writer = Throttled::new(Vec::new(), Some(rate));
+        writer.write_all(&[0; THROTTLE_BURST]).unwrap();
+        thread::sleep(Duration::from_millis(300));
+
+        // 300 ms idle would have covered the whole write with an unbounded allowance
+        let started = Instant::now();
+        writer.write_all(&[0; 60 * 1024]).unwrap();
+        let minimum = (60 * 1024 - THROTTLE_BURST) as f64 / rate as f64;
+        assert!(started.elapsed().as_secs_f64() >= minimum * 0.95, "{:?}", started.elapsed());
+        assert_eq!(writer.into_inner().len(), THROTTLE_BURST + 60 * 1024);
+    }
+
+    #[test]
+    fn streams_depend_on_the_request_not_the_order() {
+        let draw = |random: &Random, key: &str| {
+            let mut stream = random.stream(key);
+            (0..8).map(|_| stream.next_f64()).collect::<Vec<_>>()
+        };
+
+        let first = Random::new(Some(7));
+        let a = [draw(&first, "GET http://a.test/"), draw(&first, "GET http://a.test/")];
+        let b = draw(&first, "CONNECT b.test:443");
+
+        let replay = Random::new(Some(7));
+        assert_eq!(draw(&replay, "CONNECT b.test:443"), b);
+        assert_eq!(draw(&replay, "GET http://a.test/"), a[0]);
+        assert_eq!(draw(&replay, "GET http://a.test/"), a[1]);
+        assert_ne!(a[0], a[1]);
+
+        let other = Random::new(Some(8));
+        assert_ne!(draw(&other, "GET http://a.test/"), a[0]);
+        assert!(a.iter().flatten().all(|number| (0.0..1.0).contains(number)));
+    }
+
+    #[test]
+    fn unlimited_writers_pass_everything_at_once() {
+        let mut writer = Throttled::new(Vec::new(), None);
+        assert_eq!(writer.write(&[1; 3 * THROTTLE_CHUNK]).unwrap(), 3 * THROTTLE_CHUNK);
+    }
+}
@@ -0,0 +1,39 @@
+//! Relaying raw bytes between two connections
+
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use crate::server::shaping::Throttled;
+
+/// Copy bytes both ways until each side has finished sending
+///
+/// `pending` holds client bytes already read past the request head and is
+/// sent upstream first. Each direction is limited to its rate in bytes per
+/// second when one is given. Returns the bytes sent upstream and to the client.
+pub fn tunnel(
+    client: TcpStream,
+    mut upstream: TcpStream,
+    pending: &[u8],
+    up_rate: Option<u64>,
+    down_rate: Option<u64>,
+) -> io::Result<(u64, u64)> {
+    upstream.write_all(pending)?;
+
+    let mut client_reader = client.try_clone()?;
+    let mut upstream_writer = Throttled::new(upstream.try_clone()?, up_rate);
+    let outbound = thread::spawn(move || {
+        let copied = io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.into_inner().shutdown(Shutdown::Write);
+        copied
+    });
+
+    let mut client_writer = Throttled::new(client, down_rate);
+    let inbound = io::copy(&mut upstream, &mut client_writer);
+    let _ = client_writer.into_inner().shutdown(Shutdown::Write);
+
+    let outbound = outbound
+        .join()
+        .map_err(|_| io::Error::other("tunnel thread panicked"))?;
+
+    Ok((outbound? + pending.len() as u64, inbound?))
+}
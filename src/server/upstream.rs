@@ -0,0 +1,618 @@
+//! Chaining to an upstream HTTP or SOCKS5 proxy
+//!
+//! Android's global proxy cannot authenticate or speak SOCKS, so the device
+//! points at this computer and the built-in proxy takes care of the upstream.
+//! HTTP proxies get Basic authentication only, SOCKS5 proxies username/password.
+
+use std::fs;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use base64::Engine;
+use colored::*;
+use crate::error::{AppError, AppResult};
+use crate::server::forward::{authority, connect, split_authority};
+use crate::server::http::{read_response_head, Headers, ResponseHead};
+
+/// Environment variable holding `user:password` when no credentials file is given
+pub const CREDENTIALS_ENV: &str = "APS_UPSTREAM_CREDENTIALS";
+
+/// Default port of an upstream HTTP proxy given without one
+const DEFAULT_UPSTREAM_PORT: u16 = 8080;
+
+/// Default port of a SOCKS proxy given without one
+const DEFAULT_SOCKS_PORT: u16 = 1080;
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USER_PASSWORD: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const SOCKS_CONNECT: u8 = 0x01;
+const SOCKS_IPV4: u8 = 0x01;
+const SOCKS_DOMAIN: u8 = 0x03;
+const SOCKS_IPV6: u8 = 0x04;
+
+/// A proxy all traffic is sent through
+pub struct UpstreamProxy {
+    host: String,
+    port: u16,
+    protocol: Protocol,
+}
+
+/// How the upstream proxy is spoken to
+enum Protocol {
+    Http {
+        /// Value of the Proxy-Authorization header
+        authorization: Option<String>,
+    },
+    Socks5 {
+        /// Username and password (RFC 1929)
+        credentials: Option<(String, String)>,
+        /// Whether host names are resolved by the proxy (`socks5h://`) rather than here
+        remote_dns: bool,
+    },
+}
+
+impl UpstreamProxy {
+    /// Proxy at `address`: `host[:port]` or `http://host[:port]` for an HTTP proxy,
+    /// `socks5://host[:port]` or `socks5h://host[:port]` (names resolved by the proxy)
+    /// for a SOCKS5 one. Credentials come from `credentials_file` or else from
+    /// `APS_UPSTREAM_CREDENTIALS`.
+    pub fn new(address: &str, credentials_file: Option<&Path>) -> AppResult<Self> {
+        let (scheme, rest) = match address.split_once("://") {
+            Some((scheme, rest)) => (scheme.to_ascii_lowercase(), rest),
+            None => ("http".to_string(), address),
+        };
+        let authority = rest.trim_end_matches('/');
+        if authority.contains('@') {
+            return Err(AppError::proxy_server(format!(
+                "put the upstream credentials in a file (--upstream-credentials) or {}, not on the command line",
+                CREDENTIALS_ENV
+            )));
+        }
+        let default_port = if scheme == "http" { DEFAULT_UPSTREAM_PORT } else { DEFAULT_SOCKS_PORT };
+        let (host, port) = split_authority(authority, default_port)
+            .ok_or_else(|| AppError::proxy_server(format!("invalid upstream proxy '{}', expected host:port", address)))?;
+
+        let credentials = match credentials_file {
+            Some(path) => Some(read_credentials_file(path)?),
+            None => std::env::var(CREDENTIALS_ENV).ok().filter(|value| !value.is_empty()),
+        };
+        let credentials = match credentials {
+            // Spaces may belong to the password, only the line ending is dropped
+            Some(credentials) => match credentials.trim_end_matches(['\r', '\n']).split_once(':') {
+                Some((user, password)) => Some((user.to_string(), password.to_string())),
+                None => return Err(AppError::proxy_server("upstream credentials must have the form user:password")),
+            },
+            None => None,
+        };
+
+        let protocol = match scheme.as_str() {
+            "http" => Protocol::Http {
+                authorization: credentials.map(|(user, password)| {
+                    format!(
+                        "Basic {}",
+                        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+                    )
+                }),
+            },
+            "socks5" | "socks5h" => {
+                if let Some((user, password)) = &credentials {
+                    if user.len() > 255 || password.len() > 255 {
+                        return Err(AppError::proxy_server("SOCKS5 usernames and passwords are limited to 255 bytes"));
+                    }
+                }
+                Protocol::Socks5 {
+                    credentials,
+                    remote_dns: scheme == "socks5h",
+                }
+            }
+            _ => {
+                return Err(AppError::proxy_server(format!(
+                    "unsupported upstream proxy scheme '{}', expected http, socks5 or socks5h",
+                    scheme
+                )))
+            }
+        };
+
+        Ok(Self { host, port, protocol })
+    }
+
+    /// Protocol and `host:port` of the proxy, and whether credentials are sent
+    pub fn describe(&self) -> String {
+        let address = authority(&self.host, self.port, 0);
+        match &self.protocol {
+            Protocol::Http { authorization } => {
+                let auth = if authorization.is_some() { "Basic credentials" } else { "no credentials" };
+                format!("{} ({})", address, auth)
+            }
+            Protocol::Socks5 { credentials, remote_dns } => {
+                let auth = if credentials.is_some() { "username/password" } else { "no credentials" };
+                let dns = if *remote_dns { "names resolved by the proxy" } else { "names resolved here" };
+                format!("SOCKS5 {} ({}, {})", address, auth, dns)
+            }
+        }
+    }
+
+    /// Whether credentials are sent to the proxy
+    pub fn has_credentials(&self) -> bool {
+        match &self.protocol {
+            Protocol::Http { authorization } => authorization.is_some(),
+            Protocol::Socks5 { credentials, .. } => credentials.is_some(),
+        }
+    }
+
+    /// Whether plain HTTP requests are sent to the proxy itself in absolute form,
+    /// rather than through a tunnel to the server
+    pub fn takes_requests(&self) -> bool {
+        matches!(self.protocol, Protocol::Http { .. })
+    }
+
+    /// Connect to the proxy itself, for requests sent in absolute form
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        connect(&self.host, self.port)
+    }
+
+    /// Add the credentials to a request going to the proxy
+    pub fn authorize(&self, headers: &mut Headers) {
+        if let Protocol::Http { authorization: Some(authorization) } = &self.protocol {
+            headers.set("Proxy-Authorization", authorization);
+        }
+    }
+
+    /// Open a tunnel to `host:port` through the proxy, with CONNECT or a SOCKS5 request
+    pub fn open_tunnel(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        match &self.protocol {
+            Protocol::Http { authorization } => self.open_http_tunnel(host, port, authorization.as_deref()),
+            Protocol::Socks5 { credentials, remote_dns } => {
+                let mut stream = self.connect()?;
+                socks5_handshake(&mut stream, credentials.as_ref())?;
+                socks5_connect(&mut stream, host, port, *remote_dns)?;
+                Ok(stream)
+            }
+        }
+    }
+
+    /// Explain a response of the proxy, warning when it asks for credentials
+    /// this tool cannot provide
+    pub fn check_response(&self, response: &ResponseHead) {
+        if response.status == 407 {
+            println!("{} {}", "⚠️".yellow(), self.refusal(response).yellow());
+        }
+    }
+
+    fn open_http_tunnel(&self, host: &str, port: u16, authorization: Option<&str>) -> io::Result<TcpStream> {
+        let mut stream = self.connect()?;
+
+        // CONNECT targets always carry the port, no port is the default
+        let target = authority(host, port, 0);
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+        if let Some(authorization) = authorization {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // A one-byte buffer never reads past the head, the tunnel starts right after it
+        let response = read_response_head(&mut BufReader::with_capacity(1, &mut stream))?;
+        if response.status != 200 {
+            return Err(io::Error::other(self.refusal(&response)));
+        }
+        Ok(stream)
+    }
+
+    fn refusal(&self, response: &ResponseHead) -> String {
+        let status = format!("upstream proxy answered {} {}", response.status, response.reason);
+        if response.status != 407 {
+            return status;
+        }
+
+        let schemes: Vec<&str> = response
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("proxy-authenticate"))
+            .filter_map(|(_, value)| value.split_whitespace().next())
+            .collect();
+        let offers_basic = schemes.iter().any(|scheme| scheme.eq_ignore_ascii_case("basic"));
+
+        let has_credentials = matches!(self.protocol, Protocol::Http { authorization: Some(_) });
+        match (has_credentials, offers_basic) {
+            (false, true) => format!("{}: set {} or --upstream-credentials", status, CREDENTIALS_ENV),
+            (true, true) => format!("{}: the credentials were rejected", status),
+            (_, false) => format!(
+                "{}: it offers {}, only Basic authentication is supported",
+                status,
+                if schemes.is_empty() { "no scheme".to_string() } else { schemes.join(", ") }
+            ),
+        }
+    }
+}
+
+// Internal helper functions
+
+/// Read `user:password` from the first line of a file
+fn read_credentials_file(path: &Path) -> AppResult<String> {
+    let content = fs::read_to_string(path).map_err(|e| AppError::state_file_error(path, e))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = fs::metadata(path) {
+            if metadata.permissions().mode() & 0o077 != 0 {
+                println!(
+                    "{} {} is readable by other users, consider chmod 600",
+                    "⚠️".yellow(),
+                    path.display()
+                );
+            }
+        }
+    }
+
+    Ok(content.lines().next().unwrap_or("").to_string())
+}
+
+/// Pick an authentication method with a SOCKS5 proxy and authenticate
+fn socks5_handshake(stream: &mut TcpStream, credentials: Option<&(String, String)>) -> io::Result<()> {
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASSWORD],
+        None => &[SOCKS_VERSION, 1, SOCKS_NO_AUTH],
+    };
+    stream.write_all(greeting)?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(io::Error::other("upstream proxy does not speak SOCKS5"));
+    }
+
+    match (choice[1], credentials) {
+        (SOCKS_NO_AUTH, _) => Ok(()),
+        (SOCKS_USER_PASSWORD, Some((user, password))) => {
+            let mut request = vec![0x01, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0 {
+                return Err(io::Error::other("SOCKS5 proxy rejected the credentials"));
+            }
+            Ok(())
+        }
+        (SOCKS_NO_ACCEPTABLE_METHOD, None) => Err(io::Error::other(format!(
+            "SOCKS5 proxy requires credentials: set {} or --upstream-credentials",
+            CREDENTIALS_ENV
+        ))),
+        (SOCKS_NO_ACCEPTABLE_METHOD, Some(_)) => {
+            Err(io::Error::other("SOCKS5 proxy accepts neither username/password nor no authentication"))
+        }
+        (method, _) => Err(io::Error::other(format!("SOCKS5 proxy chose unsupported method {:#04x}", method))),
+    }
+}
+
+/// Ask the SOCKS5 proxy to connect to `host:port`, passing the name on when
+/// `remote_dns` is set and resolving it here otherwise
+fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16, remote_dns: bool) -> io::Result<()> {
+    let address = match host.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) if remote_dns => None,
+        Err(_) => Some(
+            (host, port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", host)))?
+                .ip(),
+        ),
+    };
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0x00];
+    match address {
+        Some(IpAddr::V4(ip)) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(IpAddr::V6(ip)) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        None => {
+            if host.len() > 255 {
+                return Err(io::Error::other(format!("host name too long for SOCKS5: {}", host)));
+            }
+            request.push(SOCKS_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    // Version, reply, reserved and the type of the bound address that follows
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(io::Error::other(format!("SOCKS5 proxy: {}", socks5_reply_message(reply[1]))));
+    }
+    let bound_length = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut length = [0u8; 1];
+            stream.read_exact(&mut length)?;
+            length[0] as usize
+        }
+        other => return Err(io::Error::other(format!("SOCKS5 proxy sent unknown address type {}", other))),
+    };
+    // The bound address and port are of no use to a CONNECT
+    let mut bound = vec![0u8; bound_length + 2];
+    stream.read_exact(&mut bound)?;
+
+    Ok(())
+}
+
+fn socks5_reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::server::forward::{ForwardProxy, ProxyContext};
+    use crate::server::http::{read_request_head, RequestHead};
+
+    /// What the stand-in SOCKS5 server received
+    struct Received {
+        greeting: Vec<u8>,
+        credentials: (String, String),
+        connect: Vec<u8>,
+    }
+
+    /// Stand-in SOCKS5 server requiring `user:password` (RFC 1929), answering
+    /// `pong` to `ping` once the CONNECT is accepted
+    fn spawn_socks(user: &'static str, password: &'static str) -> (u16, thread::JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut methods).unwrap();
+            let greeting = [header.to_vec(), methods].concat();
+            stream.write_all(&[SOCKS_VERSION, SOCKS_USER_PASSWORD]).unwrap();
+
+            let read_field = |stream: &mut TcpStream| {
+                let mut length = [0u8; 1];
+                stream.read_exact(&mut length).unwrap();
+                let mut field = vec![0u8; length[0] as usize];
+                stream.read_exact(&mut field).unwrap();
+                String::from_utf8(field).unwrap()
+            };
+            let mut version = [0u8; 1];
+            stream.read_exact(&mut version).unwrap();
+            assert_eq!(version[0], 0x01, "RFC 1929 sub-negotiation version");
+            let credentials = (read_field(&mut stream), read_field(&mut stream));
+            let accepted = credentials == (user.to_string(), password.to_string());
+            stream.write_all(&[0x01, if accepted { 0x00 } else { 0x01 }]).unwrap();
+            if !accepted {
+                return Received {
+                    greeting,
+                    credentials,
+                    connect: Vec::new(),
+                };
+            }
+
+            let mut connect = vec![0u8; 4];
+            stream.read_exact(&mut connect).unwrap();
+            let address_length = match connect[3] {
+                SOCKS_IPV4 => 4,
+                SOCKS_IPV6 => 16,
+                _ => {
+                    let mut length = [0u8; 1];
+                    stream.read_exact(&mut length).unwrap();
+                    connect.push(length[0]);
+                    length[0] as usize
+                }
+            };
+            let mut rest = vec![0u8; address_length + 2];
+            stream.read_exact(&mut rest).unwrap();
+            connect.extend_from_slice(&rest);
+            stream.write_all(&[SOCKS_VERSION, 0x00, 0x00, SOCKS_IPV4, 10, 0, 0, 7, 0x1F, 0x90]).unwrap();
+
+            let mut ping = [0u8; 4];
+            stream.read_exact(&mut ping).unwrap();
+            assert_eq!(&ping, b"ping");
+            stream.write_all(b"pong").unwrap();
+
+            Received {
+                greeting,
+                credentials,
+                connect,
+            }
+        });
+
+        (port, handle)
+    }
+
+    fn credentials_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("aps-socks-test-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        path
+    }
+
+    /// Stand-in HTTP proxy answering one request with `response`, then `pong`
+    /// to `ping` when it accepted a CONNECT
+    fn spawn_http_proxy(response: String) -> (u16, thread::JoinHandle<RequestHead>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let request = read_request_head(&mut reader).unwrap().unwrap();
+            let stream = reader.get_mut();
+            stream.write_all(response.as_bytes()).unwrap();
+            if request.method == "CONNECT" && response.starts_with("HTTP/1.1 200") {
+                let mut ping = [0u8; 4];
+                stream.read_exact(&mut ping).unwrap();
+                stream.write_all(b"pong").unwrap();
+            }
+            request
+        });
+
+        (port, handle)
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(credentials))
+    }
+
+    #[test]
+    fn http_connect_sends_basic_credentials() {
+        let (port, server) = spawn_http_proxy("HTTP/1.1 200 Connection Established\r\n\r\n".to_string());
+        let file = credentials_file("connect", "alice: s3 cret \r\n");
+        let upstream = UpstreamProxy::new(&format!("127.0.0.1:{}", port), Some(&file)).unwrap();
+
+        let mut tunnel = upstream.open_tunnel("intranet.invalid", 443).unwrap();
+        tunnel.write_all(b"ping").unwrap();
+        let mut pong = [0u8; 4];
+        tunnel.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong, b"pong");
+
+        let request = server.join().unwrap();
+        assert_eq!((request.method.as_str(), request.target.as_str()), ("CONNECT", "intranet.invalid:443"));
+        assert_eq!(request.headers.get("proxy-authorization"), Some(basic("alice: s3 cret ").as_str()));
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn plain_requests_are_sent_to_the_proxy_with_credentials() {
+        let (port, server) = spawn_http_proxy("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_string());
+        let file = credentials_file("plain", "alice:s3cret\n");
+        let context = ProxyContext {
+            upstream: Some(UpstreamProxy::new(&format!("http://127.0.0.1:{}/", port), Some(&file)).unwrap()),
+            ..ProxyContext::default()
+        };
+        fs::remove_file(file).unwrap();
+        let proxy = ForwardProxy::bind("127.0.0.1", 0, context).unwrap();
+        let address = proxy.local_addr().unwrap();
+        proxy.spawn();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        write!(client, "GET http://intranet.invalid/status HTTP/1.1\r\nHost: intranet.invalid\r\nConnection: close\r\n\r\n").unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("ok"), "{}", response);
+
+        let request = server.join().unwrap();
+        assert_eq!(request.target, "http://intranet.invalid/status");
+        assert_eq!(request.headers.get("proxy-authorization"), Some(basic("alice:s3cret").as_str()));
+    }
+
+    #[test]
+    fn http_407_explains_what_is_missing() {
+        let cases = [
+            (None, "Proxy-Authenticate: Basic realm=\"corp\"", "set APS_UPSTREAM_CREDENTIALS or --upstream-credentials"),
+            (Some("alice:wrong\n"), "Proxy-Authenticate: Basic realm=\"corp\"", "the credentials were rejected"),
+            (Some("alice:s3cret\n"), "Proxy-Authenticate: NTLM\r\nProxy-Authenticate: Negotiate", "it offers NTLM, Negotiate, only Basic authentication is supported"),
+        ];
+        for (index, (credentials, challenge, expected)) in cases.into_iter().enumerate() {
+            let (port, server) = spawn_http_proxy(format!(
+                "HTTP/1.1 407 Proxy Authentication Required\r\n{}\r\nContent-Length: 0\r\n\r\n",
+                challenge
+            ));
+            let file = credentials.map(|credentials| credentials_file(&format!("407-{}", index), credentials));
+            let upstream = UpstreamProxy::new(&format!("127.0.0.1:{}", port), file.as_deref()).unwrap();
+
+            let error = upstream.open_tunnel("intranet.invalid", 443).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("upstream proxy answered 407 Proxy Authentication Required: {}", expected)
+            );
+            server.join().unwrap();
+            if let Some(file) = file {
+                fs::remove_file(file).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn socks5h_authenticates_and_passes_the_name_on() {
+        let (port, server) = spawn_socks("alice", "s3cret");
+        let file = credentials_file("remote", "alice:s3cret\n");
+        let upstream = UpstreamProxy::new(&format!("socks5h://127.0.0.1:{}", port), Some(&file)).unwrap();
+
+        let mut tunnel = upstream.open_tunnel("intranet.invalid", 443).unwrap();
+        tunnel.write_all(b"ping").unwrap();
+        let mut pong = [0u8; 4];
+        tunnel.read_exact(&mut pong).unwrap();
+        assert_eq!(&pong, b"pong");
+
+        let received = server.join().unwrap();
+        assert_eq!(received.greeting, [SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASSWORD]);
+        assert_eq!(received.credentials, ("alice".to_string(), "s3cret".to_string()));
+        let mut expected = vec![SOCKS_VERSION, SOCKS_CONNECT, 0x00, SOCKS_DOMAIN, 16];
+        expected.extend_from_slice(b"intranet.invalid");
+        expected.extend_from_slice(&443u16.to_be_bytes());
+        assert_eq!(received.connect, expected);
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn socks5_resolves_names_here() {
+        let (port, server) = spawn_socks("alice", "s3cret");
+        let file = credentials_file("local", "alice:s3cret\n");
+        let upstream = UpstreamProxy::new(&format!("socks5://127.0.0.1:{}", port), Some(&file)).unwrap();
+
+        let mut tunnel = upstream.open_tunnel("localhost", 8443).unwrap();
+        tunnel.write_all(b"ping").unwrap();
+        tunnel.read_exact(&mut [0u8; 4]).unwrap();
+
+        let connect = server.join().unwrap().connect;
+        let (request, port) = connect.split_at(connect.len() - 2);
+        assert_eq!(port, 8443u16.to_be_bytes());
+        let address: IpAddr = match request {
+            [SOCKS_VERSION, SOCKS_CONNECT, 0x00, SOCKS_IPV4, ip @ ..] => <[u8; 4]>::try_from(ip).unwrap().into(),
+            [SOCKS_VERSION, SOCKS_CONNECT, 0x00, SOCKS_IPV6, ip @ ..] => <[u8; 16]>::try_from(ip).unwrap().into(),
+            other => panic!("expected a resolved address, got {:?}", other),
+        };
+        assert!(address.is_loopback());
+
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn socks5_reports_rejected_credentials() {
+        let (port, server) = spawn_socks("alice", "s3cret");
+        let file = credentials_file("rejected", "alice:wrong\n");
+        let upstream = UpstreamProxy::new(&format!("socks5h://127.0.0.1:{}", port), Some(&file)).unwrap();
+
+        let error = upstream.open_tunnel("intranet.invalid", 443).unwrap_err();
+        assert_eq!(error.to_string(), "SOCKS5 proxy rejected the credentials");
+        assert_eq!(server.join().unwrap().credentials, ("alice".to_string(), "wrong".to_string()));
+
+        fs::remove_file(file).unwrap();
+    }
+}